@@ -0,0 +1,24 @@
+//! Benchmarks of laying out whole documents.
+//!
+//! Run with `cargo bench -p xi2`. Paragraphs are laid out by a stub
+//! factory, so this measures the widget's side of layout, like building
+//! the layout rope and the styles of each paragraph, rather than Piet.
+
+use xi2::edit_widget::{EditWidget, StubFactory, XiState};
+
+#[path = "../../xi-text-core/benches/harness/mod.rs"]
+mod harness;
+
+use harness::{bench, document};
+
+fn main() {
+    let state = XiState::new(&document(100_000));
+    let mut widget = EditWidget::default();
+    let mut factory = StubFactory {
+        cell_width: 8.0,
+        line_height: 17.0,
+    };
+    bench("lay out 100k lines", || {
+        widget.update_layouts(&state, &mut factory)
+    });
+}
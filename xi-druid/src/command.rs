@@ -0,0 +1,105 @@
+//! Edit commands that can be run by name.
+
+use std::collections::HashMap;
+
+use xi_rope::Rope;
+use xi_text_core::{EditOp, Measurement, Movement, Selection};
+
+/// An edit command.
+///
+/// A command edits the text in place and returns the new selection.
+pub type Command = Box<dyn Fn(&mut Rope, &Selection, &dyn Measurement) -> Selection>;
+
+/// A set of commands, keyed by name.
+pub struct CommandRegistry {
+    commands: HashMap<String, Command>,
+}
+
+/// The built-in commands, which apply an `EditOp`.
+fn builtins() -> Vec<(&'static str, EditOp)> {
+    vec![
+        ("backspace", EditOp::Backspace),
+        ("collapse-selection", EditOp::CollapseSelection),
+        ("move-left", EditOp::Move(Movement::Left)),
+        ("move-right", EditOp::Move(Movement::Right)),
+        ("move-home", EditOp::Move(Movement::SmartHome)),
+        ("move-up", EditOp::Move(Movement::Up)),
+        ("move-down", EditOp::Move(Movement::Down)),
+        (
+            "move-paragraph-start",
+            EditOp::Move(Movement::StartOfParagraph),
+        ),
+        ("move-paragraph-end", EditOp::Move(Movement::EndOfParagraph)),
+        (
+            "move-document-start",
+            EditOp::Move(Movement::StartOfDocument),
+        ),
+        ("move-document-end", EditOp::Move(Movement::EndOfDocument)),
+        ("add-caret-above", EditOp::AddCaretVertical(Movement::Up)),
+        ("add-caret-below", EditOp::AddCaretVertical(Movement::Down)),
+        ("move-line-up", EditOp::MoveLine(Movement::Up)),
+        ("move-line-down", EditOp::MoveLine(Movement::Down)),
+        ("swap-case", EditOp::SwapCase),
+        (
+            "sort-lines",
+            EditOp::SortLines {
+                ascending: true,
+                case_sensitive: true,
+            },
+        ),
+        (
+            "dedupe-lines",
+            EditOp::DedupeLines {
+                trim_whitespace: false,
+            },
+        ),
+        ("increment-number", EditOp::IncrementNumber(1)),
+        ("decrement-number", EditOp::IncrementNumber(-1)),
+    ]
+}
+
+impl CommandRegistry {
+    /// A registry with no commands.
+    pub fn new() -> CommandRegistry {
+        CommandRegistry {
+            commands: HashMap::new(),
+        }
+    }
+
+    /// Register a command, replacing any command with the same name.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        command: impl Fn(&mut Rope, &Selection, &dyn Measurement) -> Selection + 'static,
+    ) {
+        self.commands.insert(name.into(), Box::new(command));
+    }
+
+    /// Register an edit operation as a command.
+    pub fn register_op(&mut self, name: impl Into<String>, op: EditOp) {
+        self.register(name, move |text, sel, measurement| {
+            op.apply(text, sel, &measurement)
+        });
+    }
+
+    /// The command with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// The names of all commands, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.commands.keys().map(String::as_str)
+    }
+}
+
+impl Default for CommandRegistry {
+    /// A registry with the built-in commands.
+    fn default() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+        for (name, op) in builtins() {
+            registry.register_op(name, op);
+        }
+        registry
+    }
+}
@@ -0,0 +1,126 @@
+//! A completion popup, offering words to replace the one at the caret.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use druid::Data;
+
+use xi_rope::Rope;
+use xi_text_core::measurement;
+
+/// The state of an open completion popup.
+#[derive(Clone, Data)]
+pub struct Completion {
+    candidates: Arc<Vec<String>>,
+    /// The index of the selected candidate.
+    selected: usize,
+}
+
+impl Completion {
+    /// A popup offering the given candidates, with the first selected.
+    pub fn new(candidates: impl IntoIterator<Item = impl Into<String>>) -> Completion {
+        let candidates: Vec<String> = candidates.into_iter().map(Into::into).collect();
+        Completion {
+            candidates: Arc::new(candidates),
+            selected: 0,
+        }
+    }
+
+    pub fn candidates(&self) -> &[String] {
+        &self.candidates
+    }
+
+    /// The index of the selected candidate.
+    pub fn selected_index(&self) -> usize {
+        self.selected
+    }
+
+    /// The selected candidate, if there are any.
+    pub fn selected(&self) -> Option<&str> {
+        self.candidates.get(self.selected).map(String::as_str)
+    }
+
+    /// Select the next candidate, if there is one.
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.candidates.len() {
+            self.selected += 1;
+        }
+    }
+
+    /// Select the previous candidate, if there is one.
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+}
+
+/// The word a completion at a caret replaces: the run of word characters
+/// (see `measurement::word_range`) on either side of the caret.
+///
+/// The range is empty if the caret isn't touching a word.
+pub fn word_at_caret(text: &Rope, offset: usize) -> Range<usize> {
+    let is_word = |range: &Range<usize>| {
+        text.slice_to_cow(range.clone())
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+    };
+    let line_start = text.offset_of_line(text.line_of_offset(offset));
+    let start = match text.prev_codepoint_offset(offset) {
+        Some(prev) if prev >= line_start => {
+            let before = measurement::word_range(text, prev);
+            if is_word(&before) {
+                before.start
+            } else {
+                offset
+            }
+        }
+        _ => offset,
+    };
+    let after = measurement::word_range(text, offset);
+    let end = if is_word(&after) { after.end } else { offset };
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use xi_rope::Rope;
+
+    use super::{word_at_caret, Completion};
+
+    #[test]
+    fn navigation() {
+        let mut completion = Completion::new(vec!["alpha", "beta", "gamma"]);
+        assert_eq!(completion.selected(), Some("alpha"));
+        completion.select_prev();
+        assert_eq!(completion.selected_index(), 0);
+        completion.select_next();
+        assert_eq!(completion.selected(), Some("beta"));
+        completion.select_next();
+        completion.select_next();
+        assert_eq!(completion.selected(), Some("gamma"));
+        completion.select_prev();
+        assert_eq!(completion.selected_index(), 1);
+
+        let mut empty = Completion::new(Vec::<String>::new());
+        empty.select_next();
+        empty.select_prev();
+        assert_eq!(empty.selected(), None);
+    }
+
+    #[test]
+    fn word_ranges() {
+        let text = Rope::from("let foo_bar = x;\nbaz");
+        // At the end, start, and middle of a word.
+        assert_eq!(word_at_caret(&text, 11), 4..11);
+        assert_eq!(word_at_caret(&text, 4), 4..11);
+        assert_eq!(word_at_caret(&text, 7), 4..11);
+        // Between punctuation and space, there is no word.
+        assert_eq!(word_at_caret(&text, 13), 13..13);
+        // Right after punctuation, only the word after counts.
+        assert_eq!(word_at_caret(&text, 14), 14..15);
+        assert_eq!(word_at_caret(&text, 16), 16..16);
+        // Words don't reach across a line break.
+        assert_eq!(word_at_caret(&text, 17), 17..20);
+        assert_eq!(word_at_caret(&text, 20), 17..20);
+    }
+}
@@ -0,0 +1,68 @@
+//! Decorations drawn with the text, like diagnostic underlines and gutter
+//! markers.
+
+use std::ops::Range;
+
+use druid::piet::Color;
+use xi_rope::Rope;
+
+/// The geometry of a visible logical line, for placing decorations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineGeometry {
+    /// The logical line.
+    pub line: usize,
+    /// The top of the line, in widget coordinates.
+    pub top: f64,
+    /// The height of the line, including all its visual lines.
+    pub height: f64,
+}
+
+/// Something to draw for a decoration.
+#[derive(Clone, Debug)]
+pub enum DecorationItem {
+    /// A squiggly underline under a range of the document, as byte
+    /// offsets. It may span several lines.
+    Underline { range: Range<usize>, color: Color },
+    /// A glyph in the gutter, at the left of a logical line.
+    Gutter {
+        line: usize,
+        glyph: String,
+        color: Color,
+    },
+}
+
+/// A source of decorations.
+pub trait Decoration {
+    /// Compute the items to draw for the visible lines.
+    ///
+    /// This is asked on each paint, with the range of visible logical lines
+    /// and the geometry of each of them that is shown; folded lines are
+    /// left out. Items outside the range aren't drawn.
+    fn decorate(
+        &mut self,
+        text: &Rope,
+        lines: Range<usize>,
+        geometry: &[LineGeometry],
+    ) -> Vec<DecorationItem>;
+}
+
+/// A decoration that doesn't draw anything.
+#[derive(Default)]
+pub struct NoDecoration;
+
+impl Decoration for NoDecoration {
+    fn decorate(
+        &mut self,
+        _text: &Rope,
+        _lines: Range<usize>,
+        _geometry: &[LineGeometry],
+    ) -> Vec<DecorationItem> {
+        Vec::new()
+    }
+}
+
+impl Default for Box<dyn Decoration> {
+    fn default() -> Self {
+        Box::new(NoDecoration)
+    }
+}
@@ -1,63 +1,680 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use druid::{
-    BoxConstraints, Data, Env, Event, EventCtx, LayoutCtx, LifeCycle, LifeCycleCtx, PaintCtx, Size,
-    UpdateCtx, Widget,
+    Application, BoxConstraints, Data, Env, Event, EventCtx, KbKey, Key, KeyEvent, LayoutCtx,
+    LifeCycle, LifeCycleCtx, PaintCtx, Selector, Size, TimerToken, UpdateCtx, Widget,
 };
 
 use druid::piet::{
-    Color, FontFamily, PietText, RenderContext, Text, TextLayout, TextLayoutBuilder,
+    Color, FontFamily, PietText, PietTextLayout, RenderContext, Text, TextAttribute, TextLayout,
+    TextLayoutBuilder,
 };
 
-use druid::kurbo::{Line, Point, Vec2};
+use druid::kurbo::{Affine, BezPath, Line, Point, Rect, Vec2};
 
-use xi_rope::Rope;
+use xi_rope::{DeltaBuilder, Interval, Rope, RopeDelta, Transformer};
 
-use xi_text_core::{EditOp, Measurement, SelRegion, Selection};
+use xi_text_core::bracket;
+use xi_text_core::clipboard;
+use xi_text_core::line_ending::{self, LineEnding};
+use xi_text_core::measurement;
+use xi_text_core::stats::{self, TextStats};
+use xi_text_core::{
+    EditKind, EditOp, InsertDrift, Measurement, SelRegion, Selection, Snapshot, UndoStack,
+};
 
-use crate::key_bindings::KeyBindings;
-use crate::layout_rope::{Layout, LayoutRope, LayoutRopeBuilder};
+use crate::command::{Command, CommandRegistry};
+use crate::completion::{self, Completion};
+use crate::decoration::{Decoration, DecorationItem, LineGeometry};
+use crate::highlight::{self, Highlighter, StyleSpan};
+use crate::key_bindings::{ClipboardOp, HistoryOp, KeyBindings};
+use crate::layout_rope::{Height, Layout, LayoutRope, LayoutRopeBuilder, StubLayout};
+use crate::palette::CommandPalette;
 use crate::util;
 
-#[derive(Clone, Data)]
+/// Move the caret to the start of a line, given as a 1-based line number,
+/// and scroll to it.
+pub const GOTO_LINE: Selector<usize> = Selector::new("xi.goto-line");
+
+/// Open the command palette.
+pub const SHOW_PALETTE: Selector<()> = Selector::new("xi.show-palette");
+
+/// Sent when Enter is pressed in single-line mode, with the text.
+pub const SUBMIT: Selector<String> = Selector::new("xi.submit");
+
+/// The color of text without a highlighting style. White if not set.
+pub const TEXT_COLOR: Key<Color> = Key::new("xi.text-color");
+
+/// The color of the bar and underline carets, and of bracket highlights.
+/// White if not set.
+pub const CARET_COLOR: Key<Color> = Key::new("xi.caret-color");
+
+/// The background of selected text, which also fills the block caret.
+/// Translucent white if not set.
+pub const SELECTION_COLOR: Key<Color> = Key::new("xi.selection-color");
+
+/// The color of the squiggles under diagnostics. Red if not set.
+pub const DIAGNOSTIC_COLOR: Key<Color> = Key::new("xi.diagnostic-color");
+
+/// The horizontal position of the text in paint coordinates.
+const TEXT_ORIGIN_X: f64 = 10.0;
+
+/// The vertical position of the first paragraph in paint coordinates.
+const TEXT_ORIGIN_Y: f64 = 12.0;
+
+/// The width of a block or underline caret with no grapheme after it, if
+/// the layout has no measured space width.
+const EMPTY_CARET_WIDTH: f64 = 8.0;
+
+/// How far from the bottom, in px, the view still counts as scrolled to
+/// the bottom, to allow for rounding.
+const BOTTOM_TOLERANCE: f64 = 1.0;
+
+/// The estimated height of a paragraph that hasn't been laid out.
+const ESTIMATED_LINE_HEIGHT: f64 = 17.0;
+
+/// The width of a selected line break.
+const NEWLINE_WIDTH: f64 = 8.0;
+
+/// The color of the placeholder text.
+const PLACEHOLDER_COLOR: Color = Color::rgb8(0x80, 0x80, 0x80);
+
+/// How often to scroll while dragging a selection past the viewport.
+const AUTO_SCROLL_INTERVAL: Duration = Duration::from_millis(30);
+
+/// How long a smooth scroll takes.
+const SCROLL_ANIM_DURATION: Duration = Duration::from_millis(150);
+
+#[derive(Clone)]
 pub struct XiState {
-    #[data(same_fn = "util::rope_eq")]
     text: Rope,
+    /// The history the text descends from, shared by clones, and the
+    /// revision of the text within it, which changes with every edit.
+    /// Together they let `Data::same` skip comparing the text.
+    history: u64,
+    revision: u64,
     sel: Arc<Selection>,
+    /// The line ending convention used when saving.
+    line_ending: LineEnding,
+    /// Highlighting styles, sorted by start offset.
+    styles: Arc<Vec<StyleSpan>>,
+    /// The command palette, if it is open.
+    palette: Option<CommandPalette>,
+    /// The completion popup, if it is open.
+    completion: Option<Completion>,
+    /// The folded ranges, sorted and disjoint. Each runs from the start of
+    /// its first hidden line to the start of the line after its last.
+    folds: Arc<Vec<Range<usize>>>,
+    /// The ranges of diagnostics, like compiler errors, which are
+    /// underlined with a squiggle.
+    diagnostics: Arc<Vec<Interval>>,
+}
+
+impl Data for XiState {
+    fn same(&self, other: &Self) -> bool {
+        self.same_text(other)
+            && self.sel.same(&other.sel)
+            && self.line_ending == other.line_ending
+            && self.styles.same(&other.styles)
+            && self.palette.same(&other.palette)
+            && self.completion.same(&other.completion)
+            && self.folds.same(&other.folds)
+            && self.diagnostics.same(&other.diagnostics)
+    }
+}
+
+/// The source of history ids and revisions, which are unique across all
+/// states, so that diverging clones never share a revision.
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(0);
+
+fn next_revision() -> u64 {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The default font size.
+const FONT_SIZE: f64 = 14.0;
+
+/// The width paragraphs are wrapped to, until the widget is laid out.
+const MAX_WIDTH: f64 = 400.0;
+
+/// Lays out the text of paragraphs.
+///
+/// The widget lays out with Piet, but tests can lay out without a render
+/// context.
+pub trait LayoutFactory {
+    /// Lay out a paragraph, without its line ending, wrapped to a width.
+    ///
+    /// The text is `text_color`, except for the ranges given other colors.
+    /// Words too long for the width are broken or overflow, depending on
+    /// `long_words`.
+    fn make_layout(
+        &mut self,
+        text: &str,
+        max_width: f64,
+        font_size: f64,
+        text_color: &Color,
+        colors: &[(Range<usize>, Color)],
+        long_words: LongWords,
+    ) -> Layout;
+}
+
+impl LayoutFactory for PietText {
+    /// Piet always breaks a word that doesn't fit, so for
+    /// `LongWords::Overflow`, a paragraph with such a word isn't wrapped at
+    /// all.
+    fn make_layout(
+        &mut self,
+        text: &str,
+        max_width: f64,
+        font_size: f64,
+        text_color: &Color,
+        colors: &[(Range<usize>, Color)],
+        long_words: LongWords,
+    ) -> Layout {
+        let mut piet_layout =
+            build_piet_layout(self, text, max_width, font_size, text_color, colors);
+        if long_words == LongWords::Overflow && breaks_word(text, &piet_layout) {
+            piet_layout =
+                build_piet_layout(self, text, f64::INFINITY, font_size, text_color, colors);
+        }
+        Layout::new(piet_layout).with_space_width(space_width(self, font_size))
+    }
+}
+
+thread_local! {
+    /// The advance of a space in each font size, by the bits of the size.
+    static SPACE_WIDTHS: RefCell<HashMap<u64, f64>> = RefCell::new(HashMap::new());
+}
+
+/// The trailing advance of a lone space, so that trailing whitespace isn't
+/// trimmed from the measurement.
+///
+/// This is measured once for each font size, rather than for every
+/// paragraph.
+fn space_width(factory: &mut PietText, font_size: f64) -> f64 {
+    SPACE_WIDTHS.with(|widths| {
+        *widths
+            .borrow_mut()
+            .entry(font_size.to_bits())
+            .or_insert_with(|| {
+                let space: PietTextLayout = factory
+                    .new_text_layout(" ")
+                    .font(FontFamily::MONOSPACE, font_size)
+                    .build()
+                    .unwrap();
+                space.hit_test_text_position(1).point.x
+            })
+    })
+}
+
+impl<F: LayoutFactory + ?Sized> LayoutFactory for &mut F {
+    fn make_layout(
+        &mut self,
+        text: &str,
+        max_width: f64,
+        font_size: f64,
+        text_color: &Color,
+        colors: &[(Range<usize>, Color)],
+        long_words: LongWords,
+    ) -> Layout {
+        (**self).make_layout(text, max_width, font_size, text_color, colors, long_words)
+    }
+}
+
+/// A factory of stub layouts, grids of equal cells, for measuring the
+/// widget without a render context, as in benchmarks.
+pub struct StubFactory {
+    /// The advance of each byte of text.
+    pub cell_width: f64,
+    /// The height of each visual line.
+    pub line_height: f64,
+}
+
+impl LayoutFactory for StubFactory {
+    /// Text wraps after as many bytes as fit the width, breaking long words.
+    fn make_layout(
+        &mut self,
+        text: &str,
+        max_width: f64,
+        _font_size: f64,
+        _text_color: &Color,
+        _colors: &[(Range<usize>, Color)],
+        _long_words: LongWords,
+    ) -> Layout {
+        // Without a wrap width, the whole text is one line.
+        let wrap = ((max_width / self.cell_width) as usize).clamp(1, text.len().max(1));
+        Layout::stub(StubLayout::new(
+            text.len(),
+            wrap,
+            self.cell_width,
+            self.line_height,
+        ))
+    }
+}
+
+fn build_piet_layout(
+    factory: &mut PietText,
+    text: &str,
+    max_width: f64,
+    font_size: f64,
+    text_color: &Color,
+    colors: &[(Range<usize>, Color)],
+) -> PietTextLayout {
+    let mut layout_builder = factory
+        .new_text_layout(text)
+        .max_width(max_width)
+        .font(FontFamily::MONOSPACE, font_size)
+        .text_color(text_color.clone());
+    for (range, color) in colors {
+        layout_builder = layout_builder
+            .range_attribute(range.clone(), TextAttribute::ForegroundColor(color.clone()));
+    }
+    layout_builder.build().unwrap()
+}
+
+/// Whether a layout wraps a line in the middle of a word, with no
+/// whitespace on either side of the break.
+fn breaks_word(text: &str, layout: &PietTextLayout) -> bool {
+    (1..layout.line_count()).any(|line| {
+        let offset = match layout.line_metric(line) {
+            Some(metric) => metric.start_offset,
+            None => return false,
+        };
+        let before = text[..offset].chars().next_back();
+        let after = text[offset..].chars().next();
+        match (before, after) {
+            (Some(before), Some(after)) => !before.is_whitespace() && !after.is_whitespace(),
+            _ => false,
+        }
+    })
 }
 
 #[derive(Default)]
 pub struct EditWidget {
     bindings: KeyBindings,
     layouts: LayoutRope,
-    // Each cursor is represented as the paragraph number and a line
+    // Each cursor is represented as the paragraph number and a rectangle
+    // relative to the start of that paragraph. The rectangle covers the
+    // grapheme after the caret, for the block and underline shapes; for
+    // the bar shape, it has zero width.
+    cursors: Vec<(usize, Rect)>,
+    // Whether the first cursor is that of the primary region, which has
+    // none if its paragraph is a placeholder.
+    has_primary_cursor: bool,
+    caret_shape: CaretShape,
+    // The colors from the environment, as of the last update.
+    theme: Theme,
+    // The cursors of the last update, keyed by offset, for reuse when
+    // neither the offset nor the paragraph changed.
+    cursor_cache: HashMap<usize, CachedCursor>,
+    // The number of times a cursor position was computed, rather than
+    // reused from the cache.
+    #[cfg(test)]
+    n_cursors_computed: usize,
+    // The number of paragraphs laid out.
+    #[cfg(test)]
+    n_layouts_built: usize,
+    // The brackets to highlight, as paragraph number and a rectangle
     // relative to the start of that paragraph.
-    cursors: Vec<(usize, Line)>,
+    brackets: Vec<(usize, Rect)>,
+    // Chooses a font size for each paragraph, given its index and text.
+    // If not set, all paragraphs use `FONT_SIZE`.
+    font_size_fn: Option<Box<dyn Fn(usize, &str) -> f64>>,
+    // The height of each paragraph, as a multiple of the height of its
+    // text. If not set, 1.
+    line_spacing: Option<f64>,
+    long_words: LongWords,
+    // Called after each edit that changes the text, with the delta and the
+    // new selection.
+    edit_listener: Option<Box<dyn FnMut(&RopeDelta, &Selection)>>,
+    highlighter: Box<dyn Highlighter>,
+    decoration: Box<dyn Decoration>,
+    // The vertical scroll offset, in px.
+    scroll_y: f64,
+    // The height of the visible area, as of the last layout.
+    viewport_height: f64,
+    // The maximum number of paragraphs to lay out, if any.
+    layout_limit: Option<usize>,
+    // The paragraphs that may be laid out, with a layout limit; the others
+    // are placeholders.
+    window: Range<usize>,
+    // Commands that can be run by name.
+    commands: CommandRegistry,
+    undo_stack: UndoStack,
+    // Whether `prepare_save` trims trailing whitespace, and whether it
+    // makes sure the text ends with a single line ending.
+    trim_on_save: bool,
+    final_newline_on_save: bool,
+    // The selection being dragged with the mouse, if any.
+    drag: Option<Drag>,
+    // A hint shown while the document is empty.
+    placeholder: Option<String>,
+    // Whether line breaks are kept out of the text, for use as a
+    // single-line input.
+    single_line: bool,
+    // The maximum length of the text in grapheme clusters, if any.
+    max_len: Option<usize>,
+    // Called when Enter is pressed in single-line mode, with the text.
+    submit_listener: Option<Box<dyn FnMut(&Rope)>>,
+    // Whether the view stays scrolled to the bottom as text is added, for
+    // use as a log.
+    log_mode: bool,
+    // The width paragraphs are wrapped to, as of the last layout. If not
+    // set, `MAX_WIDTH`.
+    wrap_width: Option<f64>,
+    file_drop: FileDrop,
+    // Whether revealing an offset scrolls there smoothly, rather than
+    // jumping.
+    smooth_scroll: bool,
+    scroll_anim: Option<ScrollAnim>,
+}
+
+/// A mouse drag extending a selection.
+struct Drag {
+    /// The unit selected where the drag started, which stays selected.
+    anchor: Range<usize>,
+    /// The unit the selection is extended by.
+    unit: DragUnit,
+    /// The last pointer position, in widget coordinates.
+    pos: Point,
+    /// The auto-scroll timer, while the pointer is above or below the
+    /// viewport.
+    timer: Option<TimerToken>,
+    /// For a block selection, started with Alt held, the horizontal
+    /// position the drag started at, relative to the text.
+    block: Option<f64>,
+}
+
+/// A smooth scroll in progress.
+struct ScrollAnim {
+    /// The scroll offset it started from, in px.
+    from: f64,
+    /// The scroll offset it ends at, in px.
+    to: f64,
+    /// The time since it started.
+    elapsed: Duration,
+}
+
+/// The unit a drag selects by, chosen by the click count.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DragUnit {
+    Char,
+    Word,
+    Line,
+}
+
+/// How a caret is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaretShape {
+    /// A vertical line before the next grapheme.
+    Bar,
+    /// A filled box covering the next grapheme, as in a vi normal mode.
+    Block,
+    /// A line under the next grapheme.
+    Underline,
+}
+
+impl Default for CaretShape {
+    fn default() -> CaretShape {
+        CaretShape::Bar
+    }
+}
+
+/// The colors the widget paints with, read from the `Env`.
+#[derive(Clone, Debug, PartialEq)]
+struct Theme {
+    text: Color,
+    caret: Color,
+    selection: Color,
+    diagnostic: Color,
+}
+
+impl Theme {
+    /// The colors set in the environment, with defaults for the keys that
+    /// aren't set.
+    fn from_env(env: &Env) -> Theme {
+        let default = Theme::default();
+        Theme {
+            text: env.try_get(&TEXT_COLOR).unwrap_or(default.text),
+            caret: env.try_get(&CARET_COLOR).unwrap_or(default.caret),
+            selection: env.try_get(&SELECTION_COLOR).unwrap_or(default.selection),
+            diagnostic: env.try_get(&DIAGNOSTIC_COLOR).unwrap_or(default.diagnostic),
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            text: Color::WHITE,
+            caret: Color::WHITE,
+            selection: Color::rgba8(0xff, 0xff, 0xff, 0x80),
+            diagnostic: Color::rgb8(0xff, 0x40, 0x40),
+        }
+    }
+}
+
+/// What happens to a word too long to fit the wrap width.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LongWords {
+    /// Break it between characters.
+    Break,
+    /// Keep it whole, overflowing the width.
+    Overflow,
+}
+
+impl Default for LongWords {
+    fn default() -> LongWords {
+        LongWords::Break
+    }
+}
+
+/// What dropping a file on the widget does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileDrop {
+    /// Insert the content of the file where it was dropped.
+    Insert,
+    /// Replace the document with the content of the file.
+    Open,
+}
+
+impl Default for FileDrop {
+    fn default() -> FileDrop {
+        FileDrop::Insert
+    }
+}
+
+/// Where to place a line revealed by scrolling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RevealAlign {
+    /// At the top of the viewport.
+    Top,
+    /// In the middle of the viewport.
+    Center,
+}
+
+/// What the editor reports to accessibility tools, such as screen readers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessNode {
+    pub role: AccessRole,
+    /// The document text.
+    pub value: String,
+    /// The primary selection, as the byte offsets of its anchor and its
+    /// caret end.
+    pub selection: Option<(usize, usize)>,
+}
+
+/// The kind of widget an accessibility node describes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessRole {
+    /// An editable, multi-line text field.
+    TextField,
+}
+
+/// A cursor's position and what it was computed from.
+struct CachedCursor {
+    para_ix: usize,
+    para_start: usize,
+    layout: Arc<Layout>,
+    rect: Rect,
 }
 
 struct XiMeasurement<'a> {
     layouts: &'a LayoutRope,
+    /// The width of a selected line break, drawn after the end of the line.
+    newline_width: f64,
+    /// The leaf of `layouts` last looked up, as the index of its first
+    /// layout and its layouts. Movement measures the same or neighboring
+    /// lines over and over, so this saves most rope traversals.
+    leaf: Cell<Option<(usize, &'a [(Height, Arc<Layout>)])>>,
+    /// The number of times `layouts` was traversed.
+    #[cfg(test)]
+    n_traversals: Cell<usize>,
 }
 
 impl Widget<XiState> for EditWidget {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut XiState, _env: &Env) {
+        let old_scroll_y = self.scroll_y;
         match event {
             Event::KeyDown(k) => {
-                if let Some(op) = self.bindings.map_key(k) {
+                if data.palette.is_some() {
+                    self.palette_key(data, k);
+                } else if self.completion_key(data, k) {
+                    // The completion popup handled the key.
+                } else if let Some(op) = self.bindings.map_history_key(k) {
+                    match op {
+                        HistoryOp::Undo => self.undo(data),
+                        HistoryOp::Redo => self.redo(data),
+                    };
+                } else if let Some(op) = self.bindings.map_clipboard_key(k) {
+                    let text = match op {
+                        ClipboardOp::Copy => self.copy(data),
+                        ClipboardOp::Cut => self.cut(data),
+                    };
+                    if !text.is_empty() {
+                        Application::global().clipboard().put_string(text);
+                    }
+                } else if self.single_line && k.key == KbKey::Enter {
+                    let text = self.submit(data);
+                    ctx.submit_command(SUBMIT.with(text), None);
+                } else if let Some(op) = self.bindings.map_key(k, &data.sel) {
                     self.apply_edit_op(data, op);
                 }
             }
-            Event::MouseDown(_) => {
+            Event::MouseDown(mouse) => {
                 // TODO: request focus on startup; why isn't it a method on LifeCycleCtx?
                 ctx.request_focus();
+                if mouse.button.is_left() {
+                    let offset = self.nearest_offset(&data.text, mouse.pos);
+                    let unit = match mouse.count {
+                        0 | 1 => DragUnit::Char,
+                        2 => DragUnit::Word,
+                        _ => DragUnit::Line,
+                    };
+                    let anchor = match data.sel.primary() {
+                        Some(region) if mouse.mods.shift() && unit == DragUnit::Char => {
+                            region.start..region.start
+                        }
+                        _ => unit_range(&data.text, offset, unit),
+                    };
+                    let block = if mouse.mods.alt() && unit == DragUnit::Char {
+                        Some(mouse.pos.x - TEXT_ORIGIN_X)
+                    } else {
+                        None
+                    };
+                    self.drag = Some(Drag {
+                        anchor,
+                        unit,
+                        pos: mouse.pos,
+                        timer: None,
+                        block,
+                    });
+                    self.drag_to(data, mouse.pos);
+                    ctx.set_active(true);
+                }
+            }
+            Event::MouseMove(mouse) => {
+                if let Some(drag) = &mut self.drag {
+                    drag.pos = mouse.pos;
+                    if drag.timer.is_none()
+                        && auto_scroll_delta(mouse.pos.y, self.viewport_height).is_some()
+                    {
+                        drag.timer = Some(ctx.request_timer(AUTO_SCROLL_INTERVAL));
+                    }
+                    self.drag_to(data, mouse.pos);
+                }
+            }
+            Event::MouseUp(_) => {
+                if self.drag.take().is_some() {
+                    ctx.set_active(false);
+                }
+            }
+            Event::Timer(token) => {
+                let drag = match &mut self.drag {
+                    Some(drag) if drag.timer == Some(*token) => drag,
+                    _ => return,
+                };
+                let pos = drag.pos;
+                match auto_scroll_delta(pos.y, self.viewport_height) {
+                    Some(delta) => {
+                        drag.timer = Some(ctx.request_timer(AUTO_SCROLL_INTERVAL));
+                        self.set_scroll_y(self.scroll_y + delta);
+                        self.drag_to(data, pos);
+                        ctx.request_paint();
+                    }
+                    // The pointer is back inside, so stop until it leaves
+                    // again.
+                    None => drag.timer = None,
+                }
+            }
+            Event::Command(cmd) => {
+                if let Some(line) = cmd.get(GOTO_LINE) {
+                    let offset = data.goto_line(*line);
+                    self.update_cursors(data);
+                    self.reveal_offset(&data.text, offset, RevealAlign::Center);
+                    if self.scroll_anim.is_some() {
+                        ctx.request_anim_frame();
+                    }
+                    ctx.request_paint();
+                    ctx.set_handled();
+                } else if cmd.get(SHOW_PALETTE).is_some() {
+                    data.show_palette(self.commands.names());
+                    ctx.set_handled();
+                }
+            }
+            Event::AnimFrame(interval) => {
+                if self.scroll_anim.is_some() {
+                    if self.advance_scroll_anim(Duration::from_nanos(*interval)) {
+                        ctx.request_anim_frame();
+                    }
+                    ctx.request_paint();
+                }
+            }
+            Event::Wheel(mouse) => {
+                self.set_scroll_y(self.scroll_y + mouse.wheel_delta.y);
+                ctx.request_paint();
             }
             _ => (),
         }
+        // The paragraphs scrolled into view are laid out in the layout pass.
+        if self.layout_limit.is_some() && self.scroll_y != old_scroll_y {
+            ctx.request_layout();
+        }
     }
 
-    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &XiState, _env: &Env) {
+    fn lifecycle(&mut self, ctx: &mut LifeCycleCtx, event: &LifeCycle, data: &XiState, env: &Env) {
         match event {
             LifeCycle::WidgetAdded => {
+                self.theme = Theme::from_env(env);
                 self.update_layouts(data, &mut ctx.text());
                 self.update_cursors(data);
             }
@@ -65,161 +682,3679 @@ impl Widget<XiState> for EditWidget {
         }
     }
 
-    fn update(&mut self, ctx: &mut UpdateCtx, _old_data: &XiState, data: &XiState, _env: &Env) {
-        let mut text = ctx.text();
-        self.update_layouts(data, &mut text);
-        self.update_cursors(data);
-        ctx.request_paint();
+    fn update(&mut self, ctx: &mut UpdateCtx, old_data: &XiState, data: &XiState, env: &Env) {
+        let old_rects = self.selection_rects(old_data);
+        let old_scroll_y = self.scroll_y;
+        let relayout = self.update_data(old_data, data, &mut ctx.text());
+        // An edit can move the window of paragraphs to lay out, as can
+        // scrolling to follow a log.
+        if self.layout_limit.is_some() && (relayout || self.scroll_y != old_scroll_y) {
+            ctx.request_layout();
+        }
+        let recolor = self.update_theme(Theme::from_env(env), data, &mut ctx.text());
+        if relayout || recolor || !old_data.diagnostics.same(&data.diagnostics) {
+            ctx.request_paint();
+        } else if let Some(rect) = invalidation_rect(&old_rects, &self.selection_rects(data)) {
+            // Only the selection changed, so only repaint where it was and
+            // where it is now.
+            ctx.request_paint_rect(rect - Vec2::new(0.0, self.scroll_y));
+        }
     }
 
     fn layout(
         &mut self,
-        _ctx: &mut LayoutCtx,
+        ctx: &mut LayoutCtx,
         bc: &BoxConstraints,
-        _data: &XiState,
+        data: &XiState,
         _env: &Env,
     ) -> druid::Size {
         // TODO: should do layout and measure height.
-        bc.constrain(Size::new(400.0, 400.0))
-    }
-
-    fn paint(&mut self, ctx: &mut PaintCtx, _data: &XiState, _env: &Env) {
-        let x = 10.0;
-        let mut y = 12.0;
-        let mut para_ix = 0;
-        let mut cursor_ix = 0;
-        for (height, layout) in &self.layouts {
-            ctx.draw_text(layout.piet_layout(), (x, y));
-            while let Some((c_para, line)) = self.cursors.get(cursor_ix) {
-                if para_ix != *c_para {
-                    break;
-                }
-                let xy = Vec2::new(x, y);
-                // It should be possible to add Line + Vec2.
-                let l2 = Line::new(line.p0 + xy, line.p1 + xy);
-                ctx.stroke(l2, &Color::WHITE, 1.0);
-                cursor_ix += 1;
-            }
-            y += height.to_f64();
-            para_ix += 1;
+        let size = bc.constrain(Size::new(400.0, 400.0));
+        self.viewport_height = size.height;
+        self.set_wrap_width(size.width, data, &mut ctx.text());
+        if self.layout_limit.is_some() && self.lay_out_window(data, &mut ctx.text()) {
+            self.update_cursors(data);
         }
+        size
+    }
+
+    fn paint(&mut self, ctx: &mut PaintCtx, data: &XiState, _env: &Env) {
+        let decorations = self.decoration_items(data);
+        let clip_rect = ctx.size().to_rect();
+        let scroll_y = self.scroll_y;
+        ctx.with_save(|ctx| {
+            ctx.clip(clip_rect);
+            ctx.transform(Affine::translate((0.0, -scroll_y)));
+            if let Some(placeholder) = self.placeholder_text(data) {
+                self.paint_placeholder(ctx, placeholder);
+            }
+            for rect in self.selection_background_rects(data) {
+                ctx.fill(rect, &self.theme.selection);
+            }
+            self.paint_text(ctx);
+            self.paint_decorations(ctx, &data.text, &decorations);
+            self.paint_diagnostics(ctx, data);
+        });
     }
 }
 
 impl EditWidget {
-    fn update_layouts(&mut self, data: &XiState, factory: &mut PietText) {
-        // In time, this will be more incremental.
-        let font_family = FontFamily::MONOSPACE;
+    /// Paint the text, cursors, and decorations, in unscrolled coordinates.
+    fn paint_text(&self, ctx: &mut PaintCtx) {
+        for (top, layout) in self.layouts.iter_with_tops() {
+            if layout.is_hidden() {
+                continue;
+            }
+            if let Some(piet_layout) = layout.as_piet() {
+                let y = top.to_f64() + TEXT_ORIGIN_Y;
+                ctx.draw_text(piet_layout, (TEXT_ORIGIN_X, y));
+            }
+        }
+        for rect in self.caret_rects() {
+            self.paint_caret(ctx, rect);
+        }
+        for rect in self.bracket_rects() {
+            ctx.stroke(rect, &self.theme.caret, 1.0);
+        }
+    }
 
-        let mut builder = LayoutRopeBuilder::new();
-        let mut offset = 0;
-        let mut selections = &**data.sel;
-        let mut text = data.text.clone();
-        // This is an expedient hack to make sure we get a layout and can draw
-        // the cursor for the last (empty) line, if it exists.
-        if text.is_empty() || text.byte_at(text.len() - 1) == b'\n' {
-            text = text + "\n".into();
-        }
-        for l in text.lines_raw(..) {
-            let mut end = l.len();
-            if l.ends_with('\n') {
-                end -= 1;
-            }
-            if l[..end].ends_with('\r') {
-                end -= 1;
-            }
-            let trim = &l[..end];
-            let piet_layout: druid::piet::PietTextLayout = factory
-                .new_text_layout(&trim)
-                .max_width(400.0)
-                .font(font_family.clone(), 14.0)
-                .text_color(Color::WHITE)
-                .build()
-                .unwrap();
-
-            let mut cursors = Vec::new();
-            while let Some(sel_region) = selections.first() {
-                if sel_region.end <= offset + trim.len() {
-                    let hit = piet_layout.hit_test_text_position(sel_region.end - offset);
-                    // TODO: use line metrics, but good enough for a quick hack.
-                    let pt = hit.point - Vec2::new(0.0, 12.0);
-                    let height = 18.0;
-                    let line = Line::new(pt, pt + Vec2::new(0.0, height));
-                    cursors.push(line);
-                    selections = &selections[1..];
-                } else {
-                    break;
+    /// The logical lines in the viewport, including any folded ones.
+    fn visible_lines(&self) -> Range<usize> {
+        if self.layouts.len() == 0 {
+            return 0..0;
+        }
+        let first = self.para_at_y(self.scroll_y).0;
+        let last = self.para_at_y(self.scroll_y + self.viewport_height).0;
+        first..last + 1
+    }
+
+    /// Ask the decoration for the items to draw for the visible lines.
+    fn decoration_items(&mut self, data: &XiState) -> Vec<DecorationItem> {
+        let lines = self.visible_lines();
+        let geometry: Vec<_> = lines
+            .clone()
+            .filter(|&para_ix| !self.layouts[para_ix].is_hidden())
+            .map(|para_ix| LineGeometry {
+                line: para_ix,
+                top: self.layouts.pixel_height_of_index(para_ix) + TEXT_ORIGIN_Y - self.scroll_y,
+                height: self.layouts.height_at(para_ix).unwrap().to_f64(),
+            })
+            .collect();
+        self.decoration.decorate(&data.text, lines, &geometry)
+    }
+
+    /// Paint decoration items, in unscrolled coordinates.
+    ///
+    /// Only the parts of them in the visible lines are drawn, and nothing is
+    /// drawn in placeholder or folded paragraphs.
+    fn paint_decorations(&self, ctx: &mut PaintCtx, text: &Rope, items: &[DecorationItem]) {
+        let visible = self.visible_lines();
+        if visible.is_empty() {
+            return;
+        }
+        for item in items {
+            match item {
+                DecorationItem::Underline { range, color } => {
+                    for (x0, x1, y) in self.range_segments(text, range.clone(), &visible) {
+                        ctx.stroke(squiggle(x0, x1, y), color, 1.0);
+                    }
+                }
+                DecorationItem::Gutter { line, glyph, color } => {
+                    if !visible.contains(line) || self.layouts[*line].is_hidden() {
+                        continue;
+                    }
+                    let layout = ctx
+                        .text()
+                        .new_text_layout(glyph.clone())
+                        .font(FontFamily::MONOSPACE, FONT_SIZE)
+                        .text_color(color.clone())
+                        .build()
+                        .unwrap();
+                    let y = self.layouts.pixel_height_of_index(*line) + TEXT_ORIGIN_Y;
+                    ctx.draw_text(&layout, (0.0, y));
                 }
             }
-            let layout = Layout::new(piet_layout);
-            builder.push_layout(layout);
-            offset += l.len();
         }
-        self.layouts = builder.build()
     }
 
-    fn update_cursors(&mut self, data: &XiState) {
-        self.cursors.clear();
-        for sel_region in &*data.sel {
-            let cursor_offset = sel_region.end;
-            let para_ix = data.text.line_of_offset(cursor_offset);
-            let para_start = data.text.offset_of_line(para_ix);
-            let piet_layout = self.layouts.get(para_ix).unwrap().1.piet_layout();
-            let hit = piet_layout.hit_test_text_position(cursor_offset - para_start);
-            // TODO: use line metrics, but good enough for a quick hack.
-            let pt = hit.point - Vec2::new(0.0, 12.0);
-            let height = 18.0;
-            let line = Line::new(pt, pt + Vec2::new(0.0, height));
-            self.cursors.push((para_ix, line));
+    /// Paint a squiggle under each diagnostic, in unscrolled coordinates.
+    fn paint_diagnostics(&self, ctx: &mut PaintCtx, data: &XiState) {
+        let visible = self.visible_lines();
+        if visible.is_empty() {
+            return;
+        }
+        for diagnostic in data.diagnostics.iter() {
+            let range = diagnostic.start..diagnostic.end;
+            for (x0, x1, y) in self.range_segments(&data.text, range, &visible) {
+                ctx.stroke(squiggle(x0, x1, y), &self.theme.diagnostic, 1.0);
+            }
         }
     }
 
-    fn apply_edit_op(&mut self, data: &mut XiState, op: EditOp) {
+    /// The baseline segments under a range of the document, as by
+    /// `underline_segments`, for each of a range of paragraphs it touches.
+    fn range_segments(
+        &self,
+        text: &Rope,
+        range: Range<usize>,
+        paras: &Range<usize>,
+    ) -> Vec<(f64, f64, f64)> {
+        let first = text.line_of_offset(range.start).max(paras.start);
+        let last = text.line_of_offset(range.end).min(paras.end - 1);
+        let mut segments = Vec::new();
+        for para_ix in first..=last {
+            let para_start = text.offset_of_line(para_ix);
+            let start = range.start.max(para_start) - para_start;
+            let end = range.end.min(text.offset_of_line(para_ix + 1)) - para_start;
+            segments.extend(self.underline_segments(para_ix, start..end));
+        }
+        segments
+    }
+
+    /// The baseline segments under a range of a paragraph, as the start and
+    /// end x and the y of each, in unscrolled coordinates, with one for each
+    /// visual line the range touches.
+    fn underline_segments(&self, para_ix: usize, range: Range<usize>) -> Vec<(f64, f64, f64)> {
+        let layout = match self.layouts.get(para_ix) {
+            Some((_, layout)) if !layout.is_placeholder() && !layout.is_hidden() => layout,
+            _ => return Vec::new(),
+        };
+        let top = self.layouts.pixel_height_of_index(para_ix) + TEXT_ORIGIN_Y;
+        let mut segments = Vec::new();
+        for line in 0..layout.line_count() {
+            let metric = layout.line_metric(line).unwrap();
+            let start = range.start.max(metric.start_offset);
+            let end = range.end.min(metric.end_offset);
+            if start >= end {
+                continue;
+            }
+            let x0 = layout.hit_test_text_position(start).point.x;
+            // The end of a wrapped line is at the start of the next, so
+            // that one reaches the right edge of the paragraph.
+            let hit = layout.hit_test_text_position(end);
+            let x1 = if hit.line == line {
+                hit.point.x
+            } else {
+                layout.width()
+            };
+            let y = top + metric.y_offset + metric.height - 1.0;
+            segments.push((x0 + TEXT_ORIGIN_X, x1 + TEXT_ORIGIN_X, y));
+        }
+        segments
+    }
+
+    /// Paint the placeholder text where the first paragraph would be.
+    ///
+    /// It isn't part of the layouts, so it doesn't affect the caret or the
+    /// scrollable height.
+    fn paint_placeholder(&self, ctx: &mut PaintCtx, placeholder: &str) {
+        let layout = ctx
+            .text()
+            .new_text_layout(placeholder)
+            .max_width(self.wrap_width())
+            .font(FontFamily::MONOSPACE, FONT_SIZE)
+            .text_color(PLACEHOLDER_COLOR)
+            .build()
+            .unwrap();
+        ctx.draw_text(&layout, (TEXT_ORIGIN_X, TEXT_ORIGIN_Y));
+    }
+
+    /// The placeholder text to show, which is only while the document is
+    /// empty.
+    fn placeholder_text<'a>(&'a self, data: &XiState) -> Option<&'a str> {
+        match &self.placeholder {
+            Some(placeholder) if data.text.is_empty() => Some(placeholder),
+            _ => None,
+        }
+    }
+
+    /// The caret rectangles, in unscrolled paint coordinates.
+    ///
+    /// Each is positioned from its own paragraph, so this doesn't depend on
+    /// the order of the cursors, and any number of them can share a
+    /// paragraph.
+    fn caret_rects(&self) -> impl Iterator<Item = Rect> + '_ {
+        self.cursors
+            .iter()
+            .map(move |(para_ix, rect)| self.para_rect_to_paint(*para_ix, *rect))
+    }
+
+    /// The bounds of the caret of the primary region, in widget
+    /// coordinates, for anchoring things like IME candidate windows.
+    ///
+    /// Returns `None` if there is no selection, or the caret isn't shown
+    /// because its paragraph hasn't been laid out.
+    pub fn primary_caret_rect(&self) -> Option<Rect> {
+        if !self.has_primary_cursor {
+            return None;
+        }
+        let rect = self.caret_rects().next()?;
+        Some(rect - Vec2::new(0.0, self.scroll_y))
+    }
+
+    /// The bracket highlight rectangles, in unscrolled paint coordinates.
+    fn bracket_rects(&self) -> impl Iterator<Item = Rect> + '_ {
+        self.brackets
+            .iter()
+            .map(move |(para_ix, rect)| self.para_rect_to_paint(*para_ix, *rect))
+    }
+
+    /// Convert a rectangle relative to a paragraph to unscrolled paint
+    /// coordinates.
+    fn para_rect_to_paint(&self, para_ix: usize, rect: Rect) -> Rect {
+        let y = self.layouts.pixel_height_of_index(para_ix) + TEXT_ORIGIN_Y;
+        rect + Vec2::new(TEXT_ORIGIN_X, y)
+    }
+
+    /// The rectangles of everything drawn that depends on the selection, in
+    /// unscrolled paint coordinates.
+    fn selection_rects(&self, data: &XiState) -> Vec<Rect> {
+        let mut rects: Vec<_> = self.caret_rects().chain(self.bracket_rects()).collect();
+        rects.extend(self.selection_background_rects(data));
+        rects
+    }
+
+    /// The background rectangles of the selected regions in the visible
+    /// lines, in unscrolled paint coordinates, with one for each visual
+    /// line a region touches.
+    fn selection_background_rects(&self, data: &XiState) -> Vec<Rect> {
+        let visible = self.visible_lines();
+        let mut rects = Vec::new();
+        if visible.is_empty() {
+            return rects;
+        }
         let measurement = self.measurement();
-        let new_sel = op.apply(&mut data.text, &data.sel, &measurement);
-        data.sel = Arc::new(new_sel);
+        for region in data.sel.iter().filter(|region| !region.is_caret()) {
+            let first = data.text.line_of_offset(region.min()).max(visible.start);
+            let last = data.text.line_of_offset(region.max()).min(visible.end - 1);
+            for para_ix in first..=last {
+                let layout = &self.layouts[para_ix];
+                if layout.is_placeholder() || layout.is_hidden() {
+                    continue;
+                }
+                let para_start = data.text.offset_of_line(para_ix);
+                let start = region.min().saturating_sub(para_start);
+                let end = region.max() - para_start;
+                for line in 0..layout.line_count() {
+                    let metric = layout.line_metric(line).unwrap();
+                    // A region starting at a wrap point starts on the next
+                    // visual line.
+                    let is_last = line + 1 == layout.line_count();
+                    if end < metric.start_offset || (start >= metric.end_offset && !is_last) {
+                        continue;
+                    }
+                    let (x0, x1) = measurement.visual_line_x_range(para_ix, line, start, end);
+                    if x1 > x0 {
+                        let y0 = metric.y_offset;
+                        let rect = Rect::new(x0, y0, x1, y0 + metric.height);
+                        rects.push(self.para_rect_to_paint(para_ix, rect));
+                    }
+                }
+            }
+        }
+        rects
     }
 
-    fn measurement(&self) -> XiMeasurement {
-        XiMeasurement {
-            layouts: &self.layouts,
+    fn paint_caret(&self, ctx: &mut PaintCtx, rect: Rect) {
+        match self.caret_shape {
+            CaretShape::Bar => {
+                let line = Line::new((rect.x0, rect.y0), (rect.x0, rect.y1));
+                ctx.stroke(line, &self.theme.caret, 1.0);
+            }
+            CaretShape::Block => {
+                ctx.fill(rect, &self.theme.selection);
+            }
+            CaretShape::Underline => {
+                let line = Line::new((rect.x0, rect.y1), (rect.x1, rect.y1));
+                ctx.stroke(line, &self.theme.caret, 1.0);
+            }
         }
     }
-}
 
-impl XiState {
-    pub fn new(initial_text: impl Into<Rope>) -> XiState {
-        let text = initial_text.into();
-        let len = text.len();
-        let sel = Selection::new_simple(SelRegion::new(len, len));
-        XiState {
-            text,
-            sel: Arc::new(sel),
+    /// The vertical scroll offset, in px.
+    pub fn scroll_y(&self) -> f64 {
+        self.scroll_y
+    }
+
+    /// Set the vertical scroll offset, clamped to the scrollable range.
+    ///
+    /// This stops a smooth scroll in progress. With a layout limit, the
+    /// paragraphs scrolled into view are laid out in the next layout pass,
+    /// which the caller requests.
+    pub fn set_scroll_y(&mut self, scroll_y: f64) {
+        self.scroll_anim = None;
+        self.scroll_y = clamp_scroll(&self.layouts, scroll_y, self.viewport_height);
+    }
+
+    /// Set whether revealing an offset, as with `GOTO_LINE`, scrolls there
+    /// smoothly over a few frames, rather than jumping.
+    pub fn set_smooth_scroll(&mut self, smooth_scroll: bool) {
+        self.smooth_scroll = smooth_scroll;
+    }
+
+    /// Scroll to a position, smoothly if that is on.
+    ///
+    /// A smooth scroll starts from wherever the view is, so scrolling again
+    /// during one heads for the new target.
+    fn scroll_to(&mut self, scroll_y: f64) {
+        if self.smooth_scroll && scroll_y != self.scroll_y {
+            self.scroll_anim = Some(ScrollAnim {
+                from: self.scroll_y,
+                to: scroll_y,
+                elapsed: Duration::default(),
+            });
+        } else {
+            self.scroll_anim = None;
+            self.scroll_y = scroll_y;
         }
     }
-}
 
-impl<'a> Measurement for XiMeasurement<'a> {
-    fn n_visual_lines(&self, line_num: usize) -> usize {
-        let layout = self.layouts.get(line_num).unwrap().1.piet_layout();
-        layout.line_count()
+    /// Advance the smooth scroll by the time since the last frame.
+    ///
+    /// Returns `true` if it is still in progress.
+    fn advance_scroll_anim(&mut self, interval: Duration) -> bool {
+        let anim = match &mut self.scroll_anim {
+            Some(anim) => anim,
+            None => return false,
+        };
+        anim.elapsed += interval;
+        let t = anim.elapsed.as_secs_f64() / SCROLL_ANIM_DURATION.as_secs_f64();
+        if t >= 1.0 {
+            self.scroll_y = anim.to;
+            self.scroll_anim = None;
+            false
+        } else {
+            self.scroll_y = ease_scroll(anim.from, anim.to, t);
+            true
+        }
     }
 
-    fn to_pos(&self, line_num: usize, offset: usize) -> (f64, usize) {
-        let layout = self.layouts.get(line_num).unwrap().1.piet_layout();
-        let hit = layout.hit_test_text_position(offset);
-        (hit.point.x, hit.line)
+    /// Whether the view is scrolled as far down as it goes, which it is
+    /// when all the text fits.
+    pub fn is_scrolled_to_bottom(&self) -> bool {
+        self.scroll_y >= max_scroll(&self.layouts, self.viewport_height) - BOTTOM_TOLERANCE
     }
 
-    fn from_pos(&self, line_num: usize, horiz: f64, visual_line: usize) -> usize {
-        let layout = self.layouts.get(line_num).unwrap().1.piet_layout();
-        if let Some(metric) = layout.line_metric(visual_line) {
-            let y = metric.y_offset + 0.5 * metric.height;
-            let point = Point::new(horiz, y);
-            layout.hit_test_point(point).idx
+    /// Set whether the view stays at the bottom as text is added, as when
+    /// following a log (see `XiState::append`).
+    ///
+    /// It only stays there if it was at the bottom before the text was
+    /// added, so scrolling up to read stops it following.
+    pub fn set_log_mode(&mut self, log_mode: bool) {
+        self.log_mode = log_mode;
+    }
+
+    /// Scroll so that the line containing the offset is visible.
+    ///
+    /// With smooth scrolling on, this starts the scroll, which advances on
+    /// animation frames; the caller requests the first.
+    pub fn reveal_offset(&mut self, text: &Rope, offset: usize, align: RevealAlign) {
+        let para_ix = text.line_of_offset(offset);
+        let scroll_y = reveal_scroll(&self.layouts, para_ix, self.viewport_height, align);
+        self.scroll_to(scroll_y);
+    }
+
+    /// The logical line at a y coordinate in widget coordinates, for
+    /// things like gutters and rulers drawn beside the text.
+    ///
+    /// Coordinates above the text map to the first line, and below it to
+    /// the last.
+    pub fn line_at_y(&self, y: f64) -> usize {
+        self.para_at_y(y + self.scroll_y).0
+    }
+
+    /// The offset under a point in widget coordinates, for things like
+    /// showing information about the symbol under the pointer.
+    ///
+    /// Returns `None` if the point is above, below, or beside the text,
+    /// or in a paragraph that hasn't been laid out. Clicks instead go to
+    /// the nearest offset.
+    pub fn offset_at_point(&self, data: &XiState, point: Point) -> Option<usize> {
+        let y = point.y + self.scroll_y;
+        if y < TEXT_ORIGIN_Y || y >= TEXT_ORIGIN_Y + self.layouts.pixel_height() {
+            return None;
+        }
+        let (para_ix, top) = self.para_at_y(y);
+        let layout = self.layouts.get(para_ix)?.1;
+        if layout.is_placeholder() || layout.is_hidden() {
+            return None;
+        }
+        let x = point.x - TEXT_ORIGIN_X;
+        if x < 0.0 || x > layout.width() {
+            return None;
+        }
+        let offset = layout.hit_test_point(Point::new(x, y - top));
+        Some(data.text.offset_of_line(para_ix) + offset)
+    }
+
+    /// The offset nearest a point in widget coordinates.
+    ///
+    /// Points above or below the text map to the first or last paragraph.
+    /// A placeholder paragraph maps everything to its start.
+    fn nearest_offset(&self, text: &Rope, point: Point) -> usize {
+        let y = point.y + self.scroll_y;
+        let (para_ix, top) = self.para_at_y(y);
+        let para_start = text.offset_of_line(para_ix);
+        let layout = match self.layouts.get(para_ix) {
+            Some((_, layout)) if !layout.is_placeholder() => layout,
+            _ => return para_start,
+        };
+        let point = Point::new(point.x - TEXT_ORIGIN_X, y - top);
+        para_start + layout.hit_test_point(point)
+    }
+
+    /// Extend the dragged selection to a point in widget coordinates.
+    ///
+    /// A block drag selects the rectangle between where it started and the
+    /// point, with a region on each line, for editing in columns.
+    fn drag_to(&mut self, data: &mut XiState, point: Point) {
+        let offset = self.nearest_offset(&data.text, point);
+        let sel = match &self.drag {
+            Some(Drag {
+                anchor,
+                block: Some(anchor_x),
+                ..
+            }) => {
+                let anchor = (anchor.start, *anchor_x);
+                let active = (offset, point.x - TEXT_ORIGIN_X);
+                Selection::new_block(&data.text, &self.measurement(), anchor, active)
+            }
+            Some(drag) => {
+                let region = drag_region(&data.text, &drag.anchor, offset, drag.unit);
+                Selection::new_simple(region)
+            }
+            None => return,
+        };
+        data.set_selection(sel);
+        self.undo_stack.break_unit();
+    }
+
+    /// Whether overwrite mode, toggled by the Insert key, is on.
+    pub fn overwrite(&self) -> bool {
+        self.bindings.overwrite()
+    }
+
+    /// Set whether Tab inserts spaces up to the next tab stop, rather than
+    /// a tab character.
+    pub fn set_soft_tabs(&mut self, soft_tabs: bool) {
+        self.bindings.set_soft_tabs(soft_tabs);
+    }
+
+    /// Set the distance between tab stops, in columns.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tab_width` is zero.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.bindings.set_tab_width(tab_width);
+    }
+
+    /// Set the prefix of a line comment, toggled by Ctrl+/.
+    pub fn set_line_comment(&mut self, prefix: impl Into<String>) {
+        self.bindings.set_line_comment(prefix);
+    }
+
+    /// Set whether typing an opening bracket or quote over a selection
+    /// wraps it in the pair, rather than replacing it.
+    pub fn set_auto_surround(&mut self, auto_surround: bool) {
+        self.bindings.set_auto_surround(auto_surround);
+    }
+
+    /// Set whether `prepare_save` removes trailing whitespace.
+    pub fn set_trim_on_save(&mut self, trim: bool) {
+        self.trim_on_save = trim;
+    }
+
+    /// Set whether `prepare_save` makes sure a non-empty text ends with
+    /// exactly one line ending.
+    pub fn set_final_newline_on_save(&mut self, final_newline: bool) {
+        self.final_newline_on_save = final_newline;
+    }
+
+    /// Make the edits configured to happen on save, as a single undo unit.
+    ///
+    /// Call this before writing the text.
+    pub fn prepare_save(&mut self, data: &mut XiState) {
+        let mut ops = Vec::new();
+        if self.trim_on_save {
+            ops.push(EditOp::TrimTrailingWhitespace);
+        }
+        if self.final_newline_on_save {
+            ops.push(EditOp::EnsureFinalNewline);
+        }
+        let before = data.snapshot();
+        let mut changed = false;
+        for op in ops {
+            let measurement = self.measurement();
+            if let Some(delta) = data.apply_edit_op(op, &measurement) {
+                self.text_changed(data, &delta);
+                changed = true;
+            }
+        }
+        if changed {
+            self.undo_stack
+                .record(before, data.snapshot(), EditKind::Other, Instant::now());
+        }
+    }
+
+    /// Set a hint to show, dimmed, while the document is empty.
+    pub fn set_placeholder(&mut self, placeholder: Option<String>) {
+        self.placeholder = placeholder;
+    }
+
+    /// Set the shape of the carets.
+    pub fn set_caret_shape(&mut self, caret_shape: CaretShape) {
+        self.caret_shape = caret_shape;
+        // The cached cursors have the width for the old shape.
+        self.cursor_cache.clear();
+    }
+
+    /// Set the highlighter, which is invoked on the changed lines after
+    /// each edit.
+    pub fn set_highlighter(&mut self, highlighter: impl Highlighter + 'static) {
+        self.highlighter = Box::new(highlighter);
+    }
+
+    /// Set the decoration, which is asked for the items to draw for the
+    /// visible lines on each paint.
+    pub fn set_decoration(&mut self, decoration: impl Decoration + 'static) {
+        self.decoration = Box::new(decoration);
+    }
+
+    /// Set a function choosing the font size of each paragraph, given its
+    /// index and text.
+    ///
+    /// After an edit, only the changed paragraphs are laid out again, so
+    /// the size shouldn't depend on the index alone.
+    pub fn set_font_size_fn(&mut self, f: impl Fn(usize, &str) -> f64 + 'static) {
+        self.font_size_fn = Some(Box::new(f));
+    }
+
+    /// Set the line spacing, as a multiple of the height of each
+    /// paragraph's text. The extra space goes below the text.
+    ///
+    /// As with the font size, paragraphs get the spacing when they are
+    /// laid out.
+    pub fn set_line_spacing(&mut self, line_spacing: f64) {
+        self.line_spacing = Some(line_spacing);
+    }
+
+    /// Set whether words too long for the wrap width are broken or
+    /// overflow it.
+    ///
+    /// As with the font size, paragraphs get the setting when they are
+    /// laid out.
+    pub fn set_long_words(&mut self, long_words: LongWords) {
+        self.long_words = long_words;
+    }
+
+    /// Register a command, to be run by name with `run_command`.
+    ///
+    /// This replaces any command, including a built-in one, with the same
+    /// name.
+    pub fn register_command(
+        &mut self,
+        name: impl Into<String>,
+        command: impl Fn(&mut Rope, &Selection, &dyn Measurement) -> Selection + 'static,
+    ) {
+        self.commands.register(name, command);
+    }
+
+    /// Run the command with the given name.
+    ///
+    /// Returns `false` if there is no such command.
+    pub fn run_command(&mut self, data: &mut XiState, name: &str) -> bool {
+        let command = match self.commands.get(name) {
+            Some(command) => command,
+            None => return false,
+        };
+        let before = data.snapshot();
+        let measurement = self.measurement();
+        if let Some(delta) = data.apply_command(command, &measurement) {
+            self.undo_stack
+                .record(before, data.snapshot(), EditKind::Other, Instant::now());
+            self.text_changed(data, &delta);
         } else {
-            // This shouldn't happen, but provide a reasonable value.
-            0
+            self.undo_stack.break_unit();
+        }
+        true
+    }
+
+    /// Run the command selected in the palette, and close the palette.
+    ///
+    /// Returns `false` if the palette isn't open or nothing is selected.
+    pub fn run_palette_command(&mut self, data: &mut XiState) -> bool {
+        let name = match data.palette.take() {
+            Some(palette) => palette.selected().map(String::from),
+            None => None,
+        };
+        match name {
+            Some(name) => self.run_command(data, &name),
+            None => false,
         }
     }
+
+    /// Handle a key while the palette is open: typing edits the query, the
+    /// arrow keys change the selection, Enter runs the selected command,
+    /// and Escape closes the palette.
+    fn palette_key(&mut self, data: &mut XiState, k: &KeyEvent) {
+        let palette = match &mut data.palette {
+            Some(palette) => palette,
+            None => return,
+        };
+        match &k.key {
+            KbKey::Character(s) => {
+                let query = format!("{}{}", palette.query(), s);
+                palette.set_query(query);
+            }
+            KbKey::Backspace => {
+                let mut query = palette.query().to_string();
+                query.pop();
+                palette.set_query(query);
+            }
+            KbKey::ArrowDown => palette.select_next(),
+            KbKey::ArrowUp => palette.select_prev(),
+            KbKey::Enter => {
+                self.run_palette_command(data);
+            }
+            KbKey::Escape => data.hide_palette(),
+            _ => (),
+        }
+    }
+
+    /// Replace the word at each caret (see `completion::word_at_caret`)
+    /// with the candidate selected in the completion popup, and close the
+    /// popup. Selected text is replaced as it is.
+    ///
+    /// Returns `false` if the popup isn't open or has no candidates.
+    pub fn accept_completion(&mut self, data: &mut XiState) -> bool {
+        let candidate = match data.completion.take() {
+            Some(completion) => completion.selected().map(String::from),
+            None => None,
+        };
+        let candidate = match candidate {
+            Some(candidate) => candidate,
+            None => return false,
+        };
+        let mut sel = Selection::new();
+        for region in &*data.sel {
+            if region.is_caret() {
+                let word = completion::word_at_caret(&data.text, region.end);
+                sel.add_region(SelRegion::new(word.start, word.end));
+            } else {
+                sel.add_region(*region);
+            }
+        }
+        data.set_selection(sel);
+        self.apply_edit_op(data, EditOp::Insert(candidate));
+        true
+    }
+
+    /// Insert dropped text where it was dropped, leaving a caret after it.
+    ///
+    /// The point is in widget coordinates, and is hit tested as a click
+    /// is. Druid doesn't deliver drop events, so the embedder passes them
+    /// on.
+    pub fn drop_text(&mut self, data: &mut XiState, point: Point, text: &str) {
+        let offset = self.nearest_offset(&data.text, point);
+        data.set_selection(Selection::new_simple(SelRegion::caret(offset)));
+        self.apply_edit_op(data, EditOp::Insert(text.to_string()));
+    }
+
+    /// Handle a file dropped at a point in widget coordinates, inserting
+    /// its content there or replacing the document with it (see
+    /// `set_file_drop`).
+    ///
+    /// Either way, it is a single edit, which can be undone.
+    ///
+    /// # Errors
+    ///
+    /// Errors reading the file are returned, as with
+    /// `XiState::from_reader`, and the text is left as it was.
+    pub fn drop_file(&mut self, data: &mut XiState, point: Point, path: &Path) -> io::Result<()> {
+        let content = String::from(&util::read_rope(File::open(path)?)?);
+        match self.file_drop {
+            FileDrop::Insert => self.drop_text(data, point, &content),
+            FileDrop::Open => {
+                let all = SelRegion::new(0, data.text.len());
+                data.set_selection(Selection::new_simple(all));
+                self.apply_edit_op(data, EditOp::Insert(content));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set what dropping a file does. By default, its content is inserted.
+    pub fn set_file_drop(&mut self, file_drop: FileDrop) {
+        self.file_drop = file_drop;
+    }
+
+    /// Handle a key while the completion popup is open: the arrow keys
+    /// change the selection, Enter and Tab accept it, and Escape closes
+    /// the popup.
+    ///
+    /// Returns `false` for other keys, which edit as usual.
+    fn completion_key(&mut self, data: &mut XiState, k: &KeyEvent) -> bool {
+        let completion = match &mut data.completion {
+            Some(completion) => completion,
+            None => return false,
+        };
+        match &k.key {
+            KbKey::ArrowDown => completion.select_next(),
+            KbKey::ArrowUp => completion.select_prev(),
+            KbKey::Enter | KbKey::Tab => {
+                self.accept_completion(data);
+            }
+            KbKey::Escape => data.hide_completion(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Set a function to call after each edit that changes the text, with
+    /// the delta applied and the new selection.
+    ///
+    /// Edits that only change the selection don't call it.
+    pub fn set_edit_listener(&mut self, f: impl FnMut(&RopeDelta, &Selection) + 'static) {
+        self.edit_listener = Some(Box::new(f));
+    }
+
+    /// Set whether the widget is a single-line input.
+    ///
+    /// In single-line mode, Enter submits the text (see `submit`) rather
+    /// than inserting a line break, and line breaks are removed from
+    /// inserted text.
+    pub fn set_single_line(&mut self, single_line: bool) {
+        self.single_line = single_line;
+    }
+
+    /// Set the maximum length of the text, in grapheme clusters.
+    ///
+    /// Inserted text is truncated to fit, and other edits that would make
+    /// the text too long are rejected. Text that is already too long is
+    /// left as it is.
+    pub fn set_max_len(&mut self, max_len: Option<usize>) {
+        self.max_len = max_len;
+    }
+
+    /// Set a function to call when Enter is pressed in single-line mode,
+    /// with the text.
+    pub fn set_submit_listener(&mut self, f: impl FnMut(&Rope) + 'static) {
+        self.submit_listener = Some(Box::new(f));
+    }
+
+    /// Submit the text, as Enter does in single-line mode: call the submit
+    /// listener, and return the text for the `SUBMIT` command.
+    pub fn submit(&mut self, data: &XiState) -> String {
+        if let Some(f) = &mut self.submit_listener {
+            f(&data.text);
+        }
+        String::from(&data.text)
+    }
+
+    /// Bring the layouts and cursors up to date after the data changed.
+    ///
+    /// Returns `true` if the layouts changed.
+    fn update_data(
+        &mut self,
+        old_data: &XiState,
+        data: &XiState,
+        factory: &mut impl LayoutFactory,
+    ) -> bool {
+        let follow = self.log_mode && self.is_scrolled_to_bottom();
+        // If only the selection changed, keep the layouts, so the cursors
+        // that didn't move can be reused. Styles and folds only change
+        // outside the edited paragraphs when they are set wholesale.
+        let relayout = if !data.same_text(old_data) {
+            self.update_layouts_incremental(&old_data.text, data, factory);
+            true
+        } else if !old_data.styles.same(&data.styles) || !old_data.folds.same(&data.folds) {
+            self.update_layouts(data, factory);
+            true
+        } else {
+            false
+        };
+        if relayout {
+            // The target of a smooth scroll was for the old layouts, so
+            // finish it now, rather than heading somewhere else.
+            if let Some(anim) = self.scroll_anim.take() {
+                self.scroll_y = clamp_scroll(&self.layouts, anim.to, self.viewport_height);
+            }
+        }
+        if relayout && follow {
+            self.scroll_y = max_scroll(&self.layouts, self.viewport_height);
+        }
+        self.update_cursors(data);
+        relayout
+    }
+
+    /// Switch to the colors of a new theme.
+    ///
+    /// The text color is part of the layouts, so changing it lays out all
+    /// the paragraphs again. Returns `true` if anything needs repainting.
+    fn update_theme(
+        &mut self,
+        theme: Theme,
+        data: &XiState,
+        factory: &mut impl LayoutFactory,
+    ) -> bool {
+        if theme == self.theme {
+            return false;
+        }
+        let relayout = theme.text != self.theme.text;
+        self.theme = theme;
+        if relayout {
+            self.update_layouts(data, factory);
+            self.update_cursors(data);
+        }
+        true
+    }
+
+    /// Set the maximum number of paragraphs to lay out.
+    ///
+    /// When a document has more paragraphs than this, only a window of
+    /// paragraphs around the viewport is laid out, and the others are
+    /// placeholders, with the height of a single line, until they are
+    /// scrolled into view. Carets in placeholder paragraphs are not drawn.
+    pub fn set_layout_limit(&mut self, limit: Option<usize>) {
+        self.layout_limit = limit;
+    }
+
+    /// Lay out the whole document again.
+    pub fn update_layouts(&mut self, data: &XiState, factory: &mut impl LayoutFactory) {
+        let window = self.layout_window(data.line_count());
+        let mut builder = LayoutRopeBuilder::new();
+        let mut offset = 0;
+        for (para_ix, l) in paragraphs(&data.text).enumerate() {
+            let layout = if window.contains(&para_ix) {
+                self.build_layout(data, para_ix, offset, &l, factory)
+            } else {
+                self.placeholder(data.is_folded(para_ix))
+            };
+            builder.push_layout(layout);
+            offset += l.len();
+        }
+        self.layouts = builder.build();
+        self.window = window;
+    }
+
+    /// A placeholder for a paragraph outside the layout window, with the
+    /// height of a single line.
+    fn placeholder(&self, hidden: bool) -> Layout {
+        let estimate = Height::from_f64(ESTIMATED_LINE_HEIGHT * self.line_spacing());
+        Layout::placeholder(estimate).with_hidden(hidden)
+    }
+
+    /// The range of paragraphs to lay out, given the number of paragraphs.
+    ///
+    /// This is all of them, unless there are more than the layout limit.
+    fn layout_window(&self, n_paras: usize) -> Range<usize> {
+        match self.layout_limit {
+            Some(limit) if n_paras > limit => {
+                let (top, _) = self.para_at_y(self.scroll_y);
+                // Keep a margin above the viewport, for scrolling up.
+                let start = top.saturating_sub(limit / 4).min(n_paras - limit);
+                start..start + limit
+            }
+            _ => 0..n_paras,
+        }
+    }
+
+    /// Move the window of laid out paragraphs to the viewport, laying out
+    /// the placeholders that enter it, and turning the layouts that leave it
+    /// back into placeholders, so that no more than the layout limit are
+    /// laid out.
+    ///
+    /// Returns `true` if any layouts changed.
+    fn lay_out_window(&mut self, data: &XiState, factory: &mut impl LayoutFactory) -> bool {
+        let window = self.layout_window(self.layouts.len());
+        let mut changed = false;
+        let old_window = self.window.start..self.window.end.min(self.layouts.len());
+        for para_ix in old_window.filter(|para_ix| !window.contains(para_ix)) {
+            let layout = &self.layouts[para_ix];
+            if !layout.is_placeholder() {
+                let placeholder = self.placeholder(layout.is_hidden());
+                self.layouts.set(para_ix, placeholder);
+                changed = true;
+            }
+        }
+        self.window = window.clone();
+        for para_ix in window {
+            if self.layouts[para_ix].is_placeholder() {
+                let offset = data.text.offset_of_line(para_ix);
+                let next = data.text.offset_of_line(para_ix + 1);
+                let l = data.text.slice_to_cow(offset..next);
+                let layout = self.build_layout(data, para_ix, offset, &l, factory);
+                self.layouts.set(para_ix, layout);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Update the layouts after the text changed from `old_text`.
+    ///
+    /// Only the paragraphs whose text changed are laid out again (see
+    /// `util::changed_paragraphs`); the layouts of the others are shared
+    /// with the old rope. The number of paragraphs may change, for example
+    /// when pasting text with newlines.
+    fn update_layouts_incremental(
+        &mut self,
+        old_text: &Rope,
+        data: &XiState,
+        factory: &mut impl LayoutFactory,
+    ) {
+        let text = &data.text;
+        let range = util::changed_range(old_text, text);
+        let (start, old_end, new_end) = range;
+        let first_para = text.line_of_offset(start);
+        let old_last_para = old_text.line_of_offset(old_end);
+        let new_last_para = text.line_of_offset(new_end);
+        let delta = range_delta(old_text, text, range);
+        let changed = util::changed_paragraphs(old_text, &delta, text);
+        // The window moves with the paragraphs after the change.
+        let shift = |ix: usize| {
+            if ix > old_last_para {
+                ix + new_last_para - old_last_para
+            } else {
+                ix.min(new_last_para + 1)
+            }
+        };
+        self.window = shift(self.window.start)..shift(self.window.end);
+
+        let mut builder = LayoutRopeBuilder::new();
+        let mut offset = text.offset_of_line(first_para);
+        for para_ix in first_para..=new_last_para {
+            let next = text.offset_of_line(para_ix + 1);
+            if changed.binary_search(&para_ix).is_ok() {
+                let layout = if self.layout_limit.is_none() || self.window.contains(&para_ix) {
+                    let l = text.slice_to_cow(offset..next);
+                    self.build_layout(data, para_ix, offset, &l, factory)
+                } else {
+                    self.placeholder(data.is_folded(para_ix))
+                };
+                builder.push_layout(layout);
+            } else {
+                // An unchanged paragraph here comes after the change, as when
+                // whole lines are inserted or deleted, so it only moved.
+                let old_ix = para_ix + old_last_para - new_last_para;
+                builder.push_rope_slice(&self.layouts, old_ix..old_ix + 1);
+            }
+            offset = next;
+        }
+        self.layouts
+            .edit(first_para..old_last_para + 1, builder.build());
+    }
+
+    /// Build the layout of a paragraph, given its raw text and offset.
+    fn build_layout(
+        &mut self,
+        data: &XiState,
+        para_ix: usize,
+        offset: usize,
+        l: &str,
+        factory: &mut impl LayoutFactory,
+    ) -> Layout {
+        #[cfg(test)]
+        {
+            self.n_layouts_built += 1;
+        }
+        let trim = line_ending::trim_line_ending(l);
+        let font_size = match &self.font_size_fn {
+            Some(f) => f(para_ix, trim),
+            None => FONT_SIZE,
+        };
+        let para_range = offset..offset + trim.len();
+        let colors: Vec<_> = highlight::para_spans(&data.styles, para_range)
+            .map(|(range, color)| (range, color.clone()))
+            .collect();
+        let max_width = self.wrap_width();
+        factory
+            .make_layout(
+                trim,
+                max_width,
+                font_size,
+                &self.theme.text,
+                &colors,
+                self.long_words,
+            )
+            .with_line_spacing(self.line_spacing())
+            .with_hidden(data.is_folded(para_ix))
+    }
+
+    fn wrap_width(&self) -> f64 {
+        self.wrap_width.unwrap_or(MAX_WIDTH)
+    }
+
+    /// Wrap the paragraphs to a new width.
+    ///
+    /// Every paragraph is laid out again, and the cursors are computed from
+    /// the selection's offsets with the new layouts, so each caret stays on
+    /// the same character. Returns `false` if the width didn't change.
+    fn set_wrap_width(
+        &mut self,
+        width: f64,
+        data: &XiState,
+        factory: &mut impl LayoutFactory,
+    ) -> bool {
+        if width == self.wrap_width() {
+            return false;
+        }
+        self.wrap_width = Some(width);
+        self.update_layouts(data, factory);
+        self.update_cursors(data);
+        true
+    }
+
+    fn line_spacing(&self) -> f64 {
+        self.line_spacing.unwrap_or(1.0)
+    }
+
+    /// Recompute the cursors and bracket highlights from the current
+    /// layouts and selection.
+    ///
+    /// This doesn't change the layouts, and cursors whose offset and
+    /// paragraph haven't changed are reused, so calling it again without
+    /// changes is cheap and gives the same result. After something that
+    /// changes cursor geometry without an edit, like the caret shape,
+    /// clear `cursor_cache` first.
+    fn update_cursors(&mut self, data: &XiState) {
+        self.cursors.clear();
+        self.has_primary_cursor = false;
+        let mut old_cache = std::mem::take(&mut self.cursor_cache);
+        for (i, sel_region) in data.sel.iter().enumerate() {
+            let cursor_offset = sel_region.end;
+            let cached = old_cache
+                .remove(&cursor_offset)
+                .filter(|c| c.is_valid(&data.text, &self.layouts));
+            let cached = match cached {
+                Some(cached) => cached,
+                None => match self.compute_cursor(&data.text, cursor_offset) {
+                    Some(cached) => cached,
+                    None => continue,
+                },
+            };
+            self.cursors.push((cached.para_ix, cached.rect));
+            self.has_primary_cursor |= i == 0;
+            self.cursor_cache.insert(cursor_offset, cached);
+        }
+        self.update_brackets(data);
+    }
+
+    /// Recompute the bracket highlights, for a caret next to a bracket.
+    fn update_brackets(&mut self, data: &XiState) {
+        self.brackets.clear();
+        if let Some(region) = data.sel.primary().filter(|r| r.is_caret()) {
+            let text = &data.text;
+            if let Some((a, b)) =
+                bracket::find_matching_bracket(text, region.end, bracket::DEFAULT_MAX_SCAN)
+            {
+                self.brackets.extend(self.char_rect(text, a));
+                self.brackets.extend(self.char_rect(text, b));
+            }
+        }
+    }
+
+    /// Compute the position of a cursor, or `None` if its paragraph is a
+    /// placeholder or hidden.
+    fn compute_cursor(&mut self, text: &Rope, cursor_offset: usize) -> Option<CachedCursor> {
+        #[cfg(test)]
+        {
+            self.n_cursors_computed += 1;
+        }
+        let para_ix = text.line_of_offset(cursor_offset);
+        let para_start = text.offset_of_line(para_ix);
+        let layout = self.layouts.get_arc(para_ix).unwrap().1;
+        if layout.is_placeholder() || layout.is_hidden() {
+            return None;
+        }
+        let hit = layout.hit_test_text_position(cursor_offset - para_start);
+        // TODO: use line metrics, but good enough for a quick hack.
+        let pt = hit.point - Vec2::new(0.0, 12.0);
+        let height = 18.0;
+        let width = match self.caret_shape {
+            CaretShape::Bar => 0.0,
+            _ => caret_width(&layout, text, cursor_offset),
+        };
+        let rect = Rect::from_origin_size(pt, (width, height));
+        Some(CachedCursor {
+            para_ix,
+            para_start,
+            layout,
+            rect,
+        })
+    }
+
+    /// The bounds of the (single byte) character at the offset, as a
+    /// paragraph number and a rectangle relative to that paragraph, or
+    /// `None` if the paragraph is a placeholder.
+    fn char_rect(&self, text: &Rope, offset: usize) -> Option<(usize, Rect)> {
+        let para_ix = text.line_of_offset(offset);
+        let para_start = text.offset_of_line(para_ix);
+        let layout = self.layouts.get(para_ix).unwrap().1;
+        if layout.is_placeholder() || layout.is_hidden() {
+            return None;
+        }
+        let hit0 = layout.hit_test_text_position(offset - para_start);
+        let hit1 = layout.hit_test_text_position(offset + 1 - para_start);
+        // TODO: use line metrics, as with the cursors.
+        let p0 = hit0.point - Vec2::new(0.0, 12.0);
+        let p1 = Point::new(hit1.point.x, p0.y + 18.0);
+        Some((para_ix, Rect::from_points(p0, p1)))
+    }
+
+    fn apply_edit_op(&mut self, data: &mut XiState, op: EditOp) {
+        let op = match self.filter_edit_op(data, op) {
+            Some(op) => op,
+            None => return,
+        };
+        let kind = match op {
+            EditOp::Insert(_)
+            | EditOp::Overwrite(_)
+            | EditOp::InsertTab { .. }
+            | EditOp::Surround { .. } => EditKind::Insert,
+            _ => EditKind::Other,
+        };
+        let before = data.snapshot();
+        let measurement = self.measurement();
+        if let Some(delta) = data.apply_edit_op(op, &measurement) {
+            if self.exceeds_max_len(&before.text, &data.text) {
+                data.restore(before);
+                return;
+            }
+            self.undo_stack
+                .record(before, data.snapshot(), kind, Instant::now());
+            self.text_changed(data, &delta);
+        } else {
+            self.undo_stack.break_unit();
+        }
+    }
+
+    /// Apply single-line mode and the length limit to the text an edit
+    /// inserts.
+    ///
+    /// Returns `None` if the edit inserted text and none is left.
+    fn filter_edit_op(&self, data: &XiState, op: EditOp) -> Option<EditOp> {
+        let filter = |s: String| {
+            if s.is_empty() {
+                return Some(s);
+            }
+            let s = self.filter_insert(data, s);
+            if s.is_empty() {
+                None
+            } else {
+                Some(s)
+            }
+        };
+        match op {
+            EditOp::Insert(s) => filter(s).map(EditOp::Insert),
+            EditOp::Overwrite(s) => filter(s).map(EditOp::Overwrite),
+            EditOp::InsertWithDrift { text, after, drift } => {
+                filter(text).map(|text| EditOp::InsertWithDrift { text, after, drift })
+            }
+            op => Some(op),
+        }
+    }
+
+    /// Remove line breaks in single-line mode, and truncate to the room
+    /// left under the length limit, shared between the regions.
+    fn filter_insert(&self, data: &XiState, s: String) -> String {
+        let s = if self.single_line {
+            s.replace(&['\r', '\n'][..], "")
+        } else {
+            s
+        };
+        match self.max_len {
+            Some(max_len) => {
+                // The selected text is replaced, so it makes room.
+                let selected: usize = data
+                    .sel
+                    .iter()
+                    .map(|r| stats::grapheme_count(&data.text.subseq(r.min()..r.max())))
+                    .sum();
+                let len = stats::grapheme_count(&data.text);
+                let room = (max_len + selected).saturating_sub(len) / data.sel.len().max(1);
+                truncate_graphemes(&s, room).to_string()
+            }
+            None => s,
+        }
+    }
+
+    /// Whether an edit from `old` to `new` made the text longer than the
+    /// length limit.
+    fn exceeds_max_len(&self, old: &Rope, new: &Rope) -> bool {
+        match self.max_len {
+            Some(max_len) => {
+                let len = stats::grapheme_count(new);
+                len > max_len && len > stats::grapheme_count(old)
+            }
+            None => false,
+        }
+    }
+
+    /// The text to copy: the selected text, or at a caret, its whole line
+    /// (see `clipboard::copy_text`).
+    pub fn copy(&self, data: &XiState) -> String {
+        clipboard::copy_text(&data.text, &data.sel)
+    }
+
+    /// Delete the text to copy, returning it.
+    pub fn cut(&mut self, data: &mut XiState) -> String {
+        let text = self.copy(data);
+        self.apply_edit_op(data, EditOp::Cut);
+        text
+    }
+
+    /// Undo the last unit of edits.
+    ///
+    /// Returns `false` if there was nothing to undo.
+    pub fn undo(&mut self, data: &mut XiState) -> bool {
+        self.undo_stack.rebase_appended(&data.text);
+        match self.undo_stack.undo() {
+            Some(snapshot) => {
+                self.restore(data, snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Redo the last undone unit of edits.
+    ///
+    /// Returns `false` if there was nothing to redo.
+    pub fn redo(&mut self, data: &mut XiState) -> bool {
+        self.undo_stack.rebase_appended(&data.text);
+        match self.undo_stack.redo() {
+            Some(snapshot) => {
+                self.restore(data, snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn restore(&mut self, data: &mut XiState, snapshot: Snapshot) {
+        if let Some(delta) = data.restore(snapshot) {
+            self.text_changed(data, &delta);
+        }
+    }
+
+    /// Update the styles, folds, and diagnostics after the text was edited,
+    /// and notify the edit listener.
+    fn text_changed(&mut self, data: &mut XiState, delta: &RopeDelta) {
+        data.update_styles(delta, &mut *self.highlighter);
+        data.update_folds(delta);
+        data.update_diagnostics(delta);
+        if let Some(f) = &mut self.edit_listener {
+            f(delta, &data.sel);
+        }
+    }
+
+    /// The paragraph at a y coordinate, and the y coordinate of its top.
+    ///
+    /// Coordinates are in paint space. Points above the text map to the
+    /// first paragraph, and below it to the last.
+    fn para_at_y(&self, y: f64) -> (usize, f64) {
+        para_at_y(&self.layouts, y)
+    }
+
+    fn measurement(&self) -> XiMeasurement {
+        XiMeasurement {
+            layouts: &self.layouts,
+            newline_width: NEWLINE_WIDTH,
+            leaf: Cell::new(None),
+            #[cfg(test)]
+            n_traversals: Cell::new(0),
+        }
+    }
+}
+
+/// A wavy line from `x0` to `x1`, centered on `y`, for underlining.
+fn squiggle(x0: f64, x1: f64, y: f64) -> BezPath {
+    const PERIOD: f64 = 4.0;
+    const AMPLITUDE: f64 = 1.0;
+    let mut path = BezPath::new();
+    path.move_to((x0, y));
+    let mut x = x0;
+    let mut up = true;
+    while x < x1 {
+        let next = (x + PERIOD / 2.0).min(x1);
+        let peak = if up { y - AMPLITUDE } else { y + AMPLITUDE };
+        path.quad_to(((x + next) / 2.0, peak), (next, y));
+        x = next;
+        up = !up;
+    }
+    path
+}
+
+/// A delta from one text to another, replacing the range where they
+/// differ, or `None` if they are the same.
+fn diff_delta(old: &Rope, new: &Rope) -> Option<RopeDelta> {
+    let range = util::changed_range(old, new);
+    let (start, old_end, new_end) = range;
+    if start == old_end && start == new_end {
+        return None;
+    }
+    Some(range_delta(old, new, range))
+}
+
+/// A delta from one text to another, replacing a range given as by
+/// `util::changed_range`.
+fn range_delta(old: &Rope, new: &Rope, range: (usize, usize, usize)) -> RopeDelta {
+    let (start, old_end, new_end) = range;
+    let mut builder = DeltaBuilder::new(old.len());
+    builder.replace(start..old_end, new.subseq(start..new_end));
+    builder.build()
+}
+
+/// The raw text of each paragraph, including the line ending.
+///
+/// Unlike `lines_raw`, this includes the last (empty) line after a trailing
+/// newline, so that we get a layout and can draw the cursor there.
+fn paragraphs(text: &Rope) -> impl Iterator<Item = Cow<str>> {
+    let trailing_empty = text.is_empty() || text.byte_at(text.len() - 1) == b'\n';
+    let last = if trailing_empty {
+        Some(Cow::from(""))
+    } else {
+        None
+    };
+    text.lines_raw(..).chain(last)
+}
+
+impl CachedCursor {
+    /// Whether the cursor is still valid for the same offset.
+    ///
+    /// If the paragraph starts at the same place and has the same layout,
+    /// its text is unchanged, and so is the position of the offset in it.
+    fn is_valid(&self, text: &Rope, layouts: &LayoutRope) -> bool {
+        match layouts.get_arc(self.para_ix) {
+            Some((_, layout)) => {
+                Arc::ptr_eq(&layout, &self.layout)
+                    && text.offset_of_line(self.para_ix) == self.para_start
+            }
+            None => false,
+        }
+    }
+}
+
+/// The area to repaint when the selection moves from `old` to `new`, as
+/// the union of the rectangles, padded to cover the stroke width.
+///
+/// Returns `None` if there is nothing to repaint.
+fn invalidation_rect(old: &[Rect], new: &[Rect]) -> Option<Rect> {
+    old.iter()
+        .chain(new)
+        .map(|rect| rect.inflate(1.0, 1.0))
+        .fold(None, |acc: Option<Rect>, rect| match acc {
+            Some(acc) => Some(acc.union(rect)),
+            None => Some(rect),
+        })
+}
+
+/// The width of the grapheme after a caret, for the block and underline
+/// caret shapes.
+///
+/// The layout is that of the paragraph containing the caret. At the end
+/// of a paragraph, there is no grapheme, and the caret is as wide as a
+/// space in the paragraph's font.
+fn caret_width(layout: &Layout, text: &Rope, offset: usize) -> f64 {
+    let para_ix = text.line_of_offset(offset);
+    let para_start = text.offset_of_line(para_ix);
+    let end = measurement::line_end(text, para_ix);
+    match text.next_grapheme_offset(offset) {
+        Some(next) if offset < end => {
+            let x0 = layout.hit_test_text_position(offset - para_start).point.x;
+            let x1 = layout.hit_test_text_position(next - para_start).point.x;
+            // Right-to-left text goes the other way.
+            (x1 - x0).abs()
+        }
+        _ => layout.space_width().unwrap_or(EMPTY_CARET_WIDTH),
+    }
+}
+
+/// The start of a string, up to `n` grapheme clusters.
+fn truncate_graphemes(s: &str, n: usize) -> &str {
+    let rope = Rope::from(s);
+    let mut end = 0;
+    for _ in 0..n {
+        match rope.next_grapheme_offset(end) {
+            Some(next) => end = next,
+            None => break,
+        }
+    }
+    &s[..end]
+}
+
+/// Clamp a scroll offset so the viewport stays within the text.
+fn clamp_scroll(layouts: &LayoutRope, scroll_y: f64, viewport_height: f64) -> f64 {
+    scroll_y.min(max_scroll(layouts, viewport_height)).max(0.0)
+}
+
+/// The scroll offset that shows the bottom of the text, or 0 if it fits.
+fn max_scroll(layouts: &LayoutRope, viewport_height: f64) -> f64 {
+    let content_height = TEXT_ORIGIN_Y + layouts.pixel_height();
+    (content_height - viewport_height).max(0.0)
+}
+
+/// The scroll offset that reveals the paragraph.
+fn reveal_scroll(
+    layouts: &LayoutRope,
+    para_ix: usize,
+    viewport_height: f64,
+    align: RevealAlign,
+) -> f64 {
+    // The same amount of padding as at the top of the document.
+    let top = layouts.pixel_height_of_index(para_ix);
+    let height = layouts
+        .height_at(para_ix)
+        .map(Height::to_f64)
+        .unwrap_or(0.0);
+    let target = match align {
+        RevealAlign::Top => top,
+        RevealAlign::Center => top + TEXT_ORIGIN_Y + 0.5 * (height - viewport_height),
+    };
+    clamp_scroll(layouts, target, viewport_height)
+}
+
+/// The range of the unit at an offset: empty for a character, or the
+/// word, or the whole line, including its line ending.
+fn unit_range(text: &Rope, offset: usize, unit: DragUnit) -> Range<usize> {
+    match unit {
+        DragUnit::Char => offset..offset,
+        DragUnit::Word => measurement::word_range(text, offset),
+        DragUnit::Line => {
+            let line = text.line_of_offset(offset);
+            text.offset_of_line(line)..text.offset_of_line(line + 1)
+        }
+    }
+}
+
+/// The selection when dragging from the `anchor` unit to the unit at an
+/// offset: it covers both, with the caret at the far end of the unit being
+/// dragged to.
+fn drag_region(text: &Rope, anchor: &Range<usize>, offset: usize, unit: DragUnit) -> SelRegion {
+    let target = unit_range(text, offset, unit);
+    if target.start < anchor.start {
+        SelRegion::new(anchor.end, target.start)
+    } else {
+        SelRegion::new(anchor.start, target.end.max(anchor.end))
+    }
+}
+
+/// The scroll offset a fraction `t` of the way through a smooth scroll,
+/// easing out so it slows down as it arrives.
+fn ease_scroll(from: f64, to: f64, t: f64) -> f64 {
+    let remaining = 1.0 - t;
+    from + (to - from) * (1.0 - remaining * remaining * remaining)
+}
+
+/// How far to scroll when dragging to a pointer y in widget coordinates,
+/// or `None` if the pointer is within the viewport.
+///
+/// The distance is how far the pointer is past the edge, so dragging
+/// further scrolls faster.
+fn auto_scroll_delta(y: f64, viewport_height: f64) -> Option<f64> {
+    if y < 0.0 {
+        Some(y)
+    } else if y > viewport_height {
+        Some(y - viewport_height)
+    } else {
+        None
+    }
+}
+
+fn para_at_y(layouts: &LayoutRope, y: f64) -> (usize, f64) {
+    // Hidden paragraphs take no space, so this is the shown one at the
+    // height, or the last shown one past the end.
+    let height = Height::from_f64(y - TEXT_ORIGIN_Y);
+    let para_ix = layouts.shown_index_of_height(height).unwrap_or(0);
+    let top = layouts.pixel_height_of_index(para_ix) + TEXT_ORIGIN_Y;
+    (para_ix, top)
+}
+
+impl XiState {
+    pub fn new(initial_text: impl Into<Rope>) -> XiState {
+        let text = initial_text.into();
+        let len = text.len();
+        let sel = Selection::new_simple(SelRegion::new(len, len));
+        let line_ending = LineEnding::detect(&text).unwrap_or_default();
+        XiState {
+            text,
+            history: next_revision(),
+            revision: next_revision(),
+            sel: Arc::new(sel),
+            line_ending,
+            styles: Default::default(),
+            palette: None,
+            completion: None,
+            folds: Default::default(),
+            diagnostics: Default::default(),
+        }
+    }
+
+    /// A state with the text read from a reader, such as stdin, as UTF-8.
+    ///
+    /// The text is read in chunks, without holding all of it in a single
+    /// string. The line ending convention is detected as with `new`.
+    ///
+    /// # Errors
+    ///
+    /// Read errors are passed on, and invalid UTF-8 is an error of kind
+    /// `InvalidData`.
+    pub fn from_reader<R: Read>(r: R) -> io::Result<XiState> {
+        Ok(XiState::new(util::read_rope(r)?))
+    }
+
+    /// Add text to the end of the document, as when following a log, with
+    /// line endings normalized to the document's convention.
+    ///
+    /// The selection stays where it is, even a caret at the end. The
+    /// appended text isn't an undo unit of its own, and undo keeps it.
+    pub fn append(&mut self, s: &str) {
+        let len = self.text.len();
+        let mut builder = DeltaBuilder::new(len);
+        builder.replace(len..len, Rope::from(self.line_ending.normalize(s)));
+        let delta = builder.build();
+        let sel = self.sel.apply_delta(&delta, false, InsertDrift::Default);
+        self.replace_selection(sel);
+        self.text = delta.apply(&self.text);
+        self.revision = next_revision();
+        self.update_folds(&delta);
+        self.update_diagnostics(&delta);
+    }
+
+    /// Fold a range of logical lines, hiding them.
+    ///
+    /// Folds that overlap or touch the range are merged with it. Hidden
+    /// lines are still part of the text, but take no space, and vertical
+    /// movement skips them. Carets in them aren't shown. Lines past the
+    /// last one are clamped to the end of the text.
+    pub fn fold_lines(&mut self, lines: Range<usize>) {
+        let n_lines = self.line_count();
+        let start = self.text.offset_of_line(lines.start.min(n_lines));
+        let mut fold = start..self.text.offset_of_line(lines.end.min(n_lines));
+        if fold.start >= fold.end {
+            return;
+        }
+        let mut folds = Vec::with_capacity(self.folds.len() + 1);
+        for other in self.folds.iter() {
+            if other.end < fold.start || other.start > fold.end {
+                folds.push(other.clone());
+            } else {
+                fold = fold.start.min(other.start)..fold.end.max(other.end);
+            }
+        }
+        folds.push(fold);
+        folds.sort_by_key(|fold| fold.start);
+        self.folds = Arc::new(folds);
+    }
+
+    /// Unfold the fold containing a logical line, if there is one.
+    pub fn unfold_line(&mut self, line: usize) {
+        if self.is_folded(line) {
+            let folds = self
+                .folds
+                .iter()
+                .filter(|fold| !self.fold_line_range(fold).contains(&line))
+                .cloned()
+                .collect();
+            self.folds = Arc::new(folds);
+        }
+    }
+
+    pub fn unfold_all(&mut self) {
+        self.folds = Default::default();
+    }
+
+    /// Whether a logical line is hidden by a fold.
+    pub fn is_folded(&self, line: usize) -> bool {
+        self.folds
+            .iter()
+            .any(|fold| self.fold_line_range(fold).contains(&line))
+    }
+
+    /// The logical lines a fold hides.
+    fn fold_line_range(&self, fold: &Range<usize>) -> Range<usize> {
+        self.text.line_of_offset(fold.start)..self.text.line_of_offset(fold.end - 1) + 1
+    }
+
+    /// Move the folds through an edit. A fold whose text was all deleted
+    /// is dropped.
+    fn update_folds(&mut self, delta: &RopeDelta) {
+        if self.folds.is_empty() {
+            return;
+        }
+        let mut transformer = Transformer::new(delta);
+        let folds = self
+            .folds
+            .iter()
+            .map(|fold| {
+                transformer.transform(fold.start, true)..transformer.transform(fold.end, false)
+            })
+            .filter(|fold| fold.start < fold.end)
+            .collect();
+        self.folds = Arc::new(folds);
+    }
+
+    pub fn diagnostics(&self) -> &[Interval] {
+        &self.diagnostics
+    }
+
+    /// Set the ranges of diagnostics, which are underlined with a squiggle
+    /// in `DIAGNOSTIC_COLOR`, and follow edits to the text.
+    pub fn set_diagnostics(&mut self, diagnostics: Vec<Interval>) {
+        self.diagnostics = Arc::new(diagnostics);
+    }
+
+    /// Move the diagnostics through an edit. A diagnostic whose text was all
+    /// deleted is dropped.
+    fn update_diagnostics(&mut self, delta: &RopeDelta) {
+        if self.diagnostics.is_empty() {
+            return;
+        }
+        let mut transformer = Transformer::new(delta);
+        let diagnostics = self
+            .diagnostics
+            .iter()
+            .map(|iv| {
+                Interval::new(
+                    transformer.transform(iv.start, true),
+                    transformer.transform(iv.end, false),
+                )
+            })
+            .filter(|iv| !iv.is_empty())
+            .collect();
+        self.diagnostics = Arc::new(diagnostics);
+    }
+
+    /// Set the highlighting styles.
+    ///
+    /// The spans must be sorted by start offset.
+    pub fn set_styles(&mut self, styles: Vec<StyleSpan>) {
+        self.styles = Arc::new(styles);
+    }
+
+    /// Apply an edit operation to the document.
+    ///
+    /// Line endings in inserted text are converted to the document's
+    /// convention. Returns the delta, if the text changed.
+    pub fn apply_edit_op(
+        &mut self,
+        op: EditOp,
+        measurement: &impl Measurement,
+    ) -> Option<RopeDelta> {
+        let op = match op {
+            EditOp::Insert(s) => EditOp::Insert(self.line_ending.normalize(&s)),
+            EditOp::Overwrite(s) => EditOp::Overwrite(self.line_ending.normalize(&s)),
+            EditOp::ReplaceLines { lines, text } => EditOp::ReplaceLines {
+                lines,
+                text: self.line_ending.normalize(&text),
+            },
+            EditOp::InsertWithDrift { text, after, drift } => EditOp::InsertWithDrift {
+                text: self.line_ending.normalize(&text),
+                after,
+                drift,
+            },
+            op => op,
+        };
+        let (new_sel, delta) = op.apply_with_delta(&mut self.text, &self.sel, measurement);
+        self.replace_selection(new_sel);
+        if delta.is_some() {
+            self.revision = next_revision();
+        }
+        delta
+    }
+
+    /// Apply a command, returning the delta applied to the text, if the text
+    /// changed.
+    pub fn apply_command(
+        &mut self,
+        command: &Command,
+        measurement: &dyn Measurement,
+    ) -> Option<RopeDelta> {
+        let old_text = self.text.clone();
+        let new_sel = command(&mut self.text, &self.sel, measurement);
+        self.replace_selection(new_sel);
+        // Commands only report the new text, so recover the delta by
+        // comparing it with the old.
+        let delta = diff_delta(&old_text, &self.text);
+        if delta.is_some() {
+            self.revision = next_revision();
+        }
+        delta
+    }
+
+    /// The text and selection, for the undo history.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            text: self.text.clone(),
+            sel: (*self.sel).clone(),
+        }
+    }
+
+    /// Restore a snapshot, returning the delta applied to the text, if the
+    /// text changed.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Option<RopeDelta> {
+        let old_text = std::mem::replace(&mut self.text, snapshot.text);
+        self.replace_selection(snapshot.sel);
+        let delta = diff_delta(&old_text, &self.text);
+        if delta.is_some() {
+            self.revision = next_revision();
+        }
+        delta
+    }
+
+    /// The revision of the text, which increases with every edit.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Whether the text is the same as another state's. States from the
+    /// same history compare revisions, and only unrelated ones compare the
+    /// text itself.
+    fn same_text(&self, other: &XiState) -> bool {
+        if self.history == other.history {
+            self.revision == other.revision
+        } else {
+            util::rope_eq(&self.text, &other.text)
+        }
+    }
+
+    /// Update the highlighting styles after an edit.
+    pub fn update_styles(&mut self, delta: &RopeDelta, highlighter: &mut dyn Highlighter) {
+        let styles = highlight::update_spans(&self.styles, delta, &self.text, highlighter);
+        self.styles = Arc::new(styles);
+    }
+
+    /// The logical line and grapheme column of the primary caret, both
+    /// zero-based.
+    pub fn caret_line_col(&self) -> Option<(usize, usize)> {
+        let region = self.sel.primary()?;
+        Some(measurement::offset_to_line_col(&self.text, region.end))
+    }
+
+    /// The number of logical lines in the document.
+    ///
+    /// A trailing newline starts a new (empty) line, which is counted.
+    pub fn line_count(&self) -> usize {
+        self.text.line_of_offset(self.text.len()) + 1
+    }
+
+    /// The number of characters, words, and lines in the document.
+    pub fn stats(&self) -> TextStats {
+        TextStats::of(&self.text)
+    }
+
+    /// The accessibility node for the document: a text field with its text
+    /// and selection.
+    // TODO: report this from an accessibility lifecycle event, once druid
+    // has one.
+    pub fn access_node(&self) -> AccessNode {
+        AccessNode {
+            role: AccessRole::TextField,
+            value: String::from(&self.text),
+            selection: self.sel.primary().map(|r| (r.start, r.end)),
+        }
+    }
+
+    /// Move the caret to the start of a line, given as a 1-based line
+    /// number. Out of range line numbers are clamped.
+    ///
+    /// Returns the new caret offset.
+    pub fn goto_line(&mut self, line: usize) -> usize {
+        let line = line.saturating_sub(1).min(self.line_count() - 1);
+        let offset = self.text.offset_of_line(line);
+        self.replace_selection(Selection::new_simple(SelRegion::caret(offset)));
+        offset
+    }
+
+    /// Set the selection.
+    ///
+    /// Offsets past the end of the text are clamped to the end, and offsets
+    /// within a grapheme cluster are moved to its start. Regions that
+    /// overlap as a result are merged.
+    pub fn set_selection(&mut self, sel: Selection) {
+        let mut clamped = Selection::new();
+        for region in &sel {
+            clamped.add_region(SelRegion {
+                start: measurement::grapheme_boundary(&self.text, region.start),
+                end: measurement::grapheme_boundary(&self.text, region.end),
+                ..*region
+            });
+        }
+        self.replace_selection(clamped);
+    }
+
+    /// Replace the selection, keeping the old one if it is the same, so
+    /// that nothing is allocated and `Data::same` sees no change.
+    fn replace_selection(&mut self, sel: Selection) {
+        if *self.sel != sel {
+            self.sel = Arc::new(sel);
+        }
+    }
+
+    /// Whether there is more than one selection region.
+    pub fn has_multiple_selections(&self) -> bool {
+        self.sel.len() > 1
+    }
+
+    /// The line ending convention of the document.
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    /// Set the line ending convention used when saving.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    /// Open the command palette, listing the given command names.
+    pub fn show_palette<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) {
+        self.palette = Some(CommandPalette::new(names));
+    }
+
+    /// Close the command palette, without running a command.
+    pub fn hide_palette(&mut self) {
+        self.palette = None;
+    }
+
+    /// The command palette, if it is open.
+    pub fn palette(&self) -> Option<&CommandPalette> {
+        self.palette.as_ref()
+    }
+
+    /// Open the completion popup, offering the given candidates.
+    pub fn show_completion(&mut self, candidates: impl IntoIterator<Item = impl Into<String>>) {
+        self.completion = Some(Completion::new(candidates));
+    }
+
+    pub fn hide_completion(&mut self) {
+        self.completion = None;
+    }
+
+    /// The completion popup, if it is open.
+    pub fn completion(&self) -> Option<&Completion> {
+        self.completion.as_ref()
+    }
+
+    /// Write the document, with line endings normalized to the document's
+    /// convention.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        line_ending::write_text_normalized(&self.text, self.line_ending, w)
+    }
+}
+
+impl<'a> XiMeasurement<'a> {
+    /// The layout of a logical line, or `None` if it is a placeholder.
+    ///
+    /// Placeholders are measured as a single visual line, with every offset
+    /// at the start.
+    fn text_layout(&self, line_num: usize) -> Option<&'a Layout> {
+        let layout = self.layout(line_num);
+        if layout.is_placeholder() {
+            None
+        } else {
+            Some(layout)
+        }
+    }
+
+    /// The layout of a logical line.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the line is out of range.
+    fn layout(&self, line_num: usize) -> &'a Layout {
+        let (start, leaf) = match self.leaf.get() {
+            Some((start, leaf)) if (start..start + leaf.len()).contains(&line_num) => (start, leaf),
+            _ => {
+                #[cfg(test)]
+                {
+                    self.n_traversals.set(self.n_traversals.get() + 1);
+                }
+                let found = self.layouts.leaf_at(line_num).unwrap();
+                self.leaf.set(Some(found));
+                found
+            }
+        };
+        &leaf[line_num - start].1
+    }
+
+    /// The horizontal extent of a selection on a visual line, for drawing
+    /// the selection background.
+    ///
+    /// The offsets are relative to the start of the logical line, as with
+    /// `Measurement`, and the selection may extend beyond the visual line
+    /// on either side. When it extends past the end of a visual line, the
+    /// range reaches the right edge of the layout, or, on the last visual
+    /// line, the end of the text plus `newline_width`, to show that the line
+    /// break is selected.
+    fn visual_line_x_range(
+        &self,
+        line_num: usize,
+        visual_line: usize,
+        start_off: usize,
+        end_off: usize,
+    ) -> (f64, f64) {
+        let layout = match self.text_layout(line_num) {
+            Some(layout) => layout,
+            None => return (0.0, 0.0),
+        };
+        let metric = match layout.line_metric(visual_line) {
+            Some(metric) => metric,
+            None => return (0.0, 0.0),
+        };
+        let is_last = visual_line + 1 == layout.line_count();
+        let x0 = if start_off <= metric.start_offset {
+            0.0
+        } else {
+            layout.hit_test_text_position(start_off).point.x
+        };
+        let x1 = if end_off < metric.end_offset || (is_last && end_off == metric.end_offset) {
+            layout.hit_test_text_position(end_off).point.x
+        } else if is_last {
+            layout.hit_test_text_position(metric.end_offset).point.x + self.newline_width
+        } else {
+            layout.width()
+        };
+        (x0, x1)
+    }
+}
+
+impl<'a> Measurement for XiMeasurement<'a> {
+    fn n_visual_lines(&self, line_num: usize) -> usize {
+        if self.layout(line_num).is_hidden() {
+            return 0;
+        }
+        match self.text_layout(line_num) {
+            Some(layout) => layout.line_count(),
+            None => 1,
+        }
+    }
+
+    fn to_pos(&self, line_num: usize, offset: usize) -> (f64, usize) {
+        match self.text_layout(line_num) {
+            Some(layout) => {
+                let hit = layout.hit_test_text_position(offset);
+                (hit.point.x, hit.line)
+            }
+            None => (0.0, 0),
+        }
+    }
+
+    fn from_pos(&self, line_num: usize, horiz: f64, visual_line: usize) -> usize {
+        let layout = match self.text_layout(line_num) {
+            Some(layout) => layout,
+            None => return 0,
+        };
+        // A visual line past the end shouldn't happen, but if it does, use
+        // the last one, so that the caret doesn't jump to the start.
+        let last_line = layout.line_count().saturating_sub(1);
+        match layout.line_metric(visual_line.min(last_line)) {
+            Some(metric) => {
+                let y = metric.y_offset + 0.5 * metric.height;
+                let point = Point::new(horiz, y);
+                layout.hit_test_point(point)
+            }
+            // A layout with no lines has nowhere else to go.
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::ops::Range;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use xi_rope::{Interval, Rope};
+    use xi_text_core::{EditOp, LineEnding, Measurement, Movement, SelRegion, Selection};
+
+    use druid::kurbo::{Point, Rect};
+    use druid::piet::Color;
+    use druid::{Data, Env};
+
+    use super::{
+        auto_scroll_delta, caret_width, drag_region, invalidation_rect, para_at_y, paragraphs,
+        reveal_scroll, unit_range, AccessNode, AccessRole, CaretShape, Drag, DragUnit, EditWidget,
+        LayoutFactory, LongWords, RevealAlign, StubFactory, Theme, XiState, CARET_COLOR,
+        EMPTY_CARET_WIDTH, FONT_SIZE, MAX_WIDTH, NEWLINE_WIDTH, TEXT_COLOR, TEXT_ORIGIN_X,
+        TEXT_ORIGIN_Y,
+    };
+    use crate::command::Command;
+    use crate::decoration::{Decoration, DecorationItem, LineGeometry};
+    use crate::highlight::StyleSpan;
+    use crate::layout_rope::{Height, Layout, LayoutRope, StubLayout, N_LOOKUPS};
+    use crate::test_util::{make_layout, make_layouts, make_sized_layouts, with_text};
+
+    /// A measurement for edits that don't need one.
+    struct NullMeasurement;
+
+    impl Measurement for NullMeasurement {
+        fn n_visual_lines(&self, _line_num: usize) -> usize {
+            1
+        }
+
+        fn to_pos(&self, _line_num: usize, offset: usize) -> (f64, usize) {
+            (offset as f64, 0)
+        }
+
+        fn from_pos(&self, _line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+            horiz as usize
+        }
+    }
+
+    /// A layout factory that doesn't need a render context, and records
+    /// what it lays out.
+    ///
+    /// Its layouts are placeholders, as tall as their font size, so they
+    /// can't be hit tested.
+    #[derive(Default)]
+    struct MockFactory {
+        /// The text, font size, and colored ranges of each layout.
+        made: Vec<(String, f64, Vec<Range<usize>>)>,
+        /// The text color of each layout.
+        text_colors: Vec<Color>,
+        /// What happens to long words in each layout.
+        long_words: Vec<LongWords>,
+    }
+
+    impl LayoutFactory for MockFactory {
+        fn make_layout(
+            &mut self,
+            text: &str,
+            _max_width: f64,
+            font_size: f64,
+            text_color: &Color,
+            colors: &[(Range<usize>, Color)],
+            long_words: LongWords,
+        ) -> Layout {
+            let ranges = colors.iter().map(|(range, _)| range.clone()).collect();
+            self.made.push((text.to_string(), font_size, ranges));
+            self.text_colors.push(text_color.clone());
+            self.long_words.push(long_words);
+            Layout::placeholder(Height::from_f64(font_size))
+        }
+    }
+
+    fn text(state: &XiState) -> String {
+        String::from(&state.text)
+    }
+
+    #[test]
+    fn y_to_para() {
+        let layouts: LayoutRope =
+            make_sized_layouts(vec![("small", 10.0), ("big", 40.0), ("medium", 20.0)])
+                .into_iter()
+                .collect();
+        let tops: Vec<f64> = (0..3)
+            .map(|i| layouts.pixel_height_of_index(i) + TEXT_ORIGIN_Y)
+            .collect();
+        let bottom = layouts.pixel_height() + TEXT_ORIGIN_Y;
+        assert!(tops[1] - tops[0] < tops[2] - tops[1]);
+        assert_eq!(para_at_y(&layouts, -100.0), (0, tops[0]));
+        assert_eq!(para_at_y(&layouts, 0.0), (0, tops[0]));
+        assert_eq!(para_at_y(&layouts, tops[0] + 1.0), (0, tops[0]));
+        assert_eq!(para_at_y(&layouts, tops[1]), (1, tops[1]));
+        assert_eq!(para_at_y(&layouts, tops[2] - 1.0), (1, tops[1]));
+        assert_eq!(para_at_y(&layouts, tops[2] + 1.0), (2, tops[2]));
+        assert_eq!(para_at_y(&layouts, bottom), (2, tops[2]));
+        assert_eq!(para_at_y(&layouts, bottom + 100.0), (2, tops[2]));
+    }
+
+    #[test]
+    fn trailing_empty_paragraph() {
+        let paras = |s: &str| -> Vec<String> {
+            paragraphs(&Rope::from(s)).map(|p| p.into_owned()).collect()
+        };
+        assert_eq!(paras(""), vec![""]);
+        assert_eq!(paras("a"), vec!["a"]);
+        assert_eq!(paras("a\n"), vec!["a\n", ""]);
+        assert_eq!(paras("a\r\nb\r\n"), vec!["a\r\n", "b\r\n", ""]);
+        assert_eq!(paras("a\n\nb"), vec!["a\n", "\n", "b"]);
+    }
+
+    #[test]
+    fn trailing_empty_paragraph_large() {
+        // About 1MB. The empty paragraph is synthesized, not built by
+        // concatenating to the rope.
+        let text = Rope::from("0123456789abcdef\n".repeat(60_000));
+        let mut count = 0;
+        let mut last = None;
+        for para in paragraphs(&text) {
+            count += 1;
+            last = Some(para);
+        }
+        assert_eq!(count, 60_001);
+        assert!(matches!(last, Some(Cow::Borrowed(""))));
+
+        let state = XiState::new(text);
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        assert_eq!(widget.layouts.len(), 60_001);
+        widget.update_cursors(&state);
+        assert_eq!(widget.cursors.len(), 1);
+        assert_eq!(widget.cursors[0].0, 60_000);
+    }
+
+    #[test]
+    fn reveal() {
+        let layouts: LayoutRope = make_sized_layouts(std::iter::repeat(("x", 14.0)).take(100))
+            .into_iter()
+            .collect();
+        let line_height = layouts.height_at(0).unwrap().to_f64();
+        let total = layouts.pixel_height();
+        let viewport = 10.0 * line_height;
+        let max_scroll = TEXT_ORIGIN_Y + total - viewport;
+
+        assert_eq!(reveal_scroll(&layouts, 0, viewport, RevealAlign::Top), 0.0);
+        assert_eq!(
+            reveal_scroll(&layouts, 0, viewport, RevealAlign::Center),
+            0.0
+        );
+        let top_50 = layouts.pixel_height_of_index(50);
+        assert_eq!(
+            reveal_scroll(&layouts, 50, viewport, RevealAlign::Top),
+            top_50
+        );
+        let center_50 = top_50 + TEXT_ORIGIN_Y + 0.5 * (line_height - viewport);
+        assert_eq!(
+            reveal_scroll(&layouts, 50, viewport, RevealAlign::Center),
+            center_50
+        );
+        // Near the end, the scroll is clamped so the viewport isn't past
+        // the end of the document.
+        assert_eq!(
+            reveal_scroll(&layouts, 98, viewport, RevealAlign::Top),
+            max_scroll
+        );
+        assert_eq!(
+            reveal_scroll(&layouts, 98, viewport, RevealAlign::Center),
+            max_scroll
+        );
+        // Short documents don't scroll at all.
+        assert_eq!(
+            reveal_scroll(&layouts, 99, total * 2.0, RevealAlign::Top),
+            0.0
+        );
+    }
+
+    #[test]
+    fn auto_scroll_trigger() {
+        let viewport = 100.0;
+        assert_eq!(auto_scroll_delta(50.0, viewport), None);
+        // The edges themselves are inside.
+        assert_eq!(auto_scroll_delta(0.0, viewport), None);
+        assert_eq!(auto_scroll_delta(viewport, viewport), None);
+        // Past an edge, scroll by the distance past it.
+        assert_eq!(auto_scroll_delta(-5.0, viewport), Some(-5.0));
+        assert_eq!(auto_scroll_delta(130.0, viewport), Some(30.0));
+    }
+
+    #[test]
+    fn drag_by_word() {
+        let text = Rope::from("one two  three\nfour");
+        let anchor = unit_range(&text, 5, DragUnit::Word);
+        assert_eq!(anchor, 4..7);
+        let drag = |offset| drag_region(&text, &anchor, offset, DragUnit::Word);
+        // Within the anchor word, just the word.
+        assert_eq!(drag(6), SelRegion::new(4, 7));
+        // Forward, to the end of the word dragged to.
+        assert_eq!(drag(11), SelRegion::new(4, 14));
+        assert_eq!(drag(8), SelRegion::new(4, 9));
+        // Backward, to its start, keeping the whole anchor word.
+        assert_eq!(drag(1), SelRegion::new(7, 0));
+        // By character, the ends aren't snapped.
+        assert_eq!(
+            drag_region(&text, &(5..5), 11, DragUnit::Char),
+            SelRegion::new(5, 11)
+        );
+    }
+
+    #[test]
+    fn drag_by_line() {
+        let text = Rope::from("one\ntwo\nthree");
+        let anchor = unit_range(&text, 5, DragUnit::Line);
+        assert_eq!(anchor, 4..8);
+        let drag = |offset| drag_region(&text, &anchor, offset, DragUnit::Line);
+        assert_eq!(drag(10), SelRegion::new(4, 13));
+        assert_eq!(drag(1), SelRegion::new(8, 0));
+    }
+
+    #[test]
+    fn mixed_font_sizes() {
+        let mut state = XiState::new("# Heading\nbody text\nmore body");
+        let mut widget = EditWidget::default();
+        widget.set_font_size_fn(|_, text| if text.starts_with('#') { 28.0 } else { 14.0 });
+        with_text(|factory| widget.update_layouts(&state, factory));
+        let layouts = &widget.layouts;
+        assert_eq!(layouts.len(), 3);
+        let heading = layouts.height_of_index(1);
+        let body = layouts.height_of_index(2) - heading;
+        assert!(heading > body);
+        assert_eq!(layouts.height(), heading + body + body);
+
+        // Move down from the heading into the smaller paragraph.
+        state.sel = Arc::new(Selection::new_simple(SelRegion::caret(2)));
+        state.apply_edit_op(EditOp::Move(Movement::Down), &widget.measurement());
+        let offset = state.sel[0].end;
+        assert!((10..=19).contains(&offset), "offset {}", offset);
+        state.apply_edit_op(EditOp::Move(Movement::Up), &widget.measurement());
+        assert!(state.sel[0].end <= 9);
+    }
+
+    #[test]
+    fn mock_layouts() {
+        let mut state = XiState::new("one\ntwo\n");
+        state.set_styles(vec![StyleSpan {
+            range: 5..9,
+            color: Color::rgb8(0xff, 0, 0),
+        }]);
+        let mut widget = EditWidget::default();
+        widget.set_font_size_fn(|para_ix, _| 10.0 + para_ix as f64);
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&state, &mut factory);
+        // Line endings aren't laid out, and colors are relative to the
+        // paragraph.
+        assert_eq!(
+            factory.made,
+            vec![
+                ("one".to_string(), 10.0, vec![]),
+                ("two".to_string(), 11.0, vec![1..3]),
+                ("".to_string(), 12.0, vec![]),
+            ]
+        );
+        assert_eq!(widget.layouts.height(), Height::from_f64(33.0));
+
+        // An edit lays out only its paragraph.
+        let old_text = state.text.clone();
+        state.set_selection(Selection::new_simple(SelRegion::caret(5)));
+        state.apply_edit_op(EditOp::Insert("W".into()), &NullMeasurement);
+        factory.made.clear();
+        widget.update_layouts_incremental(&old_text, &state, &mut factory);
+        let made: Vec<_> = factory
+            .made
+            .iter()
+            .map(|(s, size, _)| (s.as_str(), *size))
+            .collect();
+        assert_eq!(made, &[("tWwo", 11.0)]);
+        assert_eq!(widget.layouts.len(), 3);
+    }
+
+    #[test]
+    fn text_color_from_env() {
+        let state = XiState::new("one\ntwo");
+        let mut widget = EditWidget::default();
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&state, &mut factory);
+        assert_eq!(factory.text_colors, vec![Color::WHITE; 2]);
+
+        // An environment without the keys has the default colors.
+        let env = Env::default();
+        assert!(!widget.update_theme(Theme::from_env(&env), &state, &mut factory));
+
+        // The caret color is only painted, so nothing is laid out.
+        let red = Color::rgb8(0xff, 0, 0);
+        let env = env.adding(CARET_COLOR, red.clone());
+        assert!(widget.update_theme(Theme::from_env(&env), &state, &mut factory));
+        assert_eq!(factory.text_colors.len(), 2);
+
+        // The text color is baked into the layouts.
+        let env = env.adding(TEXT_COLOR, red.clone());
+        assert!(widget.update_theme(Theme::from_env(&env), &state, &mut factory));
+        assert_eq!(factory.text_colors[2..], [red.clone(), red]);
+    }
+
+    #[test]
+    fn line_spacing() {
+        let mut state = XiState::new("a\nb\nc");
+        let mut natural = EditWidget::default();
+        with_text(|factory| natural.update_layouts(&state, factory));
+        let mut spaced = EditWidget::default();
+        spaced.set_line_spacing(1.5);
+        with_text(|factory| spaced.update_layouts(&state, factory));
+        // Each height is rounded, so allow for a raw unit per paragraph.
+        let total = natural.layouts.pixel_height();
+        let tolerance = 3.0 / Height::SCALE_FACTOR;
+        assert!((spaced.layouts.pixel_height() - 1.5 * total).abs() <= tolerance);
+
+        // Moving down steps the spaced line height.
+        spaced.update_cursors(&state);
+        let y0 = spaced.caret_rects().next().unwrap().y0;
+        state.apply_edit_op(EditOp::Move(Movement::Down), &spaced.measurement());
+        spaced.update_cursors(&state);
+        let y1 = spaced.caret_rects().next().unwrap().y0;
+        assert_eq!(state.sel[0].end, 2);
+        let line_height = spaced.layouts.pixel_height_of_index(1);
+        assert!((y1 - y0 - line_height).abs() < 1e-9);
+        assert!(y1 - y0 > natural.layouts.pixel_height_of_index(1));
+    }
+
+    /// Apply an edit, and update the layouts incrementally, checking that
+    /// the result matches a full relayout.
+    fn edit_and_relayout(widget: &mut EditWidget, state: &mut XiState, op: EditOp) {
+        let old_text = state.text.clone();
+        state.apply_edit_op(op, &NullMeasurement);
+        with_text(|factory| widget.update_layouts_incremental(&old_text, state, factory));
+        let mut full = EditWidget::default();
+        with_text(|factory| full.update_layouts(state, factory));
+        assert_eq!(widget.layouts.len(), full.layouts.len());
+        assert_eq!(widget.layouts.height(), full.layouts.height());
+    }
+
+    #[test]
+    fn paste_creates_paragraphs() {
+        let mut state = XiState::new("one\ntwo\nthree");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        let first = widget.layouts.get_arc(0).unwrap().1;
+        let last = widget.layouts.get_arc(2).unwrap().1;
+
+        // Paste into the middle of "two".
+        state.sel = Arc::new(Selection::new_simple(SelRegion::caret(5)));
+        edit_and_relayout(&mut widget, &mut state, EditOp::Insert("a\nb\nc".into()));
+        assert_eq!(text(&state), "one\nta\nb\ncwo\nthree");
+        assert_eq!(widget.layouts.len(), 5);
+        // The unchanged paragraphs weren't laid out again.
+        assert!(Arc::ptr_eq(&widget.layouts.get_arc(0).unwrap().1, &first));
+        assert!(Arc::ptr_eq(&widget.layouts.get_arc(4).unwrap().1, &last));
+
+        // Delete from the middle of "one" to the middle of "cwo".
+        state.sel = Arc::new(Selection::new_simple(SelRegion::new(2, 10)));
+        edit_and_relayout(&mut widget, &mut state, EditOp::Backspace);
+        assert_eq!(text(&state), "onwo\nthree");
+        assert_eq!(widget.layouts.len(), 2);
+        assert!(Arc::ptr_eq(&widget.layouts.get_arc(1).unwrap().1, &last));
+    }
+
+    #[test]
+    fn trailing_newline_relayout() {
+        let mut state = XiState::new("a");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        edit_and_relayout(&mut widget, &mut state, EditOp::Insert("\n".into()));
+        assert_eq!(widget.layouts.len(), 2);
+        edit_and_relayout(&mut widget, &mut state, EditOp::Insert("\n\n".into()));
+        assert_eq!(widget.layouts.len(), 4);
+        edit_and_relayout(&mut widget, &mut state, EditOp::Backspace);
+        assert_eq!(widget.layouts.len(), 3);
+    }
+
+    #[test]
+    fn layout_limit() {
+        let state = XiState::new("x\n".repeat(10_000));
+        let mut widget = EditWidget::default();
+        widget.set_layout_limit(Some(100));
+        with_text(|factory| widget.update_layouts(&state, factory));
+        let real = |widget: &EditWidget| {
+            widget
+                .layouts
+                .layouts()
+                .filter(|layout| !layout.is_placeholder())
+                .count()
+        };
+        assert_eq!(widget.layouts.len(), 10_001);
+        assert_eq!(real(&widget), 100);
+        assert!(!widget.layouts[0].is_placeholder());
+        assert!(widget.layouts[100].is_placeholder());
+
+        // The caret at the end is in a placeholder, so it isn't drawn, but
+        // movement still works.
+        widget.update_cursors(&state);
+        assert!(widget.cursors.is_empty());
+        let mut state = state;
+        state.apply_edit_op(EditOp::Move(Movement::Up), &widget.measurement());
+        assert_eq!(state.sel[0].end, 19_998);
+
+        // The placeholders have estimated heights, so scrolling to the
+        // middle lays out the middle, and the top goes back to placeholders.
+        let total = widget.layouts.pixel_height();
+        assert!(total > 10_000.0 * 10.0);
+        widget.scroll_y = 0.5 * total;
+        assert!(with_text(|factory| widget.lay_out_window(&state, factory)));
+        assert!(real(&widget) <= 100, "{} real layouts", real(&widget));
+        assert!(widget.layouts[0].is_placeholder());
+        assert!(widget.layouts[99].is_placeholder());
+        let (top, _) = widget.para_at_y(widget.scroll_y);
+        assert!(top > 4_000 && top < 6_000, "top paragraph {}", top);
+        assert!(!widget.layouts[top].is_placeholder());
+        assert!(!widget.layouts[top + 50].is_placeholder());
+        assert!(!with_text(|factory| widget.lay_out_window(&state, factory)));
+    }
+
+    #[test]
+    fn carets_in_same_paragraph() {
+        let mut state = XiState::new("abc def\nghi");
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::caret(1));
+        sel.add_region(SelRegion::caret(5));
+        sel.add_region(SelRegion::caret(9));
+        state.sel = Arc::new(sel);
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.update_cursors(&state);
+        let rects: Vec<_> = widget.caret_rects().collect();
+        assert_eq!(rects.len(), 3);
+        assert_eq!(rects[0].y0, rects[1].y0);
+        assert!(rects[0].x0 < rects[1].x0);
+        assert!(rects[2].y0 > rects[1].y0);
+    }
+
+    #[test]
+    fn movement_keeps_layouts() {
+        let old_state = XiState::new("zero\none\ntwo");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&old_state, factory));
+        widget.update_cursors(&old_state);
+        assert_eq!(widget.n_layouts_built, 3);
+
+        let mut state = old_state.clone();
+        state.apply_edit_op(EditOp::Move(Movement::Left), &widget.measurement());
+        with_text(|factory| widget.update_data(&old_state, &state, factory));
+        assert_eq!(widget.n_layouts_built, 3);
+        assert_eq!(widget.n_cursors_computed, 2);
+
+        // An edit lays out only the changed paragraph.
+        let old_state = state.clone();
+        state.apply_edit_op(EditOp::Insert("x".into()), &widget.measurement());
+        with_text(|factory| widget.update_data(&old_state, &state, factory));
+        assert_eq!(widget.n_layouts_built, 4);
+    }
+
+    #[test]
+    fn caret_move_invalidation() {
+        let mut old_state = XiState::new("abc def\nghi");
+        old_state.sel = Arc::new(Selection::new_simple(SelRegion::caret(1)));
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&old_state, factory));
+        widget.update_cursors(&old_state);
+        let old_rects = widget.selection_rects(&old_state);
+        assert_eq!(old_rects.len(), 1);
+
+        let mut state = old_state.clone();
+        state.sel = Arc::new(Selection::new_simple(SelRegion::caret(5)));
+        let relayout = with_text(|factory| widget.update_data(&old_state, &state, factory));
+        assert!(!relayout);
+        let new_rects = widget.selection_rects(&state);
+        let rect = invalidation_rect(&old_rects, &new_rects).unwrap();
+        // The rectangle spans both carets, on the one line.
+        assert!(rect.x0 < old_rects[0].x0 && rect.x1 > new_rects[0].x0);
+        assert!(rect.y0 < old_rects[0].y0 && rect.y1 > old_rects[0].y1);
+        assert_eq!(rect.height(), old_rects[0].height() + 2.0);
+        assert!(rect.width() < widget.layouts[0].width());
+
+        assert_eq!(invalidation_rect(&[], &[]), None);
+    }
+
+    #[test]
+    fn reuse_unmoved_cursors() {
+        let mut state = XiState::new("zero\none\ntwo\nthree");
+        let mut sel = Selection::new();
+        for &offset in &[1, 6, 10, 15] {
+            sel.add_region(SelRegion::caret(offset));
+        }
+        state.sel = Arc::new(sel);
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.update_cursors(&state);
+        assert_eq!(widget.n_cursors_computed, 4);
+        let before = widget.cursors.clone();
+
+        // Nothing changed.
+        widget.update_cursors(&state);
+        assert_eq!(widget.n_cursors_computed, 4);
+        assert_eq!(widget.cursors, before);
+
+        // Move one cursor.
+        let mut sel = Selection::new();
+        for &offset in &[1, 7, 10, 15] {
+            sel.add_region(SelRegion::caret(offset));
+        }
+        state.sel = Arc::new(sel);
+        widget.update_cursors(&state);
+        assert_eq!(widget.n_cursors_computed, 5);
+        assert_eq!(widget.cursors[0], before[0]);
+        assert_ne!(widget.cursors[1], before[1]);
+        assert_eq!(widget.cursors[2..], before[2..]);
+
+        // After a relayout, nothing can be reused.
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.update_cursors(&state);
+        assert_eq!(widget.n_cursors_computed, 9);
+    }
+
+    #[test]
+    fn update_cursors_idempotent() {
+        let mut state = XiState::new("f(a)\nb");
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::caret(1));
+        sel.add_region(SelRegion::caret(6));
+        state.sel = Arc::new(sel);
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.update_cursors(&state);
+        let cursors = widget.cursors.clone();
+        let brackets = widget.brackets.clone();
+        assert_eq!(cursors.len(), 2);
+        assert_eq!(brackets.len(), 2);
+        widget.update_cursors(&state);
+        assert_eq!(widget.cursors, cursors);
+        assert_eq!(widget.brackets, brackets);
+        assert_eq!(widget.n_cursors_computed, 2);
+
+        // A new caret shape changes the geometry, once.
+        widget.set_caret_shape(CaretShape::Block);
+        widget.update_cursors(&state);
+        let block_cursors = widget.cursors.clone();
+        assert_ne!(block_cursors, cursors);
+        widget.update_cursors(&state);
+        assert_eq!(widget.cursors, block_cursors);
+        assert_eq!(widget.n_cursors_computed, 4);
+    }
+
+    #[test]
+    fn reflow_keeps_carets() {
+        let mut state = XiState::new("word ".repeat(20));
+        state.set_selection(Selection::new_simple(SelRegion::caret(62)));
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.update_cursors(&state);
+        let (_, wide) = widget.cursors[0];
+        let changed = with_text(|factory| widget.set_wrap_width(MAX_WIDTH, &state, factory));
+        assert!(!changed);
+
+        // Narrower, the caret is on a later visual line.
+        let changed = with_text(|factory| widget.set_wrap_width(100.0, &state, factory));
+        assert!(changed);
+        let (_, narrow) = widget.cursors[0];
+        assert!(narrow.y0 > wide.y0);
+        assert_eq!(&**state.sel, &[SelRegion::caret(62)]);
+
+        // The position is the same as when starting at that width.
+        let mut fresh = EditWidget::default();
+        fresh.wrap_width = Some(100.0);
+        with_text(|factory| fresh.update_layouts(&state, factory));
+        fresh.update_cursors(&state);
+        assert_eq!(widget.cursors, fresh.cursors);
+    }
+
+    /// A widget with stub layouts of paragraphs of the given lengths,
+    /// wrapped after 10 bytes, with cells 10 px wide and lines 20 px high.
+    fn stub_widget(lens: &[usize]) -> EditWidget {
+        let mut widget = EditWidget::default();
+        widget.layouts = lens
+            .iter()
+            .map(|&len| Layout::stub(StubLayout::new(len, 10, 10.0, 20.0)))
+            .collect();
+        widget
+    }
+
+    #[test]
+    fn stub_round_trip() {
+        let lens = [25, 0, 10];
+        let widget = stub_widget(&lens);
+        let m = widget.measurement();
+        assert_eq!(m.n_visual_lines(0), 3);
+        assert_eq!(m.n_visual_lines(1), 1);
+        // An offset at a wrap point is at the start of the next visual
+        // line, unless it's at the end.
+        assert_eq!(m.to_pos(0, 10), (0.0, 1));
+        assert_eq!(m.to_pos(0, 25), (50.0, 2));
+        assert_eq!(m.to_pos(2, 10), (100.0, 0));
+        for (line_num, &len) in lens.iter().enumerate() {
+            for offset in 0..=len {
+                let (horiz, visual_line) = m.to_pos(line_num, offset);
+                assert_eq!(
+                    m.from_pos(line_num, horiz, visual_line),
+                    offset,
+                    "line {} offset {}",
+                    line_num,
+                    offset
+                );
+            }
+        }
+        // Other positions go to the nearest offset on the visual line.
+        assert_eq!(m.from_pos(0, 34.0, 1), 13);
+        assert_eq!(m.from_pos(0, 500.0, 2), 25);
+    }
+
+    #[test]
+    fn stub_cursors() {
+        let state_text = format!("{}\n{}", "a".repeat(25), "b".repeat(5));
+        let mut state = XiState::new(state_text);
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::caret(13));
+        sel.add_region(SelRegion::caret(28));
+        state.set_selection(sel);
+        let mut widget = stub_widget(&[25, 5]);
+        widget.update_cursors(&state);
+        // The carets are relative to their paragraphs, on the second visual
+        // line of the first, and the first of the second.
+        assert_eq!(
+            widget.cursors,
+            vec![
+                (0, Rect::new(30.0, 23.0, 30.0, 41.0)),
+                (1, Rect::new(20.0, 3.0, 20.0, 21.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn fold_hides_lines() {
+        let old = XiState::new("a\nb\nc\nd");
+        let mut widget = EditWidget::default();
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&old, &mut factory);
+        assert_eq!(widget.layouts.pixel_height(), 4.0 * FONT_SIZE);
+
+        let mut state = old.clone();
+        state.fold_lines(1..3);
+        assert!(!state.is_folded(0));
+        assert!(state.is_folded(1) && state.is_folded(2));
+        assert!(!state.is_folded(3));
+        assert!(widget.update_data(&old, &state, &mut factory));
+        assert_eq!(widget.layouts.pixel_height(), 2.0 * FONT_SIZE);
+        // The hidden lines are still there, but the fourth line comes right
+        // after the first.
+        assert_eq!(widget.layouts.len(), 4);
+        let y = TEXT_ORIGIN_Y + FONT_SIZE;
+        assert_eq!(widget.para_at_y(y), (3, y));
+
+        let old = state.clone();
+        state.unfold_line(2);
+        assert!(!state.is_folded(1));
+        assert!(widget.update_data(&old, &state, &mut factory));
+        assert_eq!(widget.layouts.pixel_height(), 4.0 * FONT_SIZE);
+    }
+
+    #[test]
+    fn para_at_y_skips_fold() {
+        let old = XiState::new(format!("{}line", "line\n".repeat(19_999)));
+        let mut widget = EditWidget::default();
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&old, &mut factory);
+        let mut state = old.clone();
+        state.fold_lines(5_000..15_000);
+        state.fold_lines(19_000..20_000);
+        widget.update_data(&old, &state, &mut factory);
+
+        // At the top of the line after a fold, and past the end, only a few
+        // lookups are needed, rather than one for each hidden line.
+        let lookups = |y| {
+            N_LOOKUPS.with(|n| n.set(0));
+            let result = widget.para_at_y(y);
+            (result, N_LOOKUPS.with(|n| n.get()))
+        };
+        let y = TEXT_ORIGIN_Y + 5_000.0 * FONT_SIZE;
+        let (result, n) = lookups(y);
+        assert_eq!(result, (15_000, y));
+        assert!(n <= 8, "{} lookups", n);
+        let y = TEXT_ORIGIN_Y + 9_000.0 * FONT_SIZE;
+        let (result, n) = lookups(y + 100.0);
+        assert_eq!(result, (18_999, y - FONT_SIZE));
+        assert!(n <= 8, "{} lookups", n);
+    }
+
+    #[test]
+    fn fold_past_end() {
+        let mut state = XiState::new("a\nb\nc\nd");
+        state.fold_lines(2..100);
+        assert!(!state.is_folded(1));
+        assert!(state.is_folded(2) && state.is_folded(3));
+        // Nothing is left to fold past the end.
+        state.fold_lines(10..20);
+        assert_eq!(state.folds.len(), 1);
+    }
+
+    #[test]
+    fn down_skips_folded_lines() {
+        let mut state = XiState::new("one\ntwo\nthree\nfour");
+        state.set_selection(Selection::new_simple(SelRegion::caret(1)));
+        state.fold_lines(1..3);
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        state.apply_edit_op(EditOp::Move(Movement::Down), &widget.measurement());
+        assert_eq!(state.sel[0].end, 15);
+        state.apply_edit_op(EditOp::Move(Movement::Up), &widget.measurement());
+        assert_eq!(state.sel[0].end, 1);
+
+        // Moving right from the end of the line before the fold skips it.
+        state.set_selection(Selection::new_simple(SelRegion::caret(3)));
+        state.apply_edit_op(EditOp::Move(Movement::Right), &widget.measurement());
+        assert_eq!(state.sel[0].end, 14);
+    }
+
+    #[test]
+    fn revision_same() {
+        let state = XiState::new("one");
+        let mut clone = state.clone();
+        assert!(state.same(&clone));
+        // Clones compare revisions, not text, so a change that bypasses the
+        // revision goes unnoticed.
+        clone.text = Rope::from("two");
+        assert!(state.same(&clone));
+
+        // Each edit is a new revision, even if clones make the same one.
+        let mut edited = state.clone();
+        edited
+            .apply_edit_op(EditOp::Insert("!".into()), &NullMeasurement)
+            .unwrap();
+        assert!(edited.revision() > state.revision());
+        assert!(!state.same(&edited));
+        let mut again = state.clone();
+        again
+            .apply_edit_op(EditOp::Insert("!".into()), &NullMeasurement)
+            .unwrap();
+        assert_ne!(again.revision(), edited.revision());
+
+        // Unrelated states compare the text itself.
+        assert!(state.same_text(&XiState::new("one")));
+        assert!(!state.same_text(&XiState::new("two")));
+    }
+
+    #[test]
+    fn unchanged_selection_kept() {
+        let mut state = XiState::new("abc");
+        let sel = state.sel.clone();
+        // The caret is already at the end.
+        state.apply_edit_op(EditOp::Move(Movement::Right), &NullMeasurement);
+        assert!(Arc::ptr_eq(&sel, &state.sel));
+        state.set_selection(Selection::new_simple(SelRegion::caret(3)));
+        assert!(Arc::ptr_eq(&sel, &state.sel));
+
+        state.apply_edit_op(EditOp::Move(Movement::Left), &NullMeasurement);
+        assert!(!Arc::ptr_eq(&sel, &state.sel));
+        assert_eq!(&**state.sel, &[SelRegion::caret(2)]);
+    }
+
+    #[test]
+    fn folds_follow_edits() {
+        let mut state = XiState::new("a\nb\nc");
+        state.fold_lines(1..2);
+        state.set_selection(Selection::new_simple(SelRegion::caret(0)));
+        let delta = state
+            .apply_edit_op(EditOp::Insert("x\n".into()), &NullMeasurement)
+            .unwrap();
+        state.update_folds(&delta);
+        assert!(!state.is_folded(1));
+        assert!(state.is_folded(2));
+        assert!(!state.is_folded(3));
+    }
+
+    #[test]
+    fn drop_text() {
+        // Paragraphs 30 bytes, 60 px high, and 3 bytes.
+        let mut widget = stub_widget(&[30, 3]);
+        let mut state = XiState::new(format!("{}\none", "a".repeat(30)));
+        // A drop hits the text as a click does, here in column 1 of the
+        // second paragraph.
+        let point = Point::new(TEXT_ORIGIN_X + 12.0, TEXT_ORIGIN_Y + 65.0);
+        widget.drop_text(&mut state, point, "xy");
+        assert_eq!(&text(&state)[31..], "oxyne");
+        assert_eq!(&**state.sel, &[SelRegion::caret(34)]);
+
+        // A file that can't be read leaves the text alone.
+        let path = Path::new("does/not/exist.txt");
+        assert!(widget.drop_file(&mut state, point, path).is_err());
+        assert_eq!(&text(&state)[31..], "oxyne");
+    }
+
+    #[test]
+    fn block_drag() {
+        // Paragraphs 20 px high, with cells 10 px wide.
+        let mut widget = stub_widget(&[6, 2, 6]);
+        let mut state = XiState::new("abcdef\nab\nabcdef");
+        widget.drag = Some(Drag {
+            anchor: 1..1,
+            unit: DragUnit::Char,
+            pos: Point::ORIGIN,
+            timer: None,
+            block: Some(10.0),
+        });
+        let point = Point::new(TEXT_ORIGIN_X + 40.0, TEXT_ORIGIN_Y + 45.0);
+        widget.drag_to(&mut state, point);
+        let expected = [
+            SelRegion::new(1, 4),
+            SelRegion::new(8, 9),
+            SelRegion::new(11, 14),
+        ];
+        assert_eq!(&**state.sel, &expected);
+
+        // Typing replaces the column on every line.
+        widget.apply_edit_op(&mut state, EditOp::Insert("X".into()));
+        assert_eq!(text(&state), "aXef\naX\naXef");
+    }
+
+    #[test]
+    fn line_at_y() {
+        // Paragraphs 60, 20, and 20 px high.
+        let mut widget = stub_widget(&[25, 0, 10]);
+        assert_eq!(widget.line_at_y(-5.0), 0);
+        assert_eq!(widget.line_at_y(TEXT_ORIGIN_Y + 59.0), 0);
+        assert_eq!(widget.line_at_y(TEXT_ORIGIN_Y + 60.0), 1);
+        assert_eq!(widget.line_at_y(TEXT_ORIGIN_Y + 85.0), 2);
+        assert_eq!(widget.line_at_y(1000.0), 2);
+
+        widget.scroll_y = 50.0;
+        assert_eq!(widget.line_at_y(TEXT_ORIGIN_Y), 0);
+        assert_eq!(widget.line_at_y(TEXT_ORIGIN_Y + 10.0), 1);
+        assert_eq!(widget.line_at_y(TEXT_ORIGIN_Y + 30.0), 2);
+    }
+
+    /// A decoration that marks every line it's asked for, and records the
+    /// requests.
+    #[derive(Default)]
+    struct StubDecoration {
+        requests: Rc<RefCell<Vec<(Range<usize>, Vec<LineGeometry>)>>>,
+    }
+
+    impl Decoration for StubDecoration {
+        fn decorate(
+            &mut self,
+            _text: &Rope,
+            lines: Range<usize>,
+            geometry: &[LineGeometry],
+        ) -> Vec<DecorationItem> {
+            self.requests
+                .borrow_mut()
+                .push((lines.clone(), geometry.to_vec()));
+            lines
+                .map(|line| DecorationItem::Gutter {
+                    line,
+                    glyph: "!".into(),
+                    color: Color::WHITE,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn decoration_visible_lines() {
+        // Twenty 20 px paragraphs, the third folded.
+        let mut widget = EditWidget::default();
+        widget.layouts = (0..20)
+            .map(|i| Layout::stub(StubLayout::new(5, 10, 10.0, 20.0)).with_hidden(i == 2))
+            .collect();
+        let state = XiState::new("text");
+        let decoration = StubDecoration::default();
+        let requests = decoration.requests.clone();
+        widget.set_decoration(decoration);
+        widget.viewport_height = 50.0;
+        widget.scroll_y = 45.0;
+
+        let items = widget.decoration_items(&state);
+        assert_eq!(items.len(), 5);
+        let requests = requests.borrow();
+        assert_eq!(requests.len(), 1);
+        let (lines, geometry) = &requests[0];
+        assert_eq!(*lines, 1..6);
+        let geometry: Vec<_> = geometry.iter().map(|g| (g.line, g.top, g.height)).collect();
+        assert_eq!(
+            geometry,
+            vec![
+                (1, -13.0, 20.0),
+                (3, 7.0, 20.0),
+                (4, 27.0, 20.0),
+                (5, 47.0, 20.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn underline_segments() {
+        // A paragraph wrapped to three visual lines.
+        let widget = stub_widget(&[25, 5]);
+        let y0 = TEXT_ORIGIN_Y + 20.0 - 1.0;
+        assert_eq!(
+            widget.underline_segments(0, 5..15),
+            vec![
+                (TEXT_ORIGIN_X + 50.0, TEXT_ORIGIN_X + 100.0, y0),
+                (TEXT_ORIGIN_X, TEXT_ORIGIN_X + 50.0, y0 + 20.0),
+            ]
+        );
+        assert_eq!(widget.underline_segments(0, 5..5), vec![]);
+    }
+
+    #[test]
+    fn diagnostic_segments() {
+        // A paragraph wrapped to three visual lines, then a short one.
+        let widget = stub_widget(&[25, 5]);
+        let mut state = XiState::new(format!("{}\n{}", "a".repeat(25), "b".repeat(5)));
+        state.set_diagnostics(vec![Interval::new(5, 29)]);
+        let iv = state.diagnostics()[0];
+        let y0 = TEXT_ORIGIN_Y + 20.0 - 1.0;
+        // Each visual line of the diagnostic is underlined, with the
+        // wrapped ones reaching the right edge.
+        assert_eq!(
+            widget.range_segments(&state.text, iv.start..iv.end, &(0..2)),
+            vec![
+                (TEXT_ORIGIN_X + 50.0, TEXT_ORIGIN_X + 100.0, y0),
+                (TEXT_ORIGIN_X, TEXT_ORIGIN_X + 100.0, y0 + 20.0),
+                (TEXT_ORIGIN_X, TEXT_ORIGIN_X + 50.0, y0 + 40.0),
+                (TEXT_ORIGIN_X, TEXT_ORIGIN_X + 30.0, y0 + 60.0),
+            ]
+        );
+        // Only the requested paragraphs are underlined.
+        assert_eq!(
+            widget.range_segments(&state.text, iv.start..iv.end, &(1..2)),
+            vec![(TEXT_ORIGIN_X, TEXT_ORIGIN_X + 30.0, y0 + 60.0)]
+        );
+
+        // Diagnostics follow edits, and are dropped with their text.
+        state.set_selection(Selection::new_simple(SelRegion::caret(0)));
+        let delta = state
+            .apply_edit_op(EditOp::Insert("xx".into()), &NullMeasurement)
+            .unwrap();
+        state.update_diagnostics(&delta);
+        assert_eq!(state.diagnostics(), &[Interval::new(7, 31)]);
+        state.set_selection(Selection::new_simple(SelRegion::new(4, 33)));
+        let delta = state
+            .apply_edit_op(EditOp::Backspace, &NullMeasurement)
+            .unwrap();
+        state.update_diagnostics(&delta);
+        assert!(state.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn offset_at_point() {
+        // Paragraphs 60, 20, and 20 px high, the first wrapped.
+        let mut widget = stub_widget(&[25, 0, 10]);
+        let state = XiState::new(format!("{}\n\n{}", "a".repeat(25), "b".repeat(10)));
+        let at = |x, y| Point::new(TEXT_ORIGIN_X + x, TEXT_ORIGIN_Y + y);
+        assert_eq!(widget.offset_at_point(&state, at(30.0, 25.0)), Some(13));
+        assert_eq!(widget.offset_at_point(&state, at(0.0, 65.0)), Some(26));
+        assert_eq!(widget.offset_at_point(&state, at(100.0, 99.0)), Some(37));
+
+        // Above, below, and beside the text.
+        assert_eq!(widget.offset_at_point(&state, at(30.0, -1.0)), None);
+        assert_eq!(widget.offset_at_point(&state, at(30.0, 100.0)), None);
+        assert_eq!(widget.offset_at_point(&state, at(-1.0, 25.0)), None);
+        assert_eq!(widget.offset_at_point(&state, at(150.0, 85.0)), None);
+        assert_eq!(widget.offset_at_point(&state, at(30.0, 65.0)), None);
+
+        // The scroll offset is accounted for.
+        widget.scroll_y = 50.0;
+        assert_eq!(widget.offset_at_point(&state, at(20.0, 30.0)), Some(29));
+        assert_eq!(widget.offset_at_point(&state, at(20.0, 50.0)), None);
+    }
+
+    #[test]
+    fn primary_caret_rect() {
+        let mut widget = stub_widget(&[25, 0, 10]);
+        let text = format!("{}\n\n{}", "a".repeat(25), "b".repeat(10));
+        let mut state = XiState::new(text.as_str());
+        let mut sel = Selection::new_simple(SelRegion::caret(30));
+        sel.add_region(SelRegion::caret(1));
+        state.sel = Arc::new(sel);
+        widget.update_cursors(&state);
+        widget.scroll_y = 50.0;
+        // The primary caret is the first in the document, at column 1 of
+        // the first paragraph.
+        let x = TEXT_ORIGIN_X + 10.0;
+        let y = TEXT_ORIGIN_Y + 15.0 - 12.0 - 50.0;
+        assert_eq!(
+            widget.primary_caret_rect(),
+            Some(Rect::new(x, y, x, y + 18.0))
+        );
+
+        // Below the 60 px first paragraph and 20 px second.
+        state.sel = Arc::new(Selection::new_simple(SelRegion::caret(30)));
+        widget.update_cursors(&state);
+        let x = TEXT_ORIGIN_X + 30.0;
+        let y = y + 80.0;
+        assert_eq!(
+            widget.primary_caret_rect(),
+            Some(Rect::new(x, y, x, y + 18.0))
+        );
+
+        state.sel = Arc::new(Selection::new());
+        widget.update_cursors(&state);
+        assert_eq!(widget.primary_caret_rect(), None);
+    }
+
+    #[test]
+    fn sequential_measurement() {
+        let mut widget = EditWidget::default();
+        let texts: Vec<String> = (0..100).map(|i| format!("line {}", i)).collect();
+        for layout in make_layouts(texts.iter().map(String::as_str)) {
+            widget.layouts.push(layout);
+        }
+        let m = widget.measurement();
+        for line_num in 0..100 {
+            assert_eq!(m.n_visual_lines(line_num), 1);
+            assert_eq!(m.to_pos(line_num, 0), (0.0, 0));
+        }
+        // Leaves hold at least 16 layouts.
+        let n_traversals = m.n_traversals.get();
+        assert!(n_traversals <= 7, "{} traversals", n_traversals);
+        // Going back over the same lines is just as cheap.
+        for line_num in (90..100).rev() {
+            m.n_visual_lines(line_num);
+        }
+        assert!(m.n_traversals.get() <= n_traversals + 1);
+    }
+
+    #[test]
+    fn from_pos_past_last_visual_line() {
+        let para = "word ".repeat(60);
+        let state = XiState::new(format!("{}\nnext", para));
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        let m = widget.measurement();
+        let n_lines = m.n_visual_lines(0);
+        assert!(n_lines >= 2, "{} visual lines", n_lines);
+        let x = m.to_pos(0, 7).0;
+        let last = m.from_pos(0, x, n_lines - 1);
+        assert!(last > 7);
+        assert_eq!(m.from_pos(0, x, n_lines), last);
+        assert_eq!(m.from_pos(0, x, n_lines + 10), last);
+        // The same on an unwrapped line.
+        let x = m.to_pos(1, 3).0;
+        assert_eq!(m.from_pos(1, x, 1), 3);
+        assert_eq!(m.from_pos(1, 1000.0, 1), 4);
+    }
+
+    #[test]
+    fn selection_x_range() {
+        // A paragraph long enough to wrap to several visual lines.
+        let para = "word ".repeat(60);
+        let state = XiState::new(format!("{}\nnext", para));
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        let m = widget.measurement();
+        let layout = &widget.layouts[0];
+        let n_lines = layout.line_count();
+        assert!(n_lines >= 3, "{} visual lines", n_lines);
+        let x = |offset| layout.hit_test_text_position(offset).point.x;
+        let width = layout.width();
+        let line_1 = layout.line_metric(1).unwrap();
+        let last = layout.line_metric(n_lines - 1).unwrap();
+
+        // A selection from offset 7 on the first visual line to two
+        // characters into the last.
+        let end = last.start_offset + 2;
+        assert_eq!(m.visual_line_x_range(0, 0, 7, end), (x(7), width));
+        assert_eq!(m.visual_line_x_range(0, 1, 7, end), (0.0, width));
+        assert_eq!(m.visual_line_x_range(0, n_lines - 1, 7, end), (0.0, x(end)));
+
+        // A selection within the middle line.
+        let start = line_1.start_offset + 1;
+        assert_eq!(
+            m.visual_line_x_range(0, 1, start, start + 3),
+            (x(start), x(start + 3))
+        );
+
+        // A selection including the line break.
+        assert_eq!(
+            m.visual_line_x_range(0, n_lines - 1, 7, para.len() + 1),
+            (0.0, x(para.len()) + NEWLINE_WIDTH)
+        );
+    }
+
+    #[test]
+    fn selection_background() {
+        let mut state = XiState::new("abcdefgh\nij\nkl");
+        state.sel = Arc::new(Selection::new_simple(SelRegion::new(3, 10)));
+        let mut widget = EditWidget::default();
+        widget.viewport_height = 100.0;
+        // Wrap after five bytes.
+        widget.wrap_width = Some(50.0);
+        let mut factory = StubFactory {
+            cell_width: 10.0,
+            line_height: 20.0,
+        };
+        widget.update_layouts(&state, &mut factory);
+        let ranges: Vec<_> = widget
+            .selection_background_rects(&state)
+            .iter()
+            .map(|rect| (rect.x0 - TEXT_ORIGIN_X, rect.x1 - TEXT_ORIGIN_X))
+            .collect();
+        // To the edge of the wrapped line, to past the end of the last one
+        // for the selected line break, and one byte into the next line.
+        assert_eq!(
+            ranges,
+            vec![(30.0, 50.0), (0.0, 30.0 + NEWLINE_WIDTH), (0.0, 10.0)]
+        );
+
+        // Carets have no background.
+        state.sel = Arc::new(Selection::new_simple(SelRegion::caret(3)));
+        assert!(widget.selection_background_rects(&state).is_empty());
+    }
+
+    #[test]
+    fn block_caret_width() {
+        let text = Rope::from("ab\u{e9}\u{1F600}\ncd");
+        let layout = with_text(|factory| {
+            factory.make_layout(
+                "ab\u{e9}\u{1F600}",
+                MAX_WIDTH,
+                FONT_SIZE,
+                &Color::WHITE,
+                &[],
+                LongWords::Break,
+            )
+        });
+        let x = |offset| layout.hit_test_text_position(offset).point.x;
+        let width = caret_width(&layout, &text, 1);
+        assert!(width > 0.0);
+        assert_eq!(width, x(2) - x(1));
+        // A multi-byte grapheme is measured as a whole.
+        assert_eq!(caret_width(&layout, &text, 2), x(4) - x(2));
+        assert_eq!(caret_width(&layout, &text, 4), x(8) - x(4));
+        // At the end of the paragraph, there is no grapheme, so the caret
+        // is as wide as a space.
+        let space_width = layout.space_width().unwrap();
+        assert!(space_width > 0.0);
+        assert_eq!(caret_width(&layout, &text, 8), space_width);
+    }
+
+    #[test]
+    fn long_words() {
+        let token = "a".repeat(100);
+        let text = format!("{} b", token);
+        let lines = |long_words| {
+            let layout = with_text(|factory| {
+                factory.make_layout(&text, 100.0, FONT_SIZE, &Color::WHITE, &[], long_words)
+            });
+            layout.line_count()
+        };
+        assert!(lines(LongWords::Break) > 2);
+        assert_eq!(lines(LongWords::Overflow), 1);
+
+        // The widget passes its setting to the factory.
+        let mut widget = EditWidget::default();
+        widget.set_long_words(LongWords::Overflow);
+        let data = XiState::new(&text);
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&data, &mut factory);
+        assert_eq!(factory.long_words, vec![LongWords::Overflow]);
+    }
+
+    #[test]
+    fn block_caret_width_at_line_end() {
+        let text = Rope::from("abc\n");
+        let stub = Layout::stub(StubLayout::new(3, 10, 10.0, 20.0));
+        assert_eq!(caret_width(&stub, &text, 1), 10.0);
+        assert_eq!(caret_width(&stub, &text, 3), 10.0);
+        // Without a measured space, the default width is used.
+        let unmeasured = make_layout("abc");
+        assert_eq!(caret_width(&unmeasured, &text, 3), EMPTY_CARET_WIDTH);
+    }
+
+    #[test]
+    fn status() {
+        let mut state = XiState::new("first\nse\u{301}cond\nthird\n");
+        state.sel = Arc::new(Selection::new_simple(SelRegion::caret(10)));
+        assert_eq!(state.caret_line_col(), Some((1, 2)));
+        assert_eq!(state.line_count(), 4);
+        assert!(!state.has_multiple_selections());
+        let mut sel = (*state.sel).clone();
+        sel.add_region(SelRegion::caret(2));
+        state.sel = Arc::new(sel);
+        assert_eq!(state.caret_line_col(), Some((0, 2)));
+        assert!(state.has_multiple_selections());
+        assert_eq!(XiState::new("").line_count(), 1);
+    }
+
+    #[test]
+    fn from_reader() {
+        let state = XiState::from_reader(&b"one\r\ntwo"[..]).unwrap();
+        assert_eq!(text(&state), "one\r\ntwo");
+        assert_eq!(state.line_ending(), LineEnding::CrLf);
+        assert_eq!(&**state.sel, &[SelRegion::caret(8)]);
+        assert!(XiState::from_reader(&b"\xc3"[..]).is_err());
+    }
+
+    #[test]
+    fn append_follows_log() {
+        let mut state = XiState::new("one\ntwo\n");
+        let mut widget = EditWidget::default();
+        widget.set_log_mode(true);
+        widget.viewport_height = 30.0;
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&state, &mut factory);
+        // Three 14 px paragraphs and the top padding, less the viewport.
+        widget.scroll_y = 24.0;
+        assert!(widget.is_scrolled_to_bottom());
+
+        // Only the new paragraphs are laid out, and the view follows.
+        let old = state.clone();
+        state.append("three\nfour\n");
+        assert_eq!(text(&state), "one\ntwo\nthree\nfour\n");
+        assert_eq!(&**state.sel, &[SelRegion::caret(8)]);
+        factory.made.clear();
+        assert!(widget.update_data(&old, &state, &mut factory));
+        let made: Vec<_> = factory
+            .made
+            .iter()
+            .map(|(text, ..)| text.as_str())
+            .collect();
+        assert_eq!(made, vec!["three", "four", ""]);
+        assert_eq!(widget.scroll_y, 52.0);
+
+        // Once scrolled up, the view stays put.
+        widget.scroll_y = 10.0;
+        let old = state.clone();
+        state.append("five\n");
+        widget.update_data(&old, &state, &mut factory);
+        assert_eq!(widget.scroll_y, 10.0);
+        assert!(!widget.is_scrolled_to_bottom());
+    }
+
+    #[test]
+    fn undo_keeps_appended() {
+        let mut state = XiState::new("ab");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.apply_edit_op(&mut state, EditOp::Insert("c".into()));
+        state.append("\nlog");
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "ab\nlog");
+        assert_eq!(&**state.sel, &[SelRegion::caret(2)]);
+        assert!(widget.redo(&mut state));
+        assert_eq!(String::from(&state.text), "abc\nlog");
+
+        // An edit after an append keeps it too.
+        state.append("\nmore");
+        widget.apply_edit_op(&mut state, EditOp::Insert("d".into()));
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "abc\nlog\nmore");
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "ab\nlog\nmore");
+    }
+
+    #[test]
+    fn smooth_scroll() {
+        let mut state = XiState::new(&"line\n".repeat(20));
+        let mut widget = EditWidget::default();
+        widget.set_smooth_scroll(true);
+        widget.viewport_height = 100.0;
+        let mut factory = MockFactory::default();
+        widget.update_layouts(&state, &mut factory);
+
+        // The view eases toward the target, a frame at a time.
+        widget.scroll_to(80.0);
+        assert_eq!(widget.scroll_y, 0.0);
+        assert!(widget.advance_scroll_anim(Duration::from_millis(75)));
+        assert_eq!(widget.scroll_y, 70.0);
+        assert!(!widget.advance_scroll_anim(Duration::from_millis(75)));
+        assert_eq!(widget.scroll_y, 80.0);
+        assert!(widget.scroll_anim.is_none());
+
+        // A new target starts from wherever the view is.
+        widget.scroll_to(0.0);
+        assert!(widget.advance_scroll_anim(Duration::from_millis(75)));
+        widget.scroll_to(40.0);
+        assert!(widget.advance_scroll_anim(Duration::from_millis(75)));
+        assert_eq!(widget.scroll_y, 36.25);
+
+        // An edit finishes the animation rather than chasing stale layouts.
+        let old = state.clone();
+        state.append("more\n");
+        widget.update_data(&old, &state, &mut factory);
+        assert_eq!(widget.scroll_y, 40.0);
+        assert!(widget.scroll_anim.is_none());
+
+        // Setting the scroll position directly cancels it too.
+        widget.scroll_to(0.0);
+        widget.set_scroll_y(25.0);
+        assert!(widget.scroll_anim.is_none());
+        assert!(!widget.advance_scroll_anim(Duration::from_millis(75)));
+        assert_eq!(widget.scroll_y, 25.0);
+    }
+
+    #[test]
+    fn scrolled_to_bottom() {
+        // 112 px of text, including the top padding.
+        let mut widget = stub_widget(&[25, 0, 10]);
+        widget.viewport_height = 50.0;
+        assert!(!widget.is_scrolled_to_bottom());
+        widget.set_scroll_y(61.5);
+        assert!(widget.is_scrolled_to_bottom());
+        widget.set_scroll_y(1000.0);
+        assert_eq!(widget.scroll_y, 62.0);
+        assert!(widget.is_scrolled_to_bottom());
+        // Text that fits is always at the bottom.
+        widget.viewport_height = 200.0;
+        widget.set_scroll_y(0.0);
+        assert!(widget.is_scrolled_to_bottom());
+    }
+
+    #[test]
+    fn placeholder() {
+        let mut widget = EditWidget::default();
+        let mut state = XiState::new("");
+        assert_eq!(widget.placeholder_text(&state), None);
+        widget.set_placeholder(Some("Type here".to_string()));
+        assert_eq!(widget.placeholder_text(&state), Some("Type here"));
+        state.apply_edit_op(EditOp::Insert("x".into()), &NullMeasurement);
+        assert_eq!(widget.placeholder_text(&state), None);
+        state.apply_edit_op(EditOp::Backspace, &NullMeasurement);
+        assert_eq!(widget.placeholder_text(&state), Some("Type here"));
+    }
+
+    #[test]
+    fn access_node() {
+        let mut state = XiState::new("one\ntwo");
+        // A selection made backwards reports its anchor first.
+        state.set_selection(Selection::new_simple(SelRegion::new(6, 2)));
+        assert_eq!(
+            state.access_node(),
+            AccessNode {
+                role: AccessRole::TextField,
+                value: "one\ntwo".to_string(),
+                selection: Some((6, 2)),
+            }
+        );
+    }
+
+    #[test]
+    fn run_command() {
+        let mut state = XiState::new("hello\nworld");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.register_command("upcase", |text, sel, _| {
+            *text = Rope::from(String::from(&*text).to_uppercase());
+            sel.clone()
+        });
+        assert!(widget.run_command(&mut state, "upcase"));
+        assert_eq!(String::from(&state.text), "HELLO\nWORLD");
+        assert_eq!(&**state.sel, &[SelRegion::caret(11)]);
+
+        // Built-in edit ops are registered too.
+        assert!(widget.run_command(&mut state, "move-document-start"));
+        assert_eq!(&**state.sel, &[SelRegion::caret(0)]);
+        assert!(!widget.run_command(&mut state, "no-such-command"));
+    }
+
+    #[test]
+    fn command_delta() {
+        let mut state = XiState::new("one two three");
+        let command: Command = Box::new(|text, sel, _| {
+            *text = Rope::from("one 2 three");
+            sel.clone()
+        });
+        let delta = state.apply_command(&command, &NullMeasurement).unwrap();
+        assert_eq!(delta.summary(), (Interval::new(4, 7), 1));
+        let command: Command = Box::new(|_, sel, _| sel.clone());
+        assert!(state.apply_command(&command, &NullMeasurement).is_none());
+    }
+
+    #[test]
+    fn palette_runs_command() {
+        let mut state = XiState::new("hello");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        assert!(!widget.run_palette_command(&mut state));
+
+        state.show_palette(widget.commands.names());
+        state.palette.as_mut().unwrap().set_query("mdstart");
+        assert_eq!(
+            state.palette().unwrap().selected(),
+            Some("move-document-start")
+        );
+        assert!(widget.run_palette_command(&mut state));
+        assert_eq!(&**state.sel, &[SelRegion::caret(0)]);
+        assert!(state.palette().is_none());
+
+        // Nothing matches, so nothing runs, but the palette still closes.
+        state.show_palette(widget.commands.names());
+        state.palette.as_mut().unwrap().set_query("zzz");
+        assert!(!widget.run_palette_command(&mut state));
+        assert!(state.palette().is_none());
+    }
+
+    #[test]
+    fn accept_completion() {
+        let mut state = XiState::new("let fo = foo_bar;");
+        let mut widget = EditWidget::default();
+        assert!(!widget.accept_completion(&mut state));
+
+        // The whole word at the caret is replaced, not just its start.
+        state.set_selection(Selection::new_simple(SelRegion::caret(5)));
+        state.show_completion(vec!["foo", "format"]);
+        state.completion.as_mut().unwrap().select_next();
+        assert!(widget.accept_completion(&mut state));
+        assert_eq!(text(&state), "let format = foo_bar;");
+        assert_eq!(&**state.sel, &[SelRegion::caret(10)]);
+        assert!(state.completion().is_none());
+        widget.undo(&mut state);
+        assert_eq!(text(&state), "let fo = foo_bar;");
+
+        // Selected text is replaced as it is, and a caret away from any
+        // word inserts the candidate.
+        let mut sel = Selection::new_simple(SelRegion::new(9, 12));
+        sel.add_region(SelRegion::caret(17));
+        state.set_selection(sel);
+        state.show_completion(vec!["bar"]);
+        assert!(widget.accept_completion(&mut state));
+        assert_eq!(text(&state), "let fo = bar_bar;bar");
+    }
+
+    #[test]
+    fn set_selection() {
+        let mut state = XiState::new("hello");
+        let old_sel = state.sel.clone();
+        state.set_selection(Selection::new_simple(SelRegion::new(2, 100)));
+        assert_eq!(&**state.sel, &[SelRegion::new(2, 5)]);
+        assert!(!Arc::ptr_eq(&old_sel, &state.sel));
+        state.set_selection(Selection::new_simple(SelRegion::caret(100)));
+        assert_eq!(&**state.sel, &[SelRegion::caret(5)]);
+
+        // Offsets within a grapheme cluster move to its start, whether or
+        // not they are on a code point boundary.
+        let mut state = XiState::new("ae\u{301}b");
+        state.set_selection(Selection::new_simple(SelRegion::new(2, 3)));
+        assert_eq!(&**state.sel, &[SelRegion::caret(1)]);
+        state.set_selection(Selection::new_simple(SelRegion::new(0, 4)));
+        assert_eq!(&**state.sel, &[SelRegion::new(0, 4)]);
+    }
+
+    #[test]
+    fn edit_listener() {
+        let mut state = XiState::new("hello");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        let edits = Rc::new(RefCell::new(Vec::new()));
+        let log = edits.clone();
+        widget.set_edit_listener(move |delta, sel| {
+            log.borrow_mut().push((delta.summary(), sel.to_vec()));
+        });
+
+        widget.apply_edit_op(&mut state, EditOp::Insert("!".into()));
+        assert_eq!(
+            *edits.borrow(),
+            vec![((Interval::new(5, 5), 1), vec![SelRegion::caret(6)])]
+        );
+        // Edits that don't change the text don't notify.
+        widget.apply_edit_op(&mut state, EditOp::Move(Movement::Left));
+        widget.apply_edit_op(&mut state, EditOp::Insert("".into()));
+        assert_eq!(edits.borrow().len(), 1);
+        assert!(widget.run_command(&mut state, "backspace"));
+        assert_eq!(edits.borrow().len(), 2);
+        assert_eq!(edits.borrow()[1].0, (Interval::new(4, 5), 0));
+    }
+
+    #[test]
+    fn undo_typing() {
+        let mut state = XiState::new("ab");
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.apply_edit_op(&mut state, EditOp::Insert("c".into()));
+        widget.apply_edit_op(&mut state, EditOp::Insert("d".into()));
+        // Moving the caret starts a new undo unit.
+        widget.apply_edit_op(&mut state, EditOp::Move(Movement::Left));
+        widget.apply_edit_op(&mut state, EditOp::Insert("e".into()));
+        assert_eq!(String::from(&state.text), "abced");
+
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "abcd");
+        assert_eq!(&**state.sel, &[SelRegion::caret(3)]);
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "ab");
+        assert!(!widget.undo(&mut state));
+        assert!(widget.redo(&mut state));
+        assert_eq!(String::from(&state.text), "abcd");
+        assert_eq!(&**state.sel, &[SelRegion::caret(4)]);
+    }
+
+    #[test]
+    fn trim_on_save() {
+        let mut state = XiState::new("a  \n\tb\t\nc ");
+        state.set_selection(Selection::new_simple(SelRegion::caret(2)));
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        widget.prepare_save(&mut state);
+        assert_eq!(String::from(&state.text), "a  \n\tb\t\nc ");
+
+        widget.set_trim_on_save(true);
+        widget.set_final_newline_on_save(true);
+        widget.prepare_save(&mut state);
+        assert_eq!(String::from(&state.text), "a\n\tb\nc\n");
+        assert_eq!(&**state.sel, &[SelRegion::caret(1)]);
+        // It's undone in one go.
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "a  \n\tb\t\nc ");
+        assert_eq!(&**state.sel, &[SelRegion::caret(2)]);
+        assert!(!widget.undo(&mut state));
+    }
+
+    #[test]
+    fn final_newline_on_save() {
+        let mut widget = EditWidget::default();
+        widget.set_final_newline_on_save(true);
+        for &(before, after) in &[("a", "a\n"), ("a\n", "a\n"), ("a\n\n\n", "a\n")] {
+            let mut state = XiState::new(before);
+            with_text(|factory| widget.update_layouts(&state, factory));
+            widget.prepare_save(&mut state);
+            assert_eq!(String::from(&state.text), after);
+            // The empty line after the final newline has a layout, as with
+            // a text loaded that way.
+            with_text(|factory| widget.update_layouts(&state, factory));
+            assert_eq!(widget.layouts.len(), 2);
+            let mut loaded = EditWidget::default();
+            with_text(|factory| loaded.update_layouts(&XiState::new(after), factory));
+            assert_eq!(widget.layouts.height(), loaded.layouts.height());
+        }
+    }
+
+    #[test]
+    fn copy_and_cut() {
+        let mut state = XiState::new("ab\ncd\nef");
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::caret(4));
+        sel.add_region(SelRegion::new(6, 8));
+        state.set_selection(sel);
+        let mut widget = EditWidget::default();
+        with_text(|factory| widget.update_layouts(&state, factory));
+        assert_eq!(widget.copy(&state), "cd\nef");
+        assert_eq!(widget.cut(&mut state), "cd\nef");
+        assert_eq!(String::from(&state.text), "ab\n");
+        assert!(widget.undo(&mut state));
+        assert_eq!(String::from(&state.text), "ab\ncd\nef");
+    }
+
+    #[test]
+    fn single_line() {
+        let mut state = XiState::new("ab");
+        let mut widget = EditWidget::default();
+        widget.set_single_line(true);
+        widget.apply_edit_op(&mut state, EditOp::Insert("\n".into()));
+        assert_eq!(text(&state), "ab");
+        assert!(!widget.undo_stack.can_undo());
+        widget.apply_edit_op(&mut state, EditOp::Insert("c\r\nd\n".into()));
+        assert_eq!(text(&state), "abcd");
+    }
+
+    #[test]
+    fn submit() {
+        let mut state = XiState::new("ab");
+        let mut widget = EditWidget::default();
+        widget.set_single_line(true);
+        let submitted = Rc::new(RefCell::new(Vec::new()));
+        let log = submitted.clone();
+        widget.set_submit_listener(move |text| log.borrow_mut().push(String::from(text)));
+        assert_eq!(widget.submit(&state), "ab");
+        assert_eq!(*submitted.borrow(), &["ab"]);
+        assert_eq!(text(&state), "ab");
+        state.apply_edit_op(EditOp::Insert("c".into()), &NullMeasurement);
+        assert_eq!(widget.submit(&state), "abc");
+        assert_eq!(*submitted.borrow(), &["ab", "abc"]);
+    }
+
+    #[test]
+    fn max_len() {
+        let mut state = XiState::new("abc");
+        let mut widget = EditWidget::default();
+        widget.set_max_len(Some(5));
+        widget.apply_edit_op(&mut state, EditOp::Insert("de\u{301}fg".into()));
+        assert_eq!(text(&state), "abcde\u{301}");
+        // At the limit, nothing more can be inserted.
+        widget.apply_edit_op(&mut state, EditOp::Insert("x".into()));
+        assert_eq!(text(&state), "abcde\u{301}");
+        // Replacing a selection makes room for its length.
+        state.set_selection(Selection::new_simple(SelRegion::new(1, 3)));
+        widget.apply_edit_op(&mut state, EditOp::Insert("xyz".into()));
+        assert_eq!(text(&state), "axyde\u{301}");
+        // Each of several carets gets an equal share of the room.
+        widget.set_max_len(Some(9));
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::caret(0));
+        sel.add_region(SelRegion::caret(3));
+        state.set_selection(sel);
+        widget.apply_edit_op(&mut state, EditOp::Insert("123".into()));
+        assert_eq!(text(&state), "12axy12de\u{301}");
+    }
+
+    #[test]
+    fn goto_line() {
+        let mut state = XiState::new("zero\none\ntwo\nthree");
+        assert_eq!(state.goto_line(1), 0);
+        assert_eq!(state.goto_line(3), 9);
+        assert_eq!(&**state.sel, &[SelRegion::caret(9)]);
+        assert_eq!(state.goto_line(4), 13);
+        // Out of range lines are clamped.
+        assert_eq!(state.goto_line(5), 13);
+        assert_eq!(state.goto_line(1000), 13);
+        assert_eq!(state.goto_line(0), 0);
+        // A trailing newline starts a last, empty line.
+        let mut state = XiState::new("zero\n");
+        assert_eq!(state.goto_line(2), 5);
+        assert_eq!(state.goto_line(3), 5);
+    }
+
+    #[test]
+    fn detect_line_ending() {
+        assert_eq!(XiState::new("a\r\nb").line_ending(), LineEnding::CrLf);
+        assert_eq!(XiState::new("a\nb").line_ending(), LineEnding::Lf);
+        assert_eq!(XiState::new("ab").line_ending(), LineEnding::Lf);
+    }
+
+    #[test]
+    fn enter_inserts_crlf() {
+        let mut state = XiState::new(Rope::from("one\r\ntwo"));
+        state.apply_edit_op(EditOp::Insert("\n".into()), &NullMeasurement);
+        assert_eq!(text(&state), "one\r\ntwo\r\n");
+        assert_eq!(state.sel[0].end, 10);
+    }
+
+    #[test]
+    fn paste_normalizes_line_endings() {
+        let mut state = XiState::new("one\ntwo");
+        state.apply_edit_op(EditOp::Insert("a\r\nb".into()), &NullMeasurement);
+        assert_eq!(text(&state), "one\ntwoa\nb");
+    }
+
+    #[test]
+    fn write_normalized() {
+        let mut state = XiState::new("one\r\ntwo");
+        state.apply_edit_op(EditOp::Insert("\nthree\n".into()), &NullMeasurement);
+        let mut buf = Vec::new();
+        state.write_to(&mut buf).unwrap();
+        assert_eq!(buf, b"one\r\ntwo\r\nthree\r\n");
+    }
 }
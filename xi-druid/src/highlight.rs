@@ -0,0 +1,211 @@
+//! Syntax highlighting styles.
+
+use std::ops::Range;
+
+use druid::piet::Color;
+use xi_rope::{Interval, Rope, RopeDelta};
+
+/// A style applied to a range of the document.
+#[derive(Clone, Debug)]
+pub struct StyleSpan {
+    /// The range, as byte offsets into the document.
+    pub range: Range<usize>,
+    pub color: Color,
+}
+
+/// A source of highlighting styles.
+pub trait Highlighter {
+    /// Compute the styles for a range of the document.
+    ///
+    /// The range always covers whole logical lines. The returned spans must
+    /// be sorted and lie within the range.
+    fn highlight(&mut self, text: &Rope, range: Interval) -> Vec<StyleSpan>;
+}
+
+/// A highlighter that doesn't highlight anything.
+#[derive(Default)]
+pub struct NoHighlighter;
+
+impl Highlighter for NoHighlighter {
+    fn highlight(&mut self, _text: &Rope, _range: Interval) -> Vec<StyleSpan> {
+        Vec::new()
+    }
+}
+
+impl Default for Box<dyn Highlighter> {
+    fn default() -> Self {
+        Box::new(NoHighlighter)
+    }
+}
+
+/// Update styles after an edit, asking the highlighter only for the lines
+/// touched by the edit.
+///
+/// Here, `text` is the text after the delta has been applied.
+pub fn update_spans(
+    spans: &[StyleSpan],
+    delta: &RopeDelta,
+    text: &Rope,
+    highlighter: &mut dyn Highlighter,
+) -> Vec<StyleSpan> {
+    let (iv, new_len) = delta.summary();
+    // The changed lines, in new document coordinates.
+    let start = text.offset_of_line(text.line_of_offset(iv.start));
+    let end = text.offset_of_line(text.line_of_offset(iv.start + new_len) + 1);
+    // And the same range in old document coordinates.
+    let old_end = end + iv.size() - new_len;
+
+    let mut result = Vec::with_capacity(spans.len());
+    for span in spans {
+        if span.range.start < start {
+            let end = span.range.end.min(start);
+            result.push(StyleSpan {
+                range: span.range.start..end,
+                color: span.color.clone(),
+            });
+        } else {
+            break;
+        }
+    }
+    result.extend(highlighter.highlight(text, Interval::new(start, end)));
+    for span in spans {
+        if span.range.end > old_end {
+            let start = span.range.start.max(old_end);
+            result.push(StyleSpan {
+                range: start + end - old_end..span.range.end + end - old_end,
+                color: span.color.clone(),
+            });
+        }
+    }
+    result
+}
+
+/// The spans intersecting a paragraph, clipped to it, with offsets
+/// relative to the start of the paragraph.
+///
+/// The spans are expected to be sorted by start offset.
+pub fn para_spans(
+    spans: &[StyleSpan],
+    para: Range<usize>,
+) -> impl Iterator<Item = (Range<usize>, &Color)> {
+    // Spans starting after the paragraph can't intersect it.
+    let n = spans
+        .iter()
+        .position(|span| span.range.start >= para.end)
+        .unwrap_or(spans.len());
+    spans[..n].iter().filter_map(move |span| {
+        let start = span.range.start.max(para.start);
+        let end = span.range.end.min(para.end);
+        if start < end {
+            Some((start - para.start..end - para.start, &span.color))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use druid::piet::Color;
+
+    use xi_rope::{DeltaBuilder, Interval, Rope};
+
+    use super::{para_spans, update_spans, Highlighter, StyleSpan};
+
+    /// A highlighter that colors every line red, and records the ranges it
+    /// was asked to highlight.
+    #[derive(Default)]
+    struct StubHighlighter {
+        requests: Vec<Interval>,
+    }
+
+    impl Highlighter for StubHighlighter {
+        fn highlight(&mut self, text: &Rope, range: Interval) -> Vec<StyleSpan> {
+            self.requests.push(range);
+            let mut spans = Vec::new();
+            let mut offset = range.start;
+            for line in text.lines_raw(range.start..range.end) {
+                spans.push(span(offset, offset + line.len(), &red()));
+                offset += line.len();
+            }
+            spans
+        }
+    }
+
+    fn red() -> Color {
+        Color::rgb8(255, 0, 0)
+    }
+
+    fn ranges(spans: &[StyleSpan]) -> Vec<std::ops::Range<usize>> {
+        spans.iter().map(|span| span.range.clone()).collect()
+    }
+
+    #[test]
+    fn rehighlight_changed_line() {
+        let old_text = Rope::from("zero\none\ntwo\nthree\n");
+        let mut highlighter = StubHighlighter::default();
+        let spans = highlighter.highlight(&old_text, Interval::new(0, old_text.len()));
+        assert_eq!(ranges(&spans), vec![0..5, 5..9, 9..13, 13..19]);
+        highlighter.requests.clear();
+
+        // Insert "xx" in the middle of "two".
+        let mut builder = DeltaBuilder::new(old_text.len());
+        builder.replace(Interval::new(10, 10), Rope::from("xx"));
+        let delta = builder.build();
+        let text = delta.apply(&old_text);
+        let spans = update_spans(&spans, &delta, &text, &mut highlighter);
+        assert_eq!(highlighter.requests, vec![Interval::new(9, 15)]);
+        assert_eq!(ranges(&spans), vec![0..5, 5..9, 9..15, 15..21]);
+    }
+
+    #[test]
+    fn rehighlight_joined_lines() {
+        let old_text = Rope::from("zero\none\ntwo\n");
+        let mut highlighter = StubHighlighter::default();
+        let spans = highlighter.highlight(&old_text, Interval::new(0, old_text.len()));
+        highlighter.requests.clear();
+
+        // Delete "ne\ntw", joining two lines.
+        let mut builder = DeltaBuilder::new(old_text.len());
+        builder.delete(Interval::new(6, 11));
+        let delta = builder.build();
+        let text = delta.apply(&old_text);
+        assert_eq!(String::from(&text), "zero\noo\n");
+        let spans = update_spans(&spans, &delta, &text, &mut highlighter);
+        assert_eq!(highlighter.requests, vec![Interval::new(5, 8)]);
+        assert_eq!(ranges(&spans), vec![0..5, 5..8]);
+    }
+
+    fn span(start: usize, end: usize, color: &Color) -> StyleSpan {
+        StyleSpan {
+            range: start..end,
+            color: color.clone(),
+        }
+    }
+
+    #[test]
+    fn clip_to_paragraph() {
+        let red = Color::rgb8(255, 0, 0);
+        let blue = Color::rgb8(0, 0, 255);
+        let spans = vec![
+            span(0, 3, &red),
+            span(5, 12, &blue),
+            span(14, 16, &red),
+            span(20, 30, &blue),
+        ];
+        // Paragraph 10..20, partially covered by two spans.
+        let result: Vec<_> = para_spans(&spans, 10..20)
+            .map(|(range, color)| (range, color.as_rgba_u32()))
+            .collect();
+        assert_eq!(
+            result,
+            vec![(0..2, blue.as_rgba_u32()), (4..6, red.as_rgba_u32())]
+        );
+        // A span covering the whole paragraph.
+        let result: Vec<_> = para_spans(&spans, 6..9).map(|(range, _)| range).collect();
+        assert_eq!(result, vec![0..3]);
+        // No spans.
+        assert_eq!(para_spans(&spans, 3..5).count(), 0);
+        assert_eq!(para_spans(&[], 0..5).count(), 0);
+    }
+}
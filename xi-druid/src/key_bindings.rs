@@ -2,33 +2,205 @@
 
 use druid::{KbKey, KeyEvent};
 
-use xi_text_core::{EditOp, Movement};
+use xi_text_core::bracket;
+use xi_text_core::{EditOp, Movement, Selection};
+
+/// A key command to move through the undo history.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistoryOp {
+    Undo,
+    Redo,
+}
+
+/// A key command to copy or cut to the clipboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipboardOp {
+    Copy,
+    Cut,
+}
+
+/// The default distance between tab stops, in columns.
+const TAB_WIDTH: usize = 4;
+
+/// The default line comment prefix.
+const LINE_COMMENT: &str = "//";
 
 /// A map from keys to edit commands.
 ///
-/// For now, this is just a stateless map, but it could load
-/// preferences or do vi-like bindings.
-#[derive(Default)]
-pub struct KeyBindings;
+/// For now, the only state is the overwrite mode and the tab settings, but
+/// it could load preferences or do vi-like bindings.
+pub struct KeyBindings {
+    /// Whether typed characters replace the character after the caret.
+    overwrite: bool,
+    /// Whether Tab inserts spaces up to the next tab stop.
+    soft_tabs: bool,
+    tab_width: usize,
+    /// The prefix that Ctrl+/ toggles on the selected lines.
+    line_comment: String,
+    /// Whether typing an opening bracket or quote over a selection wraps
+    /// it in the pair.
+    auto_surround: bool,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            overwrite: false,
+            soft_tabs: false,
+            tab_width: TAB_WIDTH,
+            line_comment: LINE_COMMENT.into(),
+            auto_surround: false,
+        }
+    }
+}
 
 impl KeyBindings {
-    pub fn map_key(&mut self, k: &KeyEvent) -> Option<EditOp> {
+    /// Map a key to an edit command, given the current selection.
+    pub fn map_key(&mut self, k: &KeyEvent, sel: &Selection) -> Option<EditOp> {
         match &k.key {
-            KbKey::Character(c) => {
-                // TODO: make this logic more sophisticated
-                if !k.mods.ctrl() {
-                    Some(EditOp::Insert(c.clone()))
-                } else {
-                    None
-                }
+            KbKey::Character(c) if k.mods.ctrl() && c == "/" => {
+                Some(EditOp::ToggleLineComment(self.line_comment.clone()))
+            }
+            KbKey::Character(c) if k.mods.ctrl() && k.mods.shift() && c.to_lowercase() == "u" => {
+                Some(EditOp::SwapCase)
+            }
+            KbKey::Character(_) if k.mods.ctrl() => None,
+            KbKey::Character(c) => Some(self.char_op(c, sel)),
+            KbKey::Insert => {
+                self.overwrite = !self.overwrite;
+                None
             }
             KbKey::Enter => Some(EditOp::Insert("\n".into())),
+            KbKey::Tab => Some(EditOp::InsertTab {
+                soft: self.soft_tabs,
+                width: self.tab_width,
+            }),
             KbKey::Backspace => Some(EditOp::Backspace),
             KbKey::ArrowLeft => Some(EditOp::Move(Movement::Left)),
             KbKey::ArrowRight => Some(EditOp::Move(Movement::Right)),
+            KbKey::ArrowUp if k.mods.ctrl() && k.mods.alt() => {
+                Some(EditOp::AddCaretVertical(Movement::Up))
+            }
+            KbKey::ArrowDown if k.mods.ctrl() && k.mods.alt() => {
+                Some(EditOp::AddCaretVertical(Movement::Down))
+            }
+            KbKey::ArrowUp if k.mods.alt() => Some(EditOp::MoveLine(Movement::Up)),
+            KbKey::ArrowDown if k.mods.alt() => Some(EditOp::MoveLine(Movement::Down)),
+            KbKey::Home if k.mods.shift() => Some(EditOp::Extend(Movement::SmartHome)),
+            KbKey::Home => Some(EditOp::Move(Movement::SmartHome)),
             KbKey::ArrowUp => Some(EditOp::Move(Movement::Up)),
             KbKey::ArrowDown => Some(EditOp::Move(Movement::Down)),
+            KbKey::Escape => Some(EditOp::CollapseSelection),
+            _ => None,
+        }
+    }
+
+    /// The edit command for typing a character.
+    ///
+    /// An opening bracket or quote surrounds the selected regions, if there
+    /// are any and auto surround is on; at bare carets it is typed like any
+    /// other character, so that overwrite mode applies.
+    fn char_op(&self, c: &str, sel: &Selection) -> EditOp {
+        // TODO: make this logic more sophisticated
+        let surround = if self.auto_surround && sel.iter().any(|region| !region.is_caret()) {
+            bracket::surround_pair(c)
+        } else {
+            None
+        };
+        if let Some(close) = surround {
+            EditOp::Surround {
+                open: c.into(),
+                close: close.into(),
+            }
+        } else if self.overwrite {
+            EditOp::Overwrite(c.into())
+        } else {
+            EditOp::Insert(c.into())
+        }
+    }
+
+    /// Map undo and redo keys: Ctrl+Z, and Ctrl+Shift+Z or Ctrl+Y.
+    pub fn map_history_key(&self, k: &KeyEvent) -> Option<HistoryOp> {
+        match &k.key {
+            KbKey::Character(c) if k.mods.ctrl() => match c.to_lowercase().as_str() {
+                "z" if k.mods.shift() => Some(HistoryOp::Redo),
+                "z" => Some(HistoryOp::Undo),
+                "y" => Some(HistoryOp::Redo),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Map clipboard keys: Ctrl+C and Ctrl+X.
+    pub fn map_clipboard_key(&self, k: &KeyEvent) -> Option<ClipboardOp> {
+        match &k.key {
+            KbKey::Character(c) if k.mods.ctrl() => match c.to_lowercase().as_str() {
+                "c" => Some(ClipboardOp::Copy),
+                "x" => Some(ClipboardOp::Cut),
+                _ => None,
+            },
             _ => None,
         }
     }
+
+    /// Whether overwrite mode is on.
+    pub fn overwrite(&self) -> bool {
+        self.overwrite
+    }
+
+    /// Set whether Tab inserts spaces rather than a tab character.
+    pub fn set_soft_tabs(&mut self, soft_tabs: bool) {
+        self.soft_tabs = soft_tabs;
+    }
+
+    /// Set the distance between tab stops, in columns.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tab_width` is zero.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        assert!(tab_width > 0, "tab width must be nonzero");
+        self.tab_width = tab_width;
+    }
+
+    /// Set the prefix of a line comment, toggled by Ctrl+/.
+    pub fn set_line_comment(&mut self, prefix: impl Into<String>) {
+        self.line_comment = prefix.into();
+    }
+
+    /// Set whether typing an opening bracket or quote over a selection
+    /// wraps it in the pair, rather than replacing it.
+    pub fn set_auto_surround(&mut self, auto_surround: bool) {
+        self.auto_surround = auto_surround;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use xi_text_core::{EditOp, SelRegion, Selection};
+
+    use super::KeyBindings;
+
+    #[test]
+    fn surround_only_selections() {
+        let mut bindings = KeyBindings::default();
+        bindings.set_auto_surround(true);
+        bindings.overwrite = true;
+        let caret = Selection::new_simple(SelRegion::caret(1));
+        let mut sel = caret.clone();
+        sel.add_region(SelRegion::new(2, 4));
+
+        let op = bindings.char_op("(", &sel);
+        assert!(matches!(op, EditOp::Surround { .. }));
+        // At bare carets, a bracket is typed over the next character.
+        let op = bindings.char_op("(", &caret);
+        assert!(matches!(op, EditOp::Overwrite(s) if s == "("));
+        let op = bindings.char_op("a", &sel);
+        assert!(matches!(op, EditOp::Overwrite(_)));
+
+        bindings.set_auto_surround(false);
+        let op = bindings.char_op("(", &sel);
+        assert!(matches!(op, EditOp::Overwrite(_)));
+    }
 }
@@ -1,13 +1,28 @@
 //! A rope-based vector of layouts.
 
+#[cfg(test)]
+use std::cell::Cell;
 use std::ops::Range;
 use std::sync::Arc;
 
+use druid::kurbo::Point;
 use druid::piet::{PietTextLayout, TextLayout};
 
 use xi_rope::interval::{Interval, IntervalBounds};
 use xi_rope::tree::{Cursor, DefaultMetric, Leaf, Metric, Node, NodeInfo, TreeBuilder};
 
+#[cfg(test)]
+thread_local! {
+    /// The number of lookups in layout ropes on this thread, for tests of
+    /// how many an operation needs.
+    pub static N_LOOKUPS: Cell<usize> = Cell::new(0);
+}
+
+#[cfg(test)]
+fn note_lookup() {
+    N_LOOKUPS.with(|n| n.set(n.get() + 1));
+}
+
 /// A type representing a height measure.
 ///
 /// Internally this is stored as `usize` using fixed point arithmetic,
@@ -19,13 +34,72 @@ use xi_rope::tree::{Cursor, DefaultMetric, Leaf, Metric, Node, NodeInfo, TreeBui
 /// means a maximum height of 16M, which should be good enough for most
 /// practical use but could be a limitation. Of course, on 64 bit platforms,
 /// the limit of 7.2e16 should never be a problem.
-#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Height(usize);
 
 /// An individual layout within the rope.
 ///
-/// Right now, this is just a Piet TextLayout, but we might add more stuff.
-pub struct Layout(PietTextLayout);
+/// Right now, this is a Piet TextLayout, plus a user-provided count which
+/// the rope sums, in the same way as heights. The widget uses the count for
+/// the length of the paragraph's text, but it can be anything additive.
+///
+/// For tests, the text layout can be a `StubLayout` instead, which doesn't
+/// need a Piet backend. The hit-testing methods work with either.
+///
+/// A layout may also be a placeholder, standing in for a paragraph that
+/// hasn't been laid out yet. A placeholder has an estimated height, so that
+/// heights (and hence scrolling) are approximately right before layout; it
+/// is replaced with the real layout using `LayoutRope::set`.
+///
+/// A layout can be hidden, as when its paragraph is folded. It keeps its
+/// text layout, for hit testing, but its height is zero.
+pub struct Layout {
+    // This is `None` for a placeholder.
+    inner: Option<TextLayoutKind>,
+    // The height of a placeholder.
+    estimated_height: Height,
+    // The factor the height of the Piet layout is multiplied by.
+    line_spacing: f64,
+    // The advance of a space in the font of the layout, if measured.
+    space_width: Option<f64>,
+    hidden: bool,
+    count: usize,
+}
+
+enum TextLayoutKind {
+    Piet(PietTextLayout),
+    Stub(StubLayout),
+}
+
+/// A stand-in for a Piet layout, for tests: a grid of equal cells, one per
+/// byte of text, wrapped after a fixed number of bytes.
+#[derive(Clone, Debug)]
+pub struct StubLayout {
+    len: usize,
+    wrap: usize,
+    cell_width: f64,
+    line_height: f64,
+}
+
+/// The position of an offset in a layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextPosition {
+    /// The point on the baseline before the offset.
+    pub point: Point,
+    /// The visual line containing the offset.
+    pub line: usize,
+}
+
+/// The extent of a visual line in a layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VisualLine {
+    pub start_offset: usize,
+    /// The end offset, including any trailing whitespace.
+    pub end_offset: usize,
+    /// The top of the line.
+    pub y_offset: f64,
+    pub height: f64,
+}
 
 #[derive(Clone, Default)]
 pub struct LayoutRope(Node<LayoutInfo>);
@@ -35,6 +109,9 @@ pub struct LayoutRopeBuilder(TreeBuilder<LayoutInfo>);
 /// The height metric of the rope, which is in raw Height fractions.
 struct HeightMetric;
 
+/// The metric of the user-provided counts of the layouts.
+struct CountMetric;
+
 /// The base metric of the rope, which just counts the number of layouts.
 pub struct BaseMetric;
 
@@ -49,6 +126,8 @@ struct LayoutLeaf {
 struct LayoutInfo {
     /// The height of this section of rope.
     height: Height,
+    /// The sum of the counts of the layouts in this section of rope.
+    count: usize,
 }
 
 impl std::ops::Add for Height {
@@ -65,6 +144,24 @@ impl std::ops::AddAssign for Height {
     }
 }
 
+/// Subtraction of heights.
+///
+/// As with integers, this panics on underflow in debug builds; use
+/// `saturating_sub` when the result may be negative.
+impl std::ops::Sub for Height {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Height(self.0 - other.0)
+    }
+}
+
+impl std::ops::SubAssign for Height {
+    fn sub_assign(&mut self, other: Self) {
+        self.0 -= other.0
+    }
+}
+
 impl Height {
     /// The number of fractional bits in the representation.
     pub const HEIGHT_FRAC_BITS: usize = 8;
@@ -82,27 +179,277 @@ impl Height {
         self.0
     }
 
+    /// Convert from `f64`.
+    ///
+    /// Negative values and NaN map to `ZERO`, and values too large to
+    /// represent saturate.
     pub fn from_f64(height: f64) -> Height {
-        Height((height * Self::SCALE_FACTOR).round() as usize)
+        let scaled = (height * Self::SCALE_FACTOR).round();
+        if scaled.is_nan() || scaled <= 0.0 {
+            Height::ZERO
+        } else if scaled >= usize::MAX as f64 {
+            Height(usize::MAX)
+        } else {
+            Height(scaled as usize)
+        }
     }
 
     pub fn to_f64(self) -> f64 {
         self.0 as f64 / Self::SCALE_FACTOR
     }
+
+    /// Subtraction, clamping at `ZERO`.
+    pub fn saturating_sub(self, other: Height) -> Height {
+        Height(self.0.saturating_sub(other.0))
+    }
 }
 
 impl Layout {
     pub fn new(inner: PietTextLayout) -> Layout {
-        Layout(inner)
+        Layout {
+            inner: Some(TextLayoutKind::Piet(inner)),
+            estimated_height: Height::ZERO,
+            line_spacing: 1.0,
+            space_width: None,
+            hidden: false,
+            count: 0,
+        }
+    }
+
+    /// A layout with a stub in place of a Piet layout.
+    pub fn stub(stub: StubLayout) -> Layout {
+        Layout {
+            inner: Some(TextLayoutKind::Stub(stub)),
+            estimated_height: Height::ZERO,
+            line_spacing: 1.0,
+            space_width: None,
+            hidden: false,
+            count: 0,
+        }
+    }
+
+    /// A placeholder for a paragraph that hasn't been laid out yet, with
+    /// an estimate of its height.
+    pub fn placeholder(estimated_height: Height) -> Layout {
+        Layout {
+            inner: None,
+            estimated_height,
+            line_spacing: 1.0,
+            space_width: None,
+            hidden: false,
+            count: 0,
+        }
     }
 
+    pub fn is_placeholder(&self) -> bool {
+        self.inner.is_none()
+    }
+
+    /// Returns a layout with the given count.
+    pub fn with_count(self, count: usize) -> Layout {
+        Layout { count, ..self }
+    }
+
+    /// Returns a layout with its height multiplied by the given factor.
+    ///
+    /// The extra space is below the text. The spaced height is rounded to
+    /// a `Height` once, so equal layouts always get equal heights. This has
+    /// no effect on a placeholder, whose height is the estimate as given.
+    pub fn with_line_spacing(self, line_spacing: f64) -> Layout {
+        Layout {
+            line_spacing,
+            ..self
+        }
+    }
+
+    /// Returns a layout with the measured advance of a space.
+    pub fn with_space_width(self, space_width: f64) -> Layout {
+        Layout {
+            space_width: Some(space_width),
+            ..self
+        }
+    }
+
+    /// Returns a layout that is hidden, or not.
+    pub fn with_hidden(self, hidden: bool) -> Layout {
+        Layout { hidden, ..self }
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// The advance of a space, for sizing things past the end of the text.
+    ///
+    /// This is the measured width if there is one, or the cell width of a
+    /// stub. It is `None` for an unmeasured Piet layout or a placeholder.
+    pub fn space_width(&self) -> Option<f64> {
+        match (&self.inner, self.space_width) {
+            (_, Some(width)) => Some(width),
+            (Some(TextLayoutKind::Stub(stub)), None) => Some(stub.cell_width),
+            _ => None,
+        }
+    }
+
+    /// The Piet layout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this is a placeholder or a stub.
     pub fn piet_layout(&self) -> &PietTextLayout {
-        &self.0
+        self.as_piet()
+            .expect("Layout::piet_layout called on a placeholder or stub")
+    }
+
+    /// The Piet layout, or `None` for a placeholder or a stub.
+    pub fn as_piet(&self) -> Option<&PietTextLayout> {
+        match &self.inner {
+            Some(TextLayoutKind::Piet(inner)) => Some(inner),
+            _ => None,
+        }
+    }
+
+    fn text_layout(&self) -> &TextLayoutKind {
+        self.inner
+            .as_ref()
+            .expect("Layout hit testing called on a placeholder")
+    }
+
+    /// The number of visual lines.
+    ///
+    /// # Panics
+    ///
+    /// This and the other hit-testing methods panic if this is a
+    /// placeholder.
+    pub fn line_count(&self) -> usize {
+        match self.text_layout() {
+            TextLayoutKind::Piet(inner) => inner.line_count(),
+            TextLayoutKind::Stub(stub) => stub.line_count(),
+        }
+    }
+
+    /// The extent of a visual line, or `None` if it is out of range.
+    pub fn line_metric(&self, line: usize) -> Option<VisualLine> {
+        match self.text_layout() {
+            TextLayoutKind::Piet(inner) => inner.line_metric(line).map(|metric| VisualLine {
+                start_offset: metric.start_offset,
+                end_offset: metric.end_offset,
+                y_offset: metric.y_offset,
+                height: metric.height,
+            }),
+            TextLayoutKind::Stub(stub) => stub.line_metric(line),
+        }
+    }
+
+    /// The position of an offset, relative to the start of the text.
+    pub fn hit_test_text_position(&self, offset: usize) -> TextPosition {
+        match self.text_layout() {
+            TextLayoutKind::Piet(inner) => {
+                let hit = inner.hit_test_text_position(offset);
+                TextPosition {
+                    point: hit.point,
+                    line: hit.line,
+                }
+            }
+            TextLayoutKind::Stub(stub) => stub.hit_test_text_position(offset),
+        }
     }
 
+    /// The offset nearest a point.
+    pub fn hit_test_point(&self, point: Point) -> usize {
+        match self.text_layout() {
+            TextLayoutKind::Piet(inner) => inner.hit_test_point(point).idx,
+            TextLayoutKind::Stub(stub) => stub.hit_test_point(point),
+        }
+    }
+
+    /// The width of the widest visual line.
+    pub fn width(&self) -> f64 {
+        match self.text_layout() {
+            TextLayoutKind::Piet(inner) => inner.size().width,
+            TextLayoutKind::Stub(stub) => stub.width(),
+        }
+    }
+
+    /// The user-provided count.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The height of the layout, or the estimate for a placeholder.
+    ///
+    /// The height of a hidden layout is zero.
     pub fn height(&self) -> Height {
-        let size = self.0.size();
-        Height::from_f64(size.height)
+        if self.hidden {
+            return Height::ZERO;
+        }
+        let height = match &self.inner {
+            Some(TextLayoutKind::Piet(inner)) => inner.size().height,
+            Some(TextLayoutKind::Stub(stub)) => stub.line_count() as f64 * stub.line_height,
+            None => return self.estimated_height,
+        };
+        Height::from_f64(height * self.line_spacing)
+    }
+}
+
+impl StubLayout {
+    /// The baseline, as a fraction of the line height down from its top.
+    const BASELINE: f64 = 0.75;
+
+    /// A stub for `len` bytes of text, wrapped after every `wrap` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `wrap` is zero.
+    pub fn new(len: usize, wrap: usize, cell_width: f64, line_height: f64) -> StubLayout {
+        assert!(wrap > 0, "StubLayout wrap must be nonzero");
+        StubLayout {
+            len,
+            wrap,
+            cell_width,
+            line_height,
+        }
+    }
+
+    fn line_count(&self) -> usize {
+        ((self.len + self.wrap - 1) / self.wrap).max(1)
+    }
+
+    fn line_metric(&self, line: usize) -> Option<VisualLine> {
+        if line >= self.line_count() {
+            return None;
+        }
+        let start_offset = line * self.wrap;
+        Some(VisualLine {
+            start_offset,
+            end_offset: (start_offset + self.wrap).min(self.len),
+            y_offset: line as f64 * self.line_height,
+            height: self.line_height,
+        })
+    }
+
+    fn hit_test_text_position(&self, offset: usize) -> TextPosition {
+        let offset = offset.min(self.len);
+        // As with a soft line break, an offset at a wrap point is at the
+        // start of the next line, unless it is the end of the text.
+        let line = (offset / self.wrap).min(self.line_count() - 1);
+        let col = offset - line * self.wrap;
+        let y = (line as f64 + Self::BASELINE) * self.line_height;
+        TextPosition {
+            point: Point::new(col as f64 * self.cell_width, y),
+            line,
+        }
+    }
+
+    fn hit_test_point(&self, point: Point) -> usize {
+        let line = (point.y / self.line_height).floor().max(0.0) as usize;
+        let metric = self.line_metric(line.min(self.line_count() - 1)).unwrap();
+        let col = (point.x / self.cell_width).round().max(0.0) as usize;
+        (metric.start_offset + col).min(metric.end_offset)
+    }
+
+    fn width(&self) -> f64 {
+        self.len.min(self.wrap) as f64 * self.cell_width
     }
 }
 
@@ -111,14 +458,17 @@ impl NodeInfo for LayoutInfo {
 
     fn accumulate(&mut self, other: &Self) {
         self.height += other.height;
+        self.count += other.count;
     }
 
     fn compute_info(leaf: &Self::L) -> Self {
         let mut height = Height::ZERO;
-        for (leaf_height, _) in &leaf.data {
+        let mut count = 0;
+        for (leaf_height, layout) in &leaf.data {
             height += *leaf_height;
+            count += layout.count;
         }
-        LayoutInfo { height }
+        LayoutInfo { height, count }
     }
 }
 
@@ -157,7 +507,42 @@ impl From<Vec<(Height, Arc<Layout>)>> for LayoutRope {
     }
 }
 
+impl std::iter::FromIterator<Layout> for LayoutRope {
+    fn from_iter<I: IntoIterator<Item = Layout>>(iter: I) -> Self {
+        LayoutRope::from_leaf_items(iter.into_iter().map(|layout| (layout.height(), layout)))
+    }
+}
+
 impl LayoutRope {
+    /// A rope of layouts with the given heights, in px, rather than their
+    /// own.
+    ///
+    /// This is for estimated heights, and for tests that need exact
+    /// heights.
+    pub fn from_heights(items: impl IntoIterator<Item = (f64, Layout)>) -> LayoutRope {
+        LayoutRope::from_leaf_items(
+            items
+                .into_iter()
+                .map(|(height, layout)| (Height::from_f64(height), layout)),
+        )
+    }
+
+    fn from_leaf_items(items: impl Iterator<Item = (Height, Layout)>) -> LayoutRope {
+        // Build full leaves directly, rather than concatenating singletons.
+        let mut b = TreeBuilder::new();
+        let mut leaf = LayoutLeaf::default();
+        for (height, layout) in items {
+            leaf.data.push((height, Arc::new(layout)));
+            if leaf.data.len() == MAX_LEAF {
+                b.push_leaf(std::mem::take(&mut leaf));
+            }
+        }
+        if !leaf.data.is_empty() {
+            b.push_leaf(leaf);
+        }
+        LayoutRope(b.build())
+    }
+
     /// The number of layouts in the rope.
     pub fn len(&self) -> usize {
         self.0.len()
@@ -168,6 +553,11 @@ impl LayoutRope {
         Height::from_raw_frac(self.0.measure::<HeightMetric>())
     }
 
+    /// The total height of the rope, in px.
+    pub fn pixel_height(&self) -> f64 {
+        self.height().to_f64()
+    }
+
     /// A rope consisting of a single layout.
     pub fn singleton(item: Layout) -> LayoutRope {
         LayoutRope(Node::from_leaf(Self::singleton_leaf(item)))
@@ -181,6 +571,8 @@ impl LayoutRope {
     }
 
     pub fn get(&self, index: usize) -> Option<(Height, &Layout)> {
+        #[cfg(test)]
+        note_lookup();
         let cursor = Cursor::new(&self.0, index);
         cursor
             .get_leaf()
@@ -188,6 +580,41 @@ impl LayoutRope {
             .map(|(height, layout)| (*height, &**layout))
     }
 
+    /// The leaf containing the layout at the given index, as the index of
+    /// its first layout and the layouts (with their heights) it contains.
+    ///
+    /// This is for sequential access: neighboring layouts are usually in
+    /// the same leaf, so they can be found without traversing the rope.
+    pub fn leaf_at(&self, index: usize) -> Option<(usize, &[(Height, Arc<Layout>)])> {
+        if index >= self.len() {
+            return None;
+        }
+        let cursor = Cursor::new(&self.0, index);
+        cursor
+            .get_leaf()
+            .map(|(leaf, offset)| (index - offset, leaf.data.as_slice()))
+    }
+
+    /// The height of the layout at the given index.
+    pub fn height_at(&self, index: usize) -> Option<Height> {
+        self.get(index).map(|(height, _)| height)
+    }
+
+    /// Iterate over the layouts, without their heights.
+    pub fn layouts(&self) -> impl Iterator<Item = &Layout> {
+        self.into_iter().map(|(_, layout)| &**layout)
+    }
+
+    /// Like `get`, but clones the `Arc` so the layout can be shared with
+    /// another rope.
+    pub fn get_arc(&self, index: usize) -> Option<(Height, Arc<Layout>)> {
+        let cursor = Cursor::new(&self.0, index);
+        cursor
+            .get_leaf()
+            .and_then(|(leaf, offset)| leaf.data.get(offset))
+            .map(|(height, layout)| (*height, layout.clone()))
+    }
+
     // These mutation methods might go away in favor of using the builder.
 
     pub fn push(&mut self, item: Layout) {
@@ -196,14 +623,26 @@ impl LayoutRope {
         self.0 = Node::concat(self.0.clone(), el.0)
     }
 
+    /// Remove the layout at the given index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= self.len()`.
     pub fn remove(&mut self, index: usize) {
+        self.check_index(index, "remove");
         let mut b = TreeBuilder::new();
         self.push_subseq(&mut b, Interval::new(0, index));
         self.push_subseq(&mut b, Interval::new(index + 1, self.len()));
         self.0 = b.build();
     }
 
+    /// Replace the layout at the given index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index >= self.len()`.
     pub fn set(&mut self, index: usize, item: Layout) {
+        self.check_index(index, "set");
         let mut b = TreeBuilder::new();
         self.push_subseq(&mut b, Interval::new(0, index));
         b.push_leaf(Self::singleton_leaf(item));
@@ -211,7 +650,18 @@ impl LayoutRope {
         self.0 = b.build();
     }
 
+    /// Insert a layout at the given index, shifting the following ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > self.len()`.
     pub fn insert(&mut self, index: usize, value: Layout) {
+        assert!(
+            index <= self.len(),
+            "LayoutRope::insert: index {} out of range for length {}",
+            index,
+            self.len()
+        );
         let mut b = TreeBuilder::new();
         self.push_subseq(&mut b, Interval::new(0, index));
         b.push_leaf(Self::singleton_leaf(value));
@@ -219,6 +669,38 @@ impl LayoutRope {
         self.0 = b.build();
     }
 
+    /// Replace the layouts in the given range with the contents of another
+    /// rope.
+    ///
+    /// The unchanged layouts are shared, not copied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn edit(&mut self, range: Range<usize>, replacement: LayoutRope) {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "LayoutRope::edit: range {:?} out of range for length {}",
+            range,
+            self.len()
+        );
+        let mut b = TreeBuilder::new();
+        self.push_subseq(&mut b, Interval::new(0, range.start));
+        b.push(replacement.0);
+        self.push_subseq(&mut b, Interval::new(range.end, self.len()));
+        self.0 = b.build();
+    }
+
+    fn check_index(&self, index: usize, method: &str) {
+        assert!(
+            index < self.len(),
+            "LayoutRope::{}: index {} out of range for length {}",
+            method,
+            index,
+            self.len()
+        );
+    }
+
     fn iter_chunks(&self, range: impl IntervalBounds) -> ChunkIter {
         let Interval { start, end } = range.into_interval(self.len());
 
@@ -228,29 +710,135 @@ impl LayoutRope {
         }
     }
 
+    /// Iterate over chunks of the layouts in a range, each with the height
+    /// at the top of its first layout.
+    pub fn iter_chunks_with_tops(&self, range: impl IntervalBounds) -> TopsChunkIter {
+        let Interval { start, end } = range.into_interval(self.len());
+        TopsChunkIter {
+            inner: self.iter_chunks(start..end),
+            top: self.height_of_index(start),
+        }
+    }
+
+    /// Iterate over the layouts, each with the height at its top.
+    pub fn iter_with_tops(&self) -> impl Iterator<Item = (Height, &Layout)> {
+        self.iter_chunks_with_tops(..).flat_map(|(top, chunk)| {
+            chunk.iter().scan(top, |top, (height, layout)| {
+                let layout_top = *top;
+                *top += *height;
+                Some((layout_top, &**layout))
+            })
+        })
+    }
+
     /// The height at the top of the layout at the given index.
     ///
     /// This is simply the sum of the heights of the layouts that come before
     /// it.
     pub fn height_of_index(&self, index: usize) -> Height {
+        #[cfg(test)]
+        note_lookup();
         Height::from_raw_frac(self.0.count::<HeightMetric>(index))
     }
 
+    /// The height at the top of the layout at the given index, in px.
+    pub fn pixel_height_of_index(&self, index: usize) -> f64 {
+        self.height_of_index(index).to_f64()
+    }
+
     /// The layout at the given height.
     ///
-    /// Edge cases get interesting (especially since zero-height layouts are
-    /// not forbidden), so here is a more precise spec: it is the first layout
-    /// that either contains (in the closed-open interval sense) the given
-    /// height, or is a zero-height layout at the given height.
+    /// This is the first layout that starts at the given height, or, if no
+    /// layout does, the one containing it. So among zero-height layouts at
+    /// the given height, it is the first; and at or past the total height,
+    /// it is the number of layouts, unless a zero-height layout is there.
+    pub fn index_of_height(&self, height: Height) -> usize {
+        #[cfg(test)]
+        note_lookup();
+        let ix = self
+            .0
+            .count_base_units::<HeightMetric>(height.as_raw_frac());
+        self.first_at(ix, |i| self.height_of_index(i) == height)
+    }
+
+    /// The layout of nonzero height at the given height.
     ///
-    /// If the total height is given and the rope does not end on a zero-height
-    /// layout, then it returns the number of layouts.
+    /// Unlike `index_of_height`, this skips zero-height layouts, like hidden
+    /// ones, without stepping over them one at a time. A height at or past
+    /// the total height is in the last layout of nonzero height. Returns
+    /// `None` if no layout has a height.
+    pub fn shown_index_of_height(&self, height: Height) -> Option<usize> {
+        let total = self.height().as_raw_frac();
+        if total == 0 {
+            return None;
+        }
+        // The layout is the one containing this unit of height. A measure
+        // strictly inside a layout finds it directly, whatever zero-height
+        // layouts are at its ends. The end of the unit is inside the layout
+        // unless the unit is its last, and the start unless it is its first.
+        let unit = height.as_raw_frac().min(total - 1);
+        let last = self.len() - 1;
+        let after = self.base_units_of_height(unit + 1).min(last);
+        if self.contains_height_unit(after, unit) {
+            return Some(after);
+        }
+        let at = self.base_units_of_height(unit).min(last);
+        if self.contains_height_unit(at, unit) {
+            return Some(at);
+        }
+        // The layout is one unit high, and only zero-height layouts come
+        // between it and the one found for the end of the unit.
+        let mut ix = after;
+        while !self.contains_height_unit(ix, unit) {
+            ix -= 1;
+        }
+        Some(ix)
+    }
+
+    fn base_units_of_height(&self, height: usize) -> usize {
+        #[cfg(test)]
+        note_lookup();
+        self.0.count_base_units::<HeightMetric>(height)
+    }
+
+    /// Whether the layout at the index contains the raw unit of height
+    /// starting at `unit`.
+    fn contains_height_unit(&self, index: usize, unit: usize) -> bool {
+        let top = self.height_of_index(index).as_raw_frac();
+        top <= unit && self.height_at(index).map_or(0, Height::as_raw_frac) > unit - top
+    }
+
+    /// The total of the counts of all layouts.
+    pub fn count(&self) -> usize {
+        self.0.measure::<CountMetric>()
+    }
+
+    /// The sum of the counts of the layouts before the given index.
+    pub fn count_of_index(&self, index: usize) -> usize {
+        self.0.count::<CountMetric>(index)
+    }
+
+    /// The index of the layout containing the given count.
     ///
-    /// TODO: is there a simpler way to state that? It seems more complicated
-    /// than it should be.
-    pub fn index_of_height(&self, height: Height) -> usize {
-        self.0
-            .count_base_units::<HeightMetric>(height.as_raw_frac())
+    /// The semantics are the same as `index_of_height`.
+    pub fn index_of_count(&self, count: usize) -> usize {
+        let ix = self.0.count_base_units::<CountMetric>(count);
+        self.first_at(ix, |i| self.count_of_index(i) == count)
+    }
+
+    /// Fix up the result of `count_base_units`, given a predicate for
+    /// whether a layout starts at the measure being looked up.
+    ///
+    /// Within a leaf, `to_base_units` stops at the first layout starting at
+    /// the measure. But when the measure is exactly at the end of a node,
+    /// the descent skips that node, and with it any zero-measure layouts at
+    /// its end, so step back over those.
+    fn first_at(&self, ix: usize, starts_at: impl Fn(usize) -> bool) -> usize {
+        let mut ix = ix.min(self.len());
+        while ix > 0 && starts_at(ix - 1) {
+            ix -= 1;
+        }
+        ix
     }
 
     fn push_subseq(&self, b: &mut TreeBuilder<LayoutInfo>, iv: Interval) {
@@ -260,12 +848,23 @@ impl LayoutRope {
     }
 }
 
+/// Access to the layout at an index; use `get` or `height_at` for the height.
+///
+/// There is no `IndexMut`, as the layouts are shared; use `set` instead.
+impl std::ops::Index<usize> for LayoutRope {
+    type Output = Layout;
+
+    fn index(&self, index: usize) -> &Layout {
+        self.check_index(index, "index");
+        self.get(index).unwrap().1
+    }
+}
+
 impl LayoutRopeBuilder {
     pub fn new() -> LayoutRopeBuilder {
         LayoutRopeBuilder(TreeBuilder::new())
     }
 
-    #[allow(unused)]
     pub fn push_rope_slice(&mut self, other: &LayoutRope, range: Range<usize>) {
         // TODO: use push_subseq method on TreeBuilder when that lands.
         self.0.push(other.0.subseq(Interval::from(range)))
@@ -312,6 +911,25 @@ impl<'a> Iterator for ChunkIter<'a> {
     }
 }
 
+/// An iterator over chunks of layouts, with the height at the top of each.
+pub struct TopsChunkIter<'a> {
+    inner: ChunkIter<'a>,
+    top: Height,
+}
+
+impl<'a> Iterator for TopsChunkIter<'a> {
+    type Item = (Height, &'a [(Height, Arc<Layout>)]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.inner.next()?;
+        let top = self.top;
+        for (height, _) in chunk {
+            self.top += *height;
+        }
+        Some((top, chunk))
+    }
+}
+
 impl Metric<LayoutInfo> for BaseMetric {
     fn measure(_: &LayoutInfo, len: usize) -> usize {
         len
@@ -355,6 +973,10 @@ impl Metric<LayoutInfo> for HeightMetric {
         height.as_raw_frac()
     }
 
+    // This is a linear scan. A binary search would need prefix sums of the
+    // heights, which would have to be recomputed whenever a leaf is edited,
+    // and leaves hold at most `MAX_LEAF` layouts, so the scan is only a few
+    // dozen additions; the descent through the tree dominates.
     fn to_base_units(l: &LayoutLeaf, in_measured_units: usize) -> usize {
         let mut m1 = in_measured_units;
         let mut m2 = 0;
@@ -389,3 +1011,523 @@ impl Metric<LayoutInfo> for HeightMetric {
         true
     }
 }
+
+impl Metric<LayoutInfo> for CountMetric {
+    fn measure(info: &LayoutInfo, _len: usize) -> usize {
+        info.count
+    }
+
+    fn from_base_units(l: &LayoutLeaf, in_base_units: usize) -> usize {
+        l.data[..in_base_units]
+            .iter()
+            .map(|(_, layout)| layout.count)
+            .sum()
+    }
+
+    fn to_base_units(l: &LayoutLeaf, in_measured_units: usize) -> usize {
+        let mut m1 = in_measured_units;
+        let mut m2 = 0;
+        for (_, layout) in &l.data {
+            if m1 == 0 || m1 < layout.count {
+                break;
+            }
+            m1 -= layout.count;
+            m2 += 1;
+        }
+        m2
+    }
+
+    fn is_boundary(_l: &LayoutLeaf, _offset: usize) -> bool {
+        true
+    }
+
+    fn prev(_l: &LayoutLeaf, offset: usize) -> Option<usize> {
+        Some(offset - 1)
+    }
+
+    fn next(_l: &LayoutLeaf, offset: usize) -> Option<usize> {
+        Some(offset + 1)
+    }
+
+    fn can_fragment() -> bool {
+        // Zero counts are allowed, as with heights.
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use xi_rope::tree::Metric;
+
+    use super::{
+        Height, HeightMetric, Layout, LayoutLeaf, LayoutRope, LayoutRopeBuilder, MAX_LEAF,
+    };
+    use crate::test_util::{make_layout, make_layouts};
+
+    fn make_rope(n: usize) -> LayoutRope {
+        let texts: Vec<String> = (0..n).map(|i| i.to_string()).collect();
+        make_layouts(texts.iter().map(|s| s.as_str()))
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn height_arithmetic() {
+        let a = Height::from_f64(10.5);
+        let b = Height::from_f64(2.25);
+        assert_eq!((a - b).to_f64(), 8.25);
+        assert_eq!(a - b + b, a);
+        assert_eq!(a.saturating_sub(b), a - b);
+        assert_eq!(b.saturating_sub(a), Height::ZERO);
+        assert_eq!(Height::ZERO.saturating_sub(a), Height::ZERO);
+        let mut c = a;
+        c -= b;
+        assert_eq!(c, a - b);
+        assert!(b < a);
+        assert!(Height::ZERO <= b);
+        assert_eq!(a.max(b), a);
+    }
+
+    #[test]
+    fn height_from_f64() {
+        assert_eq!(
+            Height::from_f64(1.0).as_raw_frac(),
+            1 << Height::HEIGHT_FRAC_BITS
+        );
+        assert_eq!(Height::from_f64(0.0), Height::ZERO);
+        assert_eq!(Height::from_f64(-0.0), Height::ZERO);
+        assert_eq!(Height::from_f64(-3.5), Height::ZERO);
+        assert_eq!(Height::from_f64(std::f64::NAN), Height::ZERO);
+        assert_eq!(Height::from_f64(std::f64::NEG_INFINITY), Height::ZERO);
+        let max = Height::from_raw_frac(usize::MAX);
+        assert_eq!(Height::from_f64(std::f64::INFINITY), max);
+        assert_eq!(Height::from_f64(1e300), max);
+    }
+
+    #[test]
+    fn get_arc_shares_layouts() {
+        let rope = make_rope(40);
+        let clone = rope.clone();
+        for i in 0..40 {
+            let (h1, a1) = rope.get_arc(i).unwrap();
+            let (h2, a2) = clone.get_arc(i).unwrap();
+            assert_eq!(h1, h2);
+            assert!(Arc::ptr_eq(&a1, &a2));
+        }
+        assert!(rope.get_arc(40).is_none());
+    }
+
+    #[test]
+    fn index_and_iter() {
+        let rope = make_rope(50);
+        let mut total = Height::ZERO;
+        for (i, layout) in rope.layouts().enumerate() {
+            assert!(std::ptr::eq(layout, &rope[i]));
+            assert!(std::ptr::eq(layout, rope.get(i).unwrap().1));
+            assert_eq!(rope.height_at(i), Some(layout.height()));
+            total += layout.height();
+        }
+        assert_eq!(rope.layouts().count(), 50);
+        assert_eq!(total, rope.height());
+        assert_eq!(rope.height_at(50), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn index_past_end() {
+        let rope = make_rope(3);
+        let _ = &rope[3];
+    }
+
+    #[test]
+    fn count_prefix_sums() {
+        let mut builder = LayoutRopeBuilder::new();
+        // Counts 0, 1, 2, ..., with the zero count to exercise edge cases.
+        for i in 0..100 {
+            builder.push_layout(make_layout("x").with_count(i));
+        }
+        let rope = builder.build();
+        assert_eq!(rope.count(), 99 * 100 / 2);
+        for i in 0..=100 {
+            assert_eq!(rope.count_of_index(i), i * i.saturating_sub(1) / 2);
+        }
+        assert_eq!(rope.index_of_count(0), 0);
+        assert_eq!(rope.index_of_count(1), 2);
+        assert_eq!(rope.index_of_count(2), 2);
+        assert_eq!(rope.index_of_count(3), 3);
+        assert_eq!(rope.index_of_count(45), 10);
+        assert_eq!(rope.index_of_count(46), 10);
+    }
+
+    fn make_counted_rope(counts: impl IntoIterator<Item = usize>) -> LayoutRope {
+        let mut builder = LayoutRopeBuilder::new();
+        for count in counts {
+            builder.push_layout(make_layout("x").with_count(count));
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn collect() {
+        let layouts = make_layouts(std::iter::repeat("x").take(10_000));
+        let height = layouts[0].height();
+        let rope: LayoutRope = layouts.into_iter().collect();
+        assert_eq!(rope.len(), 10_000);
+        assert_eq!(
+            rope.height(),
+            Height::from_raw_frac(height.as_raw_frac() * 10_000)
+        );
+        assert_eq!(
+            rope.height_of_index(5_000),
+            Height::from_raw_frac(height.as_raw_frac() * 5_000)
+        );
+
+        let empty: LayoutRope = Vec::new().into_iter().collect();
+        assert_eq!(empty.len(), 0);
+    }
+
+    fn counts(rope: &LayoutRope) -> Vec<usize> {
+        rope.layouts().map(|layout| layout.count()).collect()
+    }
+
+    #[test]
+    fn edit_longer() {
+        let mut rope = make_counted_rope(0..10);
+        rope.edit(3..5, make_counted_rope(vec![100, 101, 102, 103]));
+        assert_eq!(
+            counts(&rope),
+            vec![0, 1, 2, 100, 101, 102, 103, 5, 6, 7, 8, 9]
+        );
+    }
+
+    #[test]
+    fn edit_shorter() {
+        let mut rope = make_counted_rope(0..40);
+        rope.edit(1..39, make_counted_rope(vec![100]));
+        assert_eq!(counts(&rope), vec![0, 100, 39]);
+    }
+
+    #[test]
+    fn edit_empty() {
+        let mut rope = make_counted_rope(0..5);
+        rope.edit(1..3, LayoutRope::default());
+        assert_eq!(counts(&rope), vec![0, 3, 4]);
+        rope.edit(3..3, make_counted_rope(vec![100, 101]));
+        assert_eq!(counts(&rope), vec![0, 3, 4, 100, 101]);
+        rope.edit(0..5, LayoutRope::default());
+        assert_eq!(rope.len(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn edit_out_of_range() {
+        let mut rope = make_counted_rope(0..5);
+        rope.edit(3..6, LayoutRope::default());
+    }
+
+    #[test]
+    fn chunk_tops() {
+        let heights: Vec<usize> = (0..200).map(|i| i % 7).collect();
+        let rope = make_height_rope(&heights);
+        for &(start, end) in &[(0, 200), (37, 150), (100, 100), (199, 200)] {
+            let mut index = start;
+            let mut n_chunks = 0;
+            for (top, chunk) in rope.iter_chunks_with_tops(start..end) {
+                assert_eq!(top, rope.height_of_index(index));
+                index += chunk.len();
+                n_chunks += 1;
+            }
+            assert_eq!(index, end);
+            if end - start > MAX_LEAF {
+                assert!(n_chunks > 1);
+            }
+        }
+
+        let tops: Vec<Height> = rope.iter_with_tops().map(|(top, _)| top).collect();
+        let expected: Vec<Height> = (0..200).map(|i| rope.height_of_index(i)).collect();
+        assert_eq!(tops, expected);
+    }
+
+    #[test]
+    fn pixel_heights() {
+        // Heights with fractional parts, in units of 1/256 px.
+        let heights: Vec<usize> = (0..300).map(|i| (i * 37) % 1000).collect();
+        let rope = make_height_rope(&heights);
+        let mut top = 0.0;
+        for (i, &h) in heights.iter().enumerate() {
+            assert_eq!(rope.pixel_height_of_index(i), top);
+            top += Height::from_raw_frac(h).to_f64();
+        }
+        assert_eq!(rope.pixel_height_of_index(heights.len()), top);
+        assert_eq!(rope.pixel_height(), top);
+        assert_eq!(LayoutRope::default().pixel_height(), 0.0);
+    }
+
+    #[test]
+    fn height_to_base_units() {
+        // Pseudo-random leaves, with plenty of zero heights.
+        let mut seed = 1u64;
+        let mut rand = |n: u64| {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((seed >> 33) % n) as usize
+        };
+        for _ in 0..100 {
+            let len = 1 + rand(MAX_LEAF as u64);
+            let heights: Vec<usize> = (0..len).map(|_| rand(4)).collect();
+            let leaf = LayoutLeaf {
+                data: heights
+                    .iter()
+                    .map(|&h| {
+                        let height = Height::from_raw_frac(h);
+                        (height, Arc::new(Layout::placeholder(height)))
+                    })
+                    .collect(),
+            };
+            let total: usize = heights.iter().sum();
+            for m in 0..=total + 1 {
+                assert_eq!(
+                    HeightMetric::to_base_units(&leaf, m),
+                    reference_index_of_height(&heights, m),
+                    "{} in {:?}",
+                    m,
+                    heights
+                );
+            }
+            for i in 0..=len {
+                let m = HeightMetric::from_base_units(&leaf, i);
+                assert_eq!(m, heights[..i].iter().sum::<usize>());
+            }
+        }
+    }
+
+    /// A rope of placeholders with the given raw heights.
+    fn make_height_rope(heights: &[usize]) -> LayoutRope {
+        heights
+            .iter()
+            .map(|&h| Layout::placeholder(Height::from_raw_frac(h)))
+            .collect()
+    }
+
+    /// `index_of_height`, straight from the spec, for raw heights.
+    fn reference_index_of_height(heights: &[usize], height: usize) -> usize {
+        let mut top = 0;
+        for (i, &h) in heights.iter().enumerate() {
+            if top == height || top + h > height {
+                return i;
+            }
+            top += h;
+        }
+        heights.len()
+    }
+
+    fn check_index_of_height(heights: &[usize]) {
+        let rope = make_height_rope(heights);
+        let total: usize = heights.iter().sum();
+        for height in 0..=total + 2 {
+            assert_eq!(
+                rope.index_of_height(Height::from_raw_frac(height)),
+                reference_index_of_height(heights, height),
+                "height {} of {:?}",
+                height,
+                heights
+            );
+        }
+    }
+
+    #[test]
+    fn index_of_height() {
+        let rope = make_height_rope(&[10, 20, 0, 30]);
+        let index_of = |h| rope.index_of_height(Height::from_raw_frac(h));
+        assert_eq!(index_of(0), 0);
+        assert_eq!(index_of(9), 0);
+        assert_eq!(index_of(10), 1);
+        // On the boundary before a zero-height layout.
+        assert_eq!(index_of(30), 2);
+        assert_eq!(index_of(31), 3);
+        // The total height, not ending on a zero-height layout.
+        assert_eq!(index_of(60), 4);
+        assert_eq!(index_of(100), 4);
+        check_index_of_height(&[10, 20, 0, 30]);
+    }
+
+    #[test]
+    fn index_of_height_trailing_zero() {
+        let rope = make_height_rope(&[10, 20, 0]);
+        assert_eq!(rope.index_of_height(Height::from_raw_frac(30)), 2);
+        assert_eq!(rope.index_of_height(Height::from_raw_frac(31)), 3);
+        check_index_of_height(&[10, 20, 0]);
+        check_index_of_height(&[10, 0, 0]);
+        check_index_of_height(&[0]);
+        check_index_of_height(&[]);
+    }
+
+    #[test]
+    fn index_of_height_consecutive_zeros() {
+        let rope = make_height_rope(&[0, 0, 10, 0, 0, 10]);
+        let index_of = |h| rope.index_of_height(Height::from_raw_frac(h));
+        assert_eq!(index_of(0), 0);
+        assert_eq!(index_of(5), 2);
+        assert_eq!(index_of(10), 3);
+        assert_eq!(index_of(20), 6);
+        check_index_of_height(&[0, 0, 10, 0, 0, 10]);
+    }
+
+    /// `shown_index_of_height`, straight from the spec, for raw heights.
+    fn reference_shown_index_of_height(heights: &[usize], height: usize) -> Option<usize> {
+        let mut top = 0;
+        let mut last = None;
+        for (i, &h) in heights.iter().enumerate() {
+            if h > 0 {
+                if top + h > height {
+                    return Some(i);
+                }
+                last = Some(i);
+            }
+            top += h;
+        }
+        last
+    }
+
+    fn check_shown_index_of_height(heights: &[usize]) {
+        let rope = make_height_rope(heights);
+        let total: usize = heights.iter().sum();
+        for height in 0..=total + 2 {
+            assert_eq!(
+                rope.shown_index_of_height(Height::from_raw_frac(height)),
+                reference_shown_index_of_height(heights, height),
+                "height {} of {:?}",
+                height,
+                heights
+            );
+        }
+    }
+
+    #[test]
+    fn shown_index_of_height() {
+        let rope = make_height_rope(&[10, 20, 0, 0, 30, 0]);
+        let index_of = |h| rope.shown_index_of_height(Height::from_raw_frac(h));
+        assert_eq!(index_of(9), Some(0));
+        assert_eq!(index_of(10), Some(1));
+        // Zero-height layouts are skipped, at the boundary and at the end.
+        assert_eq!(index_of(30), Some(4));
+        assert_eq!(index_of(59), Some(4));
+        assert_eq!(index_of(60), Some(4));
+        check_shown_index_of_height(&[10, 20, 0, 0, 30, 0]);
+        check_shown_index_of_height(&[0, 1, 0, 1, 1, 0]);
+        check_shown_index_of_height(&[0, 0]);
+        check_shown_index_of_height(&[]);
+    }
+
+    #[test]
+    fn index_of_height_across_leaves() {
+        // Zero-height layouts in every position relative to leaf
+        // boundaries, including runs longer than a leaf.
+        let heights: Vec<usize> = (0..200).map(|i| if i % 3 == 2 { 0 } else { 2 }).collect();
+        check_index_of_height(&heights);
+        let mut heights = vec![1; 40];
+        heights.extend(vec![0; 100]);
+        heights.extend(vec![1; 40]);
+        heights.extend(vec![0; 70]);
+        check_index_of_height(&heights);
+        check_shown_index_of_height(&heights);
+        let heights: Vec<usize> = (0..200).map(|i| if i % 3 == 2 { 0 } else { 2 }).collect();
+        check_shown_index_of_height(&heights);
+    }
+
+    #[test]
+    fn from_heights() {
+        // The given heights win over the layouts' own.
+        let layout = make_layout("x");
+        assert_ne!(layout.height(), Height::from_f64(5.0));
+        let items = vec![
+            (5.0, layout),
+            (0.0, Layout::placeholder(Height::from_f64(20.0))),
+            (12.5, Layout::placeholder(Height::ZERO)),
+        ];
+        let rope = LayoutRope::from_heights(items);
+        assert_eq!(rope.len(), 3);
+        assert_eq!(rope.height(), Height::from_f64(17.5));
+        assert_eq!(rope.height_at(1), Some(Height::ZERO));
+        assert_eq!(rope.index_of_height(Height::from_f64(4.0)), 0);
+        // The zero-height layout is first at its top.
+        assert_eq!(rope.index_of_height(Height::from_f64(5.0)), 1);
+        assert_eq!(rope.index_of_height(Height::from_f64(10.0)), 2);
+        assert_eq!(rope.index_of_height(Height::from_f64(17.5)), 3);
+
+        // Enough for several leaves.
+        let rope =
+            LayoutRope::from_heights((0..100).map(|_| (2.0, Layout::placeholder(Height::ZERO))));
+        assert_eq!(rope.height(), Height::from_f64(200.0));
+        assert_eq!(rope.index_of_height(Height::from_f64(99.0)), 49);
+    }
+
+    #[test]
+    fn replace_placeholder() {
+        let estimate = Height::from_f64(20.0);
+        let rope: LayoutRope = (0..100).map(|_| Layout::placeholder(estimate)).collect();
+        assert_eq!(rope.height(), Height::from_f64(2000.0));
+        assert_eq!(rope.height_of_index(50), Height::from_f64(1000.0));
+        assert_eq!(rope.index_of_height(Height::from_f64(1010.0)), 50);
+
+        let mut rope = rope;
+        let layout = make_layout("x");
+        let actual = layout.height();
+        assert_ne!(actual, estimate);
+        rope.set(10, layout);
+        assert!(!rope[10].is_placeholder());
+        assert!(rope[11].is_placeholder());
+        assert_eq!(rope.height_at(10), Some(actual));
+        assert_eq!(rope.height(), Height::from_f64(1980.0) + actual);
+        assert_eq!(rope.height_of_index(50), Height::from_f64(980.0) + actual);
+    }
+
+    #[test]
+    fn line_spacing() {
+        let natural = make_layout("x").height();
+        let spaced = make_layout("x").with_line_spacing(1.5);
+        assert_eq!(spaced.height(), Height::from_f64(natural.to_f64() * 1.5));
+        let estimate = Height::from_f64(20.0);
+        let placeholder = Layout::placeholder(estimate).with_line_spacing(1.5);
+        assert_eq!(placeholder.height(), estimate);
+    }
+
+    #[test]
+    fn mutate_at_boundaries() {
+        let mut rope = make_rope(3);
+        rope.set(2, make_layout("x"));
+        assert_eq!(rope.len(), 3);
+        rope.insert(3, make_layout("y"));
+        assert_eq!(rope.len(), 4);
+        rope.insert(0, make_layout("z"));
+        assert_eq!(rope.len(), 5);
+        rope.remove(4);
+        assert_eq!(rope.len(), 4);
+        rope.remove(0);
+        assert_eq!(rope.len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn set_one_past_end() {
+        let mut rope = make_rope(3);
+        rope.set(3, make_layout("x"));
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn insert_past_end() {
+        let mut rope = make_rope(3);
+        rope.insert(4, make_layout("x"));
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn remove_one_past_end() {
+        let mut rope = make_rope(3);
+        rope.remove(3);
+    }
+}
@@ -0,0 +1,13 @@
+//! An editor widget for druid, built on xi-text-core.
+
+mod command;
+mod completion;
+mod decoration;
+pub mod edit_widget;
+mod highlight;
+mod key_bindings;
+mod layout_rope;
+mod palette;
+#[cfg(test)]
+mod test_util;
+mod util;
@@ -1,11 +1,6 @@
-mod edit_widget;
-mod key_bindings;
-mod layout_rope;
-mod util;
-
 use druid::{AppLauncher, Widget, WindowDesc};
 
-use edit_widget::{EditWidget, XiState};
+use xi2::edit_widget::{EditWidget, XiState};
 
 pub fn main() {
     let main_window = WindowDesc::new(build_root_widget);
@@ -0,0 +1,201 @@
+//! A command palette, for running commands by fuzzy name.
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use druid::Data;
+
+/// The bonus for a match at the start of a word.
+const WORD_START_BONUS: usize = 10;
+
+/// The bonus for a match right after the previous one.
+const CONSECUTIVE_BONUS: usize = 5;
+
+/// The state of an open command palette.
+#[derive(Clone, Data)]
+pub struct CommandPalette {
+    /// All the command names.
+    names: Arc<Vec<String>>,
+    query: String,
+    /// The names matching the query, best first.
+    matches: Arc<Vec<String>>,
+    /// The index of the selected match.
+    selected: usize,
+}
+
+impl CommandPalette {
+    /// A palette for the given command names, with an empty query.
+    pub fn new(names: impl IntoIterator<Item = impl Into<String>>) -> CommandPalette {
+        let names: Vec<String> = names.into_iter().map(Into::into).collect();
+        let mut palette = CommandPalette {
+            names: Arc::new(names),
+            query: String::new(),
+            matches: Default::default(),
+            selected: 0,
+        };
+        palette.update_matches();
+        palette
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Set the query, selecting the best match.
+    pub fn set_query(&mut self, query: impl Into<String>) {
+        self.query = query.into();
+        self.update_matches();
+    }
+
+    /// The names matching the query, best first.
+    pub fn matches(&self) -> &[String] {
+        &self.matches
+    }
+
+    /// The selected command name, if anything matches.
+    pub fn selected(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(String::as_str)
+    }
+
+    /// Select the next match, if there is one.
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    /// Select the previous match, if there is one.
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    fn update_matches(&mut self) {
+        let matches = rank(&self.query, self.names.iter().map(String::as_str));
+        self.matches = Arc::new(matches.into_iter().map(String::from).collect());
+        self.selected = 0;
+    }
+}
+
+/// Score a candidate against a fuzzy query.
+///
+/// The query matches if its characters appear in the candidate in order,
+/// ignoring case. Matches at the start of a word, and runs of consecutive
+/// matches, score higher. Returns `None` if the query doesn't match.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<usize> {
+    let mut query = query.chars().peekable();
+    let mut score = 0;
+    let mut prev = None;
+    let mut consecutive = false;
+    for c in candidate.chars() {
+        let matched = match query.peek() {
+            Some(&q) => c.to_lowercase().eq(q.to_lowercase()),
+            None => break,
+        };
+        if matched {
+            query.next();
+            score += 1;
+            if consecutive {
+                score += CONSECUTIVE_BONUS;
+            }
+            if prev.is_none_or(is_word_separator) {
+                score += WORD_START_BONUS;
+            }
+        }
+        consecutive = matched;
+        prev = Some(c);
+    }
+    match query.peek() {
+        Some(_) => None,
+        None => Some(score),
+    }
+}
+
+/// The candidates matching a fuzzy query, best first.
+///
+/// Equal scores are ranked shortest first, then alphabetically.
+pub fn rank<'a>(query: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut scored: Vec<_> = candidates
+        .filter_map(|c| fuzzy_score(query, c).map(|score| (score, c)))
+        .collect();
+    scored.sort_by_key(|&(score, c)| (Reverse(score), c.len(), c));
+    scored.into_iter().map(|(_, c)| c).collect()
+}
+
+fn is_word_separator(c: char) -> bool {
+    c == '-' || c == '_' || c.is_whitespace()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fuzzy_score, rank, CommandPalette};
+
+    const NAMES: &[&str] = &[
+        "move-down",
+        "move-document-end",
+        "move-word-left",
+        "backspace",
+        "add-caret-below",
+    ];
+
+    #[test]
+    fn score() {
+        assert_eq!(fuzzy_score("", "backspace"), Some(0));
+        assert_eq!(fuzzy_score("xyz", "backspace"), None);
+        // Order matters.
+        assert_eq!(fuzzy_score("kb", "backspace"), None);
+        // Case is ignored.
+        assert_eq!(
+            fuzzy_score("BS", "backspace"),
+            fuzzy_score("bs", "backspace")
+        );
+        // Word starts and runs score higher than scattered matches.
+        assert!(fuzzy_score("md", "move-down") > fuzzy_score("md", "move-word-left"));
+        assert!(fuzzy_score("back", "backspace") > fuzzy_score("bksp", "backspace"));
+    }
+
+    #[test]
+    fn ranking() {
+        let names = NAMES.iter().copied();
+        assert_eq!(
+            rank("md", names.clone()),
+            &["move-down", "move-document-end", "move-word-left"]
+        );
+        assert_eq!(rank("back", names.clone()), &["backspace"]);
+        assert_eq!(
+            rank("ce", names.clone()),
+            &["add-caret-below", "backspace", "move-document-end"]
+        );
+        assert_eq!(rank("left", names.clone()), &["move-word-left"]);
+        assert!(rank("zzz", names.clone()).is_empty());
+        // An empty query matches everything, sorted by length.
+        assert_eq!(
+            rank("", names),
+            &[
+                "backspace",
+                "move-down",
+                "move-word-left",
+                "add-caret-below",
+                "move-document-end",
+            ]
+        );
+    }
+
+    #[test]
+    fn palette_selection() {
+        let mut palette = CommandPalette::new(NAMES.iter().copied());
+        assert_eq!(palette.matches().len(), NAMES.len());
+        palette.set_query("md");
+        assert_eq!(palette.selected(), Some("move-down"));
+        palette.select_prev();
+        assert_eq!(palette.selected(), Some("move-down"));
+        palette.select_next();
+        palette.select_next();
+        palette.select_next();
+        assert_eq!(palette.selected(), Some("move-word-left"));
+        // Changing the query selects the best match again.
+        palette.set_query("mde");
+        assert_eq!(palette.selected(), Some("move-document-end"));
+        palette.set_query("zzz");
+        assert_eq!(palette.selected(), None);
+    }
+}
@@ -0,0 +1,44 @@
+//! Helpers for tests.
+
+use druid::piet::{Device, FontFamily, PietText, RenderContext, Text, TextLayoutBuilder};
+
+use crate::layout_rope::Layout;
+
+/// Run a function with a text factory.
+///
+/// This uses an offscreen bitmap, so it doesn't need a window.
+pub fn with_text<R>(f: impl FnOnce(&mut PietText) -> R) -> R {
+    let mut device = Device::new().unwrap();
+    let mut target = device.bitmap_target(1, 1, 1.0).unwrap();
+    let mut rc = target.render_context();
+    let result = f(rc.text());
+    rc.finish().unwrap();
+    result
+}
+
+/// Make layouts of the given texts and font sizes.
+pub fn make_sized_layouts<'a>(items: impl IntoIterator<Item = (&'a str, f64)>) -> Vec<Layout> {
+    with_text(|factory| {
+        items
+            .into_iter()
+            .map(|(text, size)| {
+                let piet_layout = factory
+                    .new_text_layout(text)
+                    .font(FontFamily::MONOSPACE, size)
+                    .build()
+                    .unwrap();
+                Layout::new(piet_layout)
+            })
+            .collect()
+    })
+}
+
+/// Make layouts of the given texts, in the default font size.
+pub fn make_layouts<'a>(texts: impl IntoIterator<Item = &'a str>) -> Vec<Layout> {
+    make_sized_layouts(texts.into_iter().map(|text| (text, 14.0)))
+}
+
+/// Make a single layout.
+pub fn make_layout(text: &str) -> Layout {
+    make_layouts(Some(text)).pop().unwrap()
+}
@@ -1,5 +1,12 @@
+use std::io::{self, Read};
+
 use xi_rope::compare::RopeScanner;
-use xi_rope::Rope;
+use xi_rope::delta::DeltaElement;
+use xi_rope::tree::TreeBuilder;
+use xi_rope::{Rope, RopeDelta, RopeInfo};
+
+/// The size of the buffer `read_rope` reads into.
+const READ_BUF_SIZE: usize = 64 * 1024;
 
 // TODO: this functionality should be moved to xi-rope.
 pub fn rope_eq(a: &Rope, b: &Rope) -> bool {
@@ -9,3 +16,189 @@ pub fn rope_eq(a: &Rope, b: &Rope) -> bool {
     }
     RopeScanner::new(a, b).find_ne_char(0, 0, None) == len
 }
+
+/// The range that differs between two ropes, as its start, its end in
+/// `old`, and its end in `new`.
+///
+/// The common prefix and suffix don't overlap, so the range is empty in
+/// both if the ropes are equal.
+pub fn changed_range(old: &Rope, new: &Rope) -> (usize, usize, usize) {
+    let mut scanner = RopeScanner::new(old, new);
+    let start = scanner.find_ne_char(0, 0, None);
+    let end = scanner.find_ne_char_back(old.len(), new.len(), None);
+    let end = end.min(old.len() - start).min(new.len() - start);
+    (start, old.len() - end, new.len() - end)
+}
+
+/// The paragraphs of `new` that need to be laid out again after `delta`
+/// was applied to `old`, in increasing order.
+///
+/// A paragraph is unchanged if it was copied whole from a paragraph of
+/// `old`, so its layout can be reused. Paragraphs that were deleted
+/// whole don't appear, since there is nothing to lay out.
+pub fn changed_paragraphs(old: &Rope, delta: &RopeDelta, new: &Rope) -> Vec<usize> {
+    let n_paras = new.line_of_offset(new.len()) + 1;
+    let mut changed = Vec::new();
+    // The first paragraph not known to be unchanged.
+    let mut next = 0;
+    let mut new_pos = 0;
+    for el in &delta.els {
+        let (old_start, old_end) = match el {
+            DeltaElement::Copy(start, end) => (*start, *end),
+            DeltaElement::Insert(node) => {
+                new_pos += node.len();
+                continue;
+            }
+        };
+        if old_start == old_end {
+            continue;
+        }
+        let new_start = new_pos;
+        let new_end = new_pos + old_end - old_start;
+        new_pos = new_end;
+        // The unchanged paragraphs are those within the copied text, that
+        // start and end at paragraph boundaries in both texts.
+        let mut first = new.line_of_offset(new_start);
+        if !(is_line_start(new, new_start) && is_line_start(old, old_start)) {
+            first += 1;
+        }
+        let mut end = new.line_of_offset(new_end);
+        if new_end == new.len() && old_end == old.len() {
+            end += 1;
+        }
+        if first < end {
+            changed.extend(next..first);
+            next = end;
+        }
+    }
+    changed.extend(next..n_paras);
+    changed
+}
+
+fn is_line_start(text: &Rope, offset: usize) -> bool {
+    offset == 0 || text.byte_at(offset - 1) == b'\n'
+}
+
+/// Read UTF-8 text into a rope, a buffer at a time.
+///
+/// A character split between reads is carried over to the next one.
+/// Invalid UTF-8, including a character cut off at the end of the input,
+/// is an error of kind `InvalidData`.
+pub fn read_rope(mut r: impl Read) -> io::Result<Rope> {
+    let mut builder = TreeBuilder::<RopeInfo>::new();
+    let mut buf = vec![0; READ_BUF_SIZE];
+    // The bytes at the start of `buf` left over from the last read.
+    let mut pending = 0;
+    // The offset of `buf` in the input, for error messages.
+    let mut pos = 0;
+    loop {
+        let n = match r.read(&mut buf[pending..]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            if pending > 0 {
+                return Err(invalid_utf8(pos));
+            }
+            return Ok(builder.build());
+        }
+        let filled = pending + n;
+        let valid = match std::str::from_utf8(&buf[..filled]) {
+            Ok(s) => s.len(),
+            // The error is only in the last few bytes, and may be completed
+            // by the next read.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(invalid_utf8(pos + e.valid_up_to())),
+        };
+        builder.push_str(std::str::from_utf8(&buf[..valid]).unwrap());
+        buf.copy_within(valid..filled, 0);
+        pending = filled - valid;
+        pos += valid;
+    }
+}
+
+fn invalid_utf8(offset: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid UTF-8 at byte {}", offset),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use xi_rope::{DeltaBuilder, Interval, Rope};
+
+    use super::{changed_paragraphs, read_rope};
+
+    /// A reader that returns a byte at a time.
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl<'a> Read for ByteReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((&b, rest)) if !buf.is_empty() => {
+                    buf[0] = b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    /// Apply replacements, given in old offsets, and return the changed
+    /// paragraphs.
+    fn changed(old: &str, edits: &[(usize, usize, &str)]) -> Vec<usize> {
+        let old = Rope::from(old);
+        let mut builder = DeltaBuilder::new(old.len());
+        for &(start, end, s) in edits {
+            builder.replace(Interval::new(start, end), Rope::from(s));
+        }
+        let delta = builder.build();
+        let new = delta.apply(&old);
+        changed_paragraphs(&old, &delta, &new)
+    }
+
+    #[test]
+    fn multi_span_delta() {
+        // Edits in "two" and "four", with the others untouched.
+        assert_eq!(
+            changed("one\ntwo\nthree\nfour\n", &[(5, 6, "W"), (18, 18, "!")]),
+            &[1, 3]
+        );
+        // Splitting a paragraph changes both halves, but not the next one.
+        assert_eq!(changed("abcd\nef", &[(2, 2, "x\n")]), &[0, 1]);
+        // Joining paragraphs changes the result.
+        assert_eq!(changed("a\nb", &[(1, 2, "")]), &[0]);
+        // Deleting whole paragraphs leaves nothing to lay out.
+        assert!(changed("a\nb\nc", &[(2, 4, "")]).is_empty());
+        // Appending a line changes the trailing empty paragraph.
+        assert_eq!(changed("a\n", &[(2, 2, "b\n")]), &[1, 2]);
+    }
+
+    #[test]
+    fn read_in_byte_chunks() {
+        // Two-, three-, and four-byte characters, each split across reads.
+        let s = "a\u{e9}\n\u{65e5}\u{1F600}z";
+        let rope = read_rope(ByteReader(s.as_bytes())).unwrap();
+        assert_eq!(String::from(&rope), s);
+        assert!(read_rope(ByteReader(b"")).unwrap().is_empty());
+        // A large input fills the buffer more than once.
+        let long = "\u{e9}".repeat(100_000);
+        assert_eq!(String::from(&read_rope(long.as_bytes()).unwrap()), long);
+    }
+
+    #[test]
+    fn read_invalid_utf8() {
+        let err = read_rope(ByteReader(b"ab\xffcd")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("byte 2"));
+        // A character cut off by the end of the input.
+        let err = read_rope(&b"ab\xe6\x97"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("byte 2"));
+    }
+}
@@ -0,0 +1,67 @@
+//! Benchmarks of the edit and movement paths on large documents.
+//!
+//! Run with `cargo bench -p xi-text-core`. Each benchmark prints the mean
+//! time of one run; compare against a run on the base revision.
+
+use xi_rope::Rope;
+use xi_text_core::{EditOp, Measurement, Movement, SelRegion, Selection};
+
+mod harness;
+
+use harness::{bench, document};
+
+/// A measurement with one visual line per logical line, one px per byte,
+/// so the benchmarks measure the edit path rather than layout.
+struct FixedMeasurement;
+
+impl Measurement for FixedMeasurement {
+    fn n_visual_lines(&self, _line_num: usize) -> usize {
+        1
+    }
+
+    fn to_pos(&self, _line_num: usize, offset: usize) -> (f64, usize) {
+        (offset as f64, 0)
+    }
+
+    fn from_pos(&self, _line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+        horiz as usize
+    }
+}
+
+fn insert_char() {
+    let text = Rope::from(document(1_000_000));
+    let offset = text.offset_of_line(500_000);
+    let sel = Selection::new_simple(SelRegion::caret(offset));
+    let op = EditOp::Insert("x".into());
+    // Cloning a rope is cheap, so it's measured along with the edit.
+    bench("insert char, 1M lines", || {
+        let mut text = text.clone();
+        op.apply_with_delta(&mut text, &sel, &FixedMeasurement)
+    });
+}
+
+fn vertical_movement() {
+    let n = 10_000;
+    let text = Rope::from(document(n));
+    let top = Selection::new_simple(SelRegion::caret(0));
+    let bottom = Selection::new_simple(SelRegion::caret(text.offset_of_line(n - 1)));
+    bench("down through 10k lines", || {
+        let mut sel = top.clone();
+        for _ in 1..n {
+            sel = Movement::Down.update_selection(&sel, &text, &FixedMeasurement, false);
+        }
+        sel
+    });
+    bench("up through 10k lines", || {
+        let mut sel = bottom.clone();
+        for _ in 1..n {
+            sel = Movement::Up.update_selection(&sel, &text, &FixedMeasurement, false);
+        }
+        sel
+    });
+}
+
+fn main() {
+    insert_char();
+    vertical_movement();
+}
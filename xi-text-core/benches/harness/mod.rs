@@ -0,0 +1,39 @@
+//! A small timing loop shared by the benchmarks of the workspace.
+//!
+//! The benchmarks of other crates include this file with a `#[path]`
+//! attribute, rather than depending on a benchmark crate. Being in a
+//! directory, it isn't built as a benchmark of its own.
+//!
+//! To compare a change, run the benchmarks on the base revision and on the
+//! change, on the same machine, and give both sets of numbers in the
+//! commit message.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// How long each benchmark is run for, after warming up for as long.
+pub const MEASURE_TIME: Duration = Duration::from_secs(2);
+
+/// Run `f` for a while, and print the mean time of a run.
+pub fn bench<T>(name: &str, mut f: impl FnMut() -> T) {
+    let start = Instant::now();
+    let mut runs = 0;
+    while start.elapsed() < MEASURE_TIME {
+        black_box(f());
+        runs += 1;
+    }
+    let start = Instant::now();
+    for _ in 0..runs {
+        black_box(f());
+    }
+    println!("{:<28} {:>12.3?}", name, start.elapsed() / runs);
+}
+
+/// A document of `n` numbered lines.
+pub fn document(n: usize) -> String {
+    let mut s = String::new();
+    for i in 0..n {
+        s.push_str(&format!("line {:08}\n", i));
+    }
+    s
+}
@@ -0,0 +1,208 @@
+// Copyright 2020 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bracket matching.
+
+use xi_rope::{Cursor, Rope};
+
+/// The default limit on the number of bytes scanned for a match.
+pub const DEFAULT_MAX_SCAN: usize = 100_000;
+
+const PAIRS: &[(u8, u8)] = &[(b'(', b')'), (b'[', b']'), (b'{', b'}')];
+
+/// The brackets and quotes that can wrap a selection.
+const SURROUND_PAIRS: &[(&str, &str)] = &[
+    ("(", ")"),
+    ("[", "]"),
+    ("{", "}"),
+    ("\"", "\""),
+    ("'", "'"),
+    ("`", "`"),
+];
+
+/// The closing half of the bracket or quote pair that starts with `open`,
+/// for wrapping a selection (see `EditOp::Surround`).
+pub fn surround_pair(open: &str) -> Option<&'static str> {
+    SURROUND_PAIRS
+        .iter()
+        .find(|&&(o, _)| o == open)
+        .map(|&(_, close)| close)
+}
+
+/// Find the bracket adjacent to a caret and its match.
+///
+/// The bracket after the caret is preferred to the one before it. Returns
+/// the offsets of the bracket and of its match, or `None` if there is no
+/// bracket adjacent to the caret, or it is unmatched within `max_scan`
+/// bytes.
+///
+/// Brackets of other kinds are ignored when counting nesting, so in
+/// `( [ )` the parentheses match.
+pub fn find_matching_bracket(text: &Rope, caret: usize, max_scan: usize) -> Option<(usize, usize)> {
+    let candidates = [Some(caret), caret.checked_sub(1)];
+    for &offset in candidates.iter().flatten() {
+        if offset >= text.len() {
+            continue;
+        }
+        let b = text.byte_at(offset);
+        for &(open, close) in PAIRS {
+            if b == open {
+                if let Some(m) = scan_forward(text, offset, open, close, max_scan) {
+                    return Some((offset, m));
+                }
+            } else if b == close {
+                if let Some(m) = scan_backward(text, offset, open, close, max_scan) {
+                    return Some((offset, m));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn scan_forward(text: &Rope, offset: usize, open: u8, close: u8, max_scan: usize) -> Option<usize> {
+    let end = text.len().min(offset.saturating_add(max_scan));
+    let mut cursor = Cursor::new(text, offset + 1);
+    let (mut leaf, mut lower) = cursor.get_leaf()?;
+    // The offset of the start of the current leaf.
+    let mut base = offset + 1 - lower;
+    let mut depth = 0;
+    loop {
+        let upper = end.saturating_sub(base).min(leaf.len());
+        if lower < upper {
+            for (i, &b) in leaf.as_bytes()[lower..upper].iter().enumerate() {
+                if b == open {
+                    depth += 1;
+                } else if b == close {
+                    if depth == 0 {
+                        return Some(base + lower + i);
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        if base + leaf.len() >= end {
+            return None;
+        }
+        base += leaf.len();
+        leaf = cursor.next_leaf()?.0;
+        lower = 0;
+    }
+}
+
+fn scan_backward(
+    text: &Rope,
+    offset: usize,
+    open: u8,
+    close: u8,
+    max_scan: usize,
+) -> Option<usize> {
+    let start = offset.saturating_sub(max_scan);
+    let mut cursor = Cursor::new(text, offset);
+    let (mut leaf, mut upper) = cursor.get_leaf()?;
+    // The offset of the start of the current leaf.
+    let mut base = offset - upper;
+    let mut depth = 0;
+    loop {
+        let lower = start.saturating_sub(base);
+        if lower < upper {
+            for (i, &b) in leaf.as_bytes()[lower..upper].iter().enumerate().rev() {
+                if b == close {
+                    depth += 1;
+                } else if b == open {
+                    if depth == 0 {
+                        return Some(base + lower + i);
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        if base <= start {
+            return None;
+        }
+        leaf = cursor.prev_leaf()?.0;
+        upper = leaf.len();
+        base -= leaf.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_matching_bracket, surround_pair, DEFAULT_MAX_SCAN};
+    use xi_rope::Rope;
+
+    fn find(s: &str, caret: usize) -> Option<(usize, usize)> {
+        find_matching_bracket(&Rope::from(s), caret, DEFAULT_MAX_SCAN)
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(find("(a)", 0), Some((0, 2)));
+        assert_eq!(find("(a)", 1), Some((0, 2)));
+        assert_eq!(find("(a)", 2), Some((2, 0)));
+        assert_eq!(find("(a)", 3), Some((2, 0)));
+        assert_eq!(find("a b", 1), None);
+        assert_eq!(find("", 0), None);
+    }
+
+    #[test]
+    fn nested() {
+        let s = "f(a[1], {b: (c)})";
+        assert_eq!(find(s, 1), Some((1, 16)));
+        assert_eq!(find(s, 17), Some((16, 1)));
+        assert_eq!(find(s, 3), Some((3, 5)));
+        assert_eq!(find(s, 8), Some((8, 15)));
+        assert_eq!(find(s, 12), Some((12, 14)));
+        // Other kinds of brackets don't affect nesting.
+        assert_eq!(find("( [ )", 0), Some((0, 4)));
+    }
+
+    #[test]
+    fn unmatched() {
+        assert_eq!(find("((a)", 0), None);
+        assert_eq!(find("(a))", 4), None);
+        assert_eq!(find("{ ]", 0), None);
+    }
+
+    #[test]
+    fn bounded_scan() {
+        let s = format!("({})", "x".repeat(1000));
+        let text = Rope::from(s.as_str());
+        assert_eq!(find_matching_bracket(&text, 0, 2000), Some((0, 1001)));
+        assert_eq!(find_matching_bracket(&text, 0, 500), None);
+        assert_eq!(find_matching_bracket(&text, 1002, 2000), Some((1001, 0)));
+        assert_eq!(find_matching_bracket(&text, 1002, 500), None);
+    }
+
+    #[test]
+    fn large_document() {
+        // Big enough to span many leaves.
+        let inner = "(a[b]{c})\n".repeat(10_000);
+        let s = format!("({})", inner);
+        let text = Rope::from(s.as_str());
+        let len = s.len();
+        assert_eq!(find_matching_bracket(&text, 0, len), Some((0, len - 1)));
+        assert_eq!(find_matching_bracket(&text, len, len), Some((len - 1, 0)));
+        let mid = 1 + 10 * 5_000;
+        assert_eq!(find_matching_bracket(&text, mid, len), Some((mid, mid + 8)));
+    }
+
+    #[test]
+    fn surround_pairs() {
+        assert_eq!(surround_pair("("), Some(")"));
+        assert_eq!(surround_pair("\""), Some("\""));
+        assert_eq!(surround_pair(")"), None);
+        assert_eq!(surround_pair("ab"), None);
+    }
+}
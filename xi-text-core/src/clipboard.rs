@@ -0,0 +1,102 @@
+// Copyright 2020 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The text copied or cut from a selection.
+
+use std::ops::Range;
+
+use xi_rope::Rope;
+
+use crate::Selection;
+
+/// The ranges of text that copying a selection takes, in order.
+///
+/// A non-empty region takes its text. A caret takes its whole logical
+/// line, including the line ending, as in many editors. Overlapping ranges,
+/// as from carets on the same line, are merged.
+pub fn copy_ranges(text: &Rope, sel: &Selection) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for region in sel {
+        let range = if region.is_caret() {
+            let line = text.line_of_offset(region.end);
+            text.offset_of_line(line)..text.offset_of_line(line + 1)
+        } else {
+            region.min()..region.max()
+        };
+        if range.is_empty() {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => ranges.push(range),
+        }
+    }
+    ranges
+}
+
+/// The text that copying a selection puts on the clipboard.
+///
+/// The text of each range (see `copy_ranges`) is on its own line, so the
+/// ranges are separated by a newline unless one already ends with one.
+pub fn copy_text(text: &Rope, sel: &Selection) -> String {
+    let mut result = String::new();
+    for range in copy_ranges(text, sel) {
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str(&text.slice_to_cow(range));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use xi_rope::Rope;
+
+    use super::copy_text;
+    use crate::{SelRegion, Selection};
+
+    fn sel(regions: &[(usize, usize)]) -> Selection {
+        let mut sel = Selection::new();
+        for &(start, end) in regions {
+            sel.add_region(SelRegion::new(start, end));
+        }
+        sel
+    }
+
+    #[test]
+    fn copy_line_at_caret() {
+        let text = Rope::from("ab\ncd\r\nef");
+        assert_eq!(copy_text(&text, &sel(&[(1, 1)])), "ab\n");
+        assert_eq!(copy_text(&text, &sel(&[(3, 3)])), "cd\r\n");
+        // The last line has no line ending.
+        assert_eq!(copy_text(&text, &sel(&[(8, 8)])), "ef");
+        // Carets on the same line copy it once.
+        assert_eq!(copy_text(&text, &sel(&[(0, 0), (2, 2)])), "ab\n");
+        assert_eq!(copy_text(&Rope::from("ab\n"), &sel(&[(3, 3)])), "");
+    }
+
+    #[test]
+    fn copy_mixed() {
+        let text = Rope::from("ab\ncd\nef");
+        assert_eq!(copy_text(&text, &sel(&[(0, 1)])), "a");
+        // Each region follows its own rule, one per line.
+        assert_eq!(
+            copy_text(&text, &sel(&[(0, 1), (4, 4), (7, 6)])),
+            "a\ncd\ne"
+        );
+        // A region overlapping a caret's line is merged with it.
+        assert_eq!(copy_text(&text, &sel(&[(1, 1), (2, 4)])), "ab\nc");
+    }
+}
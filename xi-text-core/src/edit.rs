@@ -14,19 +14,158 @@
 
 //! Edit operations.
 
-use xi_rope::{DeltaBuilder, Rope, RopeDelta};
+use std::ops::Range;
+
+use xi_rope::{DeltaBuilder, Rope, RopeDelta, Transformer};
 
 use crate::backspace;
-use crate::selection::{InsertDrift, Selection};
-use crate::{Measurement, Movement};
+use crate::clipboard;
+use crate::measurement;
+use crate::selection::{InsertDrift, SelRegion, Selection};
+use crate::{LineEnding, Measurement, Movement};
 
 /// An edit operation.
 ///
 /// We explicitly represent an edit operation.
 pub enum EditOp {
+    /// Insert text, replacing the selected text. The selection ends up as
+    /// carets after the inserted text.
     Insert(String),
+    /// Insert text, with control over where the selection ends up.
+    ///
+    /// For carets, the drift is ignored, and the caret ends up after the
+    /// inserted text if `after` is set, or stays before it otherwise.
+    ///
+    /// For non-caret regions, which are replaced by the text, the result
+    /// depends on `drift`:
+    ///
+    /// * `Default`: a caret after the text if `after` is set, otherwise a
+    ///   region covering the inserted text.
+    /// * `Inside`: a region covering the inserted text, with the original
+    ///   direction.
+    /// * `Outside`: a caret after the text. This drift is mostly useful for
+    ///   insertions adjacent to (rather than replacing) a selection.
+    InsertWithDrift {
+        text: String,
+        after: bool,
+        drift: InsertDrift,
+    },
+    /// Insert text in overwrite mode.
+    ///
+    /// Like `Insert`, but a caret also replaces the grapheme after it. At the
+    /// end of a line, there is nothing to replace, and the text is inserted.
+    Overwrite(String),
+    /// Insert a tab, replacing the selected text.
+    ///
+    /// With `soft` set, each region instead gets spaces up to the next tab
+    /// stop, counted from the visual column of its start (see
+    /// `measurement::visual_col`). The `width` must be nonzero.
+    InsertTab {
+        soft: bool,
+        width: usize,
+    },
+    /// Wrap each selected region in `open` and `close`, as when typing an
+    /// opening bracket or quote over a selection. The regions still cover
+    /// the text they did, between the inserted pair.
+    ///
+    /// Carets get `open` alone, as if it were typed.
+    Surround {
+        open: String,
+        close: String,
+    },
     Backspace,
     Move(Movement),
+    /// Move the active end of each region, keeping the other end in place,
+    /// as when moving with shift held.
+    Extend(Movement),
+    /// Collapse the selection to a single caret at the end of the primary
+    /// region (see `Selection::primary`).
+    CollapseSelection,
+    /// Add a caret on the visual line above (`Movement::Up`) or below
+    /// (`Movement::Down`), at the same horizontal position.
+    ///
+    /// The new caret is placed relative to the topmost region when going up
+    /// and the bottommost when going down, so that repeating the op grows a
+    /// column of carets. Other movements are ignored.
+    AddCaretVertical(Movement),
+    /// Swap the logical lines touched by each region (see
+    /// `measurement::lines_for_region`) with the line above (`Movement::Up`)
+    /// or below (`Movement::Down`), keeping the selection on the moved text.
+    ///
+    /// Regions on the same or adjacent lines move together. If any lines
+    /// would move past the start or end of the document, nothing moves.
+    /// Other movements are ignored.
+    MoveLine(Movement),
+    /// Toggle a line comment, starting with the given prefix, on every
+    /// logical line touched by the selection.
+    ///
+    /// If every line is already commented, the prefix (and a space after
+    /// it) is removed from each. Otherwise, the prefix and a space are
+    /// inserted after the leading whitespace of each line. Blank lines are
+    /// left alone, and don't count when deciding which way to go.
+    ToggleLineComment(String),
+    /// Invert the case of each character in the selection, lowercase to
+    /// uppercase and the other way around.
+    ///
+    /// Characters without case are left alone. A case mapping can change
+    /// the length of the text, as with `ß` to `SS`; each region still
+    /// covers the text that replaced it.
+    SwapCase,
+    /// Remove trailing whitespace from every logical line, as when saving.
+    TrimTrailingWhitespace,
+    /// Make a non-empty text end with exactly one line ending, adding one
+    /// (detected from the text, see `LineEnding::detect`) if it's missing,
+    /// and removing any empty lines after it. Carets stay before an added
+    /// line ending.
+    EnsureFinalNewline,
+    /// Replace a range of logical lines, with their line endings, as when
+    /// sorting or formatting them. The replacement can have any number of
+    /// lines, and should end with a line ending unless the range runs to
+    /// the end of the text.
+    ///
+    /// A caret at the start of the lines stays before the replacement, and
+    /// carets further in end up after it. A region covering the lines
+    /// covers the replacement. Lines past the last one are clamped to the
+    /// end of the text.
+    ReplaceLines {
+        lines: Range<usize>,
+        text: String,
+    },
+    /// Sort the logical lines touched by each region (see
+    /// `measurement::lines_for_region`). Regions that share a line sort
+    /// their lines together.
+    ///
+    /// Only the contents of the lines move; the line endings stay where
+    /// they are, so the last line doesn't lose its line ending, or take one
+    /// from another line. Lines that compare equal keep their order.
+    SortLines {
+        ascending: bool,
+        case_sensitive: bool,
+    },
+    /// Remove each logical line that is the same as the line before it,
+    /// among the lines touched by the selected regions, or in the whole
+    /// document if no text is selected. Regions that share a line are
+    /// deduplicated together.
+    ///
+    /// With `trim_whitespace` set, lines are compared without their leading
+    /// and trailing whitespace, and the first line of a run is kept as it
+    /// is. Regions shrink to cover the lines that are left.
+    DedupeLines {
+        trim_whitespace: bool,
+    },
+    /// Add to the integer at or after each region's start, on the same
+    /// line, as with Ctrl+A in vim. An integer is a run of ASCII digits,
+    /// with a `-` just before it as its sign.
+    ///
+    /// A number with leading zeros keeps its number of digits, so `007`
+    /// becomes `008`. Each region with a number becomes a caret on its last
+    /// digit; regions that find the same number change it once. A number
+    /// that would overflow an `i64` is left alone.
+    IncrementNumber(i64),
+    /// Delete the text that copying the selection takes (see
+    /// `clipboard::copy_ranges`): the selected text, or the whole line at a
+    /// caret.
+    Cut,
 }
 
 impl EditOp {
@@ -38,16 +177,32 @@ impl EditOp {
         sel: &Selection,
         measurement: &impl Measurement,
     ) -> Selection {
+        self.apply_with_delta(text, sel, measurement).0
+    }
+
+    /// Apply the edit operation, also returning the delta applied to the
+    /// text, if the text changed.
+    pub fn apply_with_delta(
+        &self,
+        text: &mut Rope,
+        sel: &Selection,
+        measurement: &impl Measurement,
+    ) -> (Selection, Option<RopeDelta>) {
         match self {
-            EditOp::Insert(s) => {
-                let rope = Rope::from(s);
-                let mut builder = DeltaBuilder::new(text.len());
-                for region in sel {
-                    builder.replace(region.min()..region.max(), rope.clone());
-                }
-                apply_delta(text, sel, &builder.build())
+            EditOp::Insert(s) => insert(text, sel, s, true, InsertDrift::Default),
+            EditOp::InsertWithDrift {
+                text: s,
+                after,
+                drift,
+            } => insert(text, sel, s, *after, *drift),
+            EditOp::Overwrite(s) => overwrite(text, sel, s),
+            EditOp::InsertTab { soft: false, .. } => {
+                insert(text, sel, "\t", true, InsertDrift::Default)
             }
+            EditOp::InsertTab { soft: true, width } => insert_soft_tab(text, sel, *width),
+            EditOp::Surround { open, close } => surround(text, sel, open, close),
             EditOp::Backspace => {
+                debug_assert_sorted(sel);
                 let mut builder = DeltaBuilder::new(text.len());
                 for region in sel {
                     let start = backspace::offset_for_delete_backwards(region, text);
@@ -55,14 +210,1394 @@ impl EditOp {
                         builder.delete(start..region.max());
                     }
                 }
-                apply_delta(text, sel, &builder.build())
+                apply_delta(text, sel, builder.build())
+            }
+            EditOp::Move(movement) => (
+                movement.update_selection(sel, text, measurement, false),
+                None,
+            ),
+            EditOp::Extend(movement) => (
+                movement.update_selection(sel, text, measurement, true),
+                None,
+            ),
+            EditOp::CollapseSelection => {
+                let mut sel = sel.clone();
+                sel.collapse();
+                (sel, None)
+            }
+            EditOp::AddCaretVertical(movement) => {
+                (add_caret_vertical(*movement, text, sel, measurement), None)
+            }
+            EditOp::MoveLine(movement) => move_lines(*movement, text, sel),
+            EditOp::ToggleLineComment(prefix) => toggle_line_comment(text, sel, prefix),
+            EditOp::SwapCase => swap_case(text, sel),
+            EditOp::TrimTrailingWhitespace => trim_trailing_whitespace(text, sel),
+            EditOp::EnsureFinalNewline => ensure_final_newline(text, sel),
+            EditOp::ReplaceLines { lines, text: s } => replace_lines(text, sel, lines, s),
+            EditOp::SortLines {
+                ascending,
+                case_sensitive,
+            } => sort_lines(text, sel, *ascending, *case_sensitive),
+            EditOp::DedupeLines { trim_whitespace } => dedupe_lines(text, sel, *trim_whitespace),
+            EditOp::IncrementNumber(delta) => increment_numbers(text, sel, *delta),
+            EditOp::Cut => {
+                let mut builder = DeltaBuilder::new(text.len());
+                for range in clipboard::copy_ranges(text, sel) {
+                    builder.delete(range);
+                }
+                apply_delta(text, sel, builder.build())
             }
-            EditOp::Move(movement) => movement.update_selection(sel, text, measurement, false),
         }
     }
 }
 
-fn apply_delta(text: &mut Rope, sel: &Selection, delta: &RopeDelta) -> Selection {
+fn insert(
+    text: &mut Rope,
+    sel: &Selection,
+    s: &str,
+    after: bool,
+    drift: InsertDrift,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let rope = Rope::from(s);
+    let mut builder = DeltaBuilder::new(text.len());
+    for region in sel {
+        builder.replace(region.min()..region.max(), rope.clone());
+    }
+    apply_delta_drift(text, sel, builder.build(), after, drift)
+}
+
+fn insert_soft_tab(
+    text: &mut Rope,
+    sel: &Selection,
+    width: usize,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let mut builder = DeltaBuilder::new(text.len());
+    for region in sel {
+        let col = measurement::visual_col(text, region.min(), width);
+        let spaces = " ".repeat(width - col % width);
+        builder.replace(region.min()..region.max(), Rope::from(spaces));
+    }
+    apply_delta(text, sel, builder.build())
+}
+
+fn surround(
+    text: &mut Rope,
+    sel: &Selection,
+    open: &str,
+    close: &str,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let mut builder = DeltaBuilder::new(text.len());
+    // As with `overwrite`, the regions are computed directly, so that a
+    // region doesn't grow over the pair of a region next to it.
+    let mut new_sel = Selection::new();
+    let mut shift = 0isize;
+    for region in sel {
+        builder.replace(region.min()..region.min(), Rope::from(open));
+        shift += open.len() as isize;
+        let moved = |offset: usize| (offset as isize + shift) as usize;
+        let new_region = SelRegion {
+            start: moved(region.start),
+            end: moved(region.end),
+            ..*region
+        };
+        new_sel.add_region(new_region.with_horiz(None));
+        if !region.is_caret() {
+            builder.replace(region.max()..region.max(), Rope::from(close));
+            shift += close.len() as isize;
+        }
+    }
+    let delta = builder.build();
+    if delta.is_identity() {
+        return (sel.clone(), None);
+    }
     *text = delta.apply(&text);
-    sel.apply_delta(delta, true, InsertDrift::Default)
+    (new_sel, Some(delta))
+}
+
+fn replace_lines(
+    text: &mut Rope,
+    sel: &Selection,
+    lines: &Range<usize>,
+    s: &str,
+) -> (Selection, Option<RopeDelta>) {
+    // The end of the text is the start of the line after the last.
+    let n_lines = text.line_of_offset(text.len()) + 1;
+    let start = text.offset_of_line(lines.start.min(n_lines));
+    let end = text.offset_of_line(lines.end.min(n_lines)).max(start);
+    let mut builder = DeltaBuilder::new(text.len());
+    builder.replace(start..end, Rope::from(s));
+    apply_delta_drift(text, sel, builder.build(), false, InsertDrift::Inside)
+}
+
+fn sort_lines(
+    text: &mut Rope,
+    sel: &Selection,
+    ascending: bool,
+    case_sensitive: bool,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let mut builder = DeltaBuilder::new(text.len());
+    for lines in line_blocks(text, sel.iter()) {
+        let contents: Vec<String> = lines.clone().map(|line| line_text(text, line)).collect();
+        let mut sorted = contents.clone();
+        sorted.sort_by(|a, b| {
+            let order = if case_sensitive {
+                a.cmp(b)
+            } else {
+                a.to_lowercase().cmp(&b.to_lowercase())
+            };
+            if ascending {
+                order
+            } else {
+                order.reverse()
+            }
+        });
+        if sorted == contents {
+            continue;
+        }
+        // The line endings between the lines, in place.
+        let mut block = String::new();
+        for (line, s) in lines.clone().zip(&sorted) {
+            block.push_str(s);
+            if line + 1 < lines.end {
+                let end = measurement::line_end(text, line);
+                let next = text.offset_of_line(line + 1);
+                block.push_str(&text.slice_to_cow(end..next));
+            }
+        }
+        let start = text.offset_of_line(lines.start);
+        let end = measurement::line_end(text, lines.end - 1);
+        builder.replace(start..end, Rope::from(block));
+    }
+    apply_delta_drift(text, sel, builder.build(), false, InsertDrift::Inside)
+}
+
+fn dedupe_lines(
+    text: &mut Rope,
+    sel: &Selection,
+    trim_whitespace: bool,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let mut blocks = line_blocks(text, sel.iter().filter(|r| !r.is_caret()));
+    if blocks.is_empty() {
+        blocks.push(0..text.line_of_offset(text.len()) + 1);
+    }
+    let key = |s: String| {
+        if trim_whitespace {
+            s.trim().to_string()
+        } else {
+            s
+        }
+    };
+    let mut builder = DeltaBuilder::new(text.len());
+    for lines in blocks {
+        let mut prev = key(line_text(text, lines.start));
+        for line in lines.start + 1..lines.end {
+            let current = key(line_text(text, line));
+            if current == prev {
+                // Delete the line with the line ending before it, so the
+                // last line of the text can go too.
+                let start = measurement::line_end(text, line - 1);
+                builder.delete(start..measurement::line_end(text, line));
+            } else {
+                prev = current;
+            }
+        }
+    }
+    apply_delta_drift(text, sel, builder.build(), false, InsertDrift::Inside)
+}
+
+/// The blocks of logical lines touched by regions (see
+/// `measurement::lines_for_region`), in order, with the lines of regions
+/// that share a line in one block.
+fn line_blocks<'a>(text: &Rope, regions: impl Iterator<Item = &'a SelRegion>) -> Vec<Range<usize>> {
+    let mut blocks: Vec<Range<usize>> = Vec::new();
+    for region in regions {
+        let lines = measurement::lines_for_region(region, text);
+        match blocks.last_mut() {
+            Some(block) if lines.start < block.end => block.end = block.end.max(lines.end),
+            _ => blocks.push(lines),
+        }
+    }
+    blocks
+}
+
+/// The text of a logical line, without its line ending.
+fn line_text(text: &Rope, line: usize) -> String {
+    let start = text.offset_of_line(line);
+    let end = measurement::line_end(text, line);
+    text.slice_to_cow(start..end).into_owned()
+}
+
+fn increment_numbers(
+    text: &mut Rope,
+    sel: &Selection,
+    delta: i64,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    // The replaced numbers, and the index of the number each region found.
+    let mut numbers: Vec<(Range<usize>, String)> = Vec::new();
+    let mut found = Vec::with_capacity(sel.len());
+    for region in sel {
+        let range = match number_at(text, region.min()) {
+            Some(range) => range,
+            None => {
+                found.push(None);
+                continue;
+            }
+        };
+        match numbers.last() {
+            Some((last, _)) if *last == range => found.push(Some(numbers.len() - 1)),
+            Some((last, _)) if range.start < last.end => found.push(None),
+            _ => {
+                let old = text.slice_to_cow(range.clone());
+                match add_to_number(&old, delta) {
+                    Some(new) => {
+                        numbers.push((range, new));
+                        found.push(Some(numbers.len() - 1));
+                    }
+                    None => found.push(None),
+                }
+            }
+        }
+    }
+    let mut builder = DeltaBuilder::new(text.len());
+    // The offset of the last digit of each number, after the edit.
+    let mut last_digits = Vec::with_capacity(numbers.len());
+    let mut shift = 0isize;
+    for (range, new) in numbers {
+        let start = (range.start as isize + shift) as usize;
+        last_digits.push(start + new.len() - 1);
+        shift += new.len() as isize - range.len() as isize;
+        builder.replace(range, Rope::from(new));
+    }
+    let delta = builder.build();
+    if delta.is_identity() {
+        return (sel.clone(), None);
+    }
+    // Regions without a number move with the text around them.
+    let mut transformer = Transformer::new(&delta);
+    let mut new_sel = Selection::new();
+    for (region, found) in sel.iter().zip(found) {
+        let new_region = match found {
+            Some(i) => SelRegion::caret(last_digits[i]),
+            None => SelRegion {
+                start: transformer.transform(region.start, false),
+                end: transformer.transform(region.end, false),
+                ..*region
+            },
+        };
+        new_sel.add_region(new_region.with_horiz(None));
+    }
+    *text = delta.apply(&text);
+    (new_sel, Some(delta))
+}
+
+/// The integer at or after an offset, on the same line: a run of ASCII
+/// digits, and a `-` just before it.
+fn number_at(text: &Rope, offset: usize) -> Option<Range<usize>> {
+    let line = text.line_of_offset(offset);
+    let line_start = text.offset_of_line(line);
+    let line = text.slice_to_cow(line_start..measurement::line_end(text, line));
+    let bytes = line.as_bytes();
+    let offset = offset - line_start;
+    let mut start = offset;
+    while start > 0 && bytes[start - 1].is_ascii_digit() {
+        start -= 1;
+    }
+    if start == offset {
+        start += bytes[offset..].iter().position(u8::is_ascii_digit)?;
+    }
+    let mut end = start;
+    while end < bytes.len() && bytes[end].is_ascii_digit() {
+        end += 1;
+    }
+    if start > 0 && bytes[start - 1] == b'-' {
+        start -= 1;
+    }
+    Some(line_start + start..line_start + end)
+}
+
+/// Add to a number, keeping the number of digits if it has leading zeros.
+fn add_to_number(number: &str, delta: i64) -> Option<String> {
+    let digits = number.trim_start_matches('-');
+    let value = number.parse::<i64>().ok()?.checked_add(delta)?;
+    let width = if digits.len() > 1 && digits.starts_with('0') {
+        digits.len()
+    } else {
+        0
+    };
+    let sign = if value < 0 { "-" } else { "" };
+    Some(format!(
+        "{}{:0width$}",
+        sign,
+        value.checked_abs()?,
+        width = width
+    ))
+}
+
+fn overwrite(text: &mut Rope, sel: &Selection, s: &str) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let rope = Rope::from(s);
+    let mut builder = DeltaBuilder::new(text.len());
+    // The carets are computed directly, rather than by transforming the
+    // selection, as adjacent replaced ranges would all transform to the
+    // end of the last one.
+    let mut new_sel = Selection::new();
+    let mut shift = 0isize;
+    for (i, region) in sel.iter().enumerate() {
+        let mut end = region.max();
+        if region.is_caret() {
+            end = overwrite_end(text, end);
+            // Don't overlap the next region.
+            if let Some(next) = sel.get(i + 1) {
+                end = end.min(next.min());
+            }
+        }
+        builder.replace(region.min()..end, rope.clone());
+        let new_offset = (region.min() as isize + shift) as usize + s.len();
+        new_sel.add_region(SelRegion::caret(new_offset));
+        shift += s.len() as isize - (end - region.min()) as isize;
+    }
+    let delta = builder.build();
+    if delta.is_identity() {
+        return (sel.clone(), None);
+    }
+    *text = delta.apply(&text);
+    (new_sel, Some(delta))
+}
+
+/// The end of the text replaced by overwriting at a caret: the next
+/// grapheme boundary, unless the caret is at the end of the line.
+fn overwrite_end(text: &Rope, offset: usize) -> usize {
+    let line_end = measurement::line_end(text, text.line_of_offset(offset));
+    if offset >= line_end {
+        offset
+    } else {
+        text.next_grapheme_offset(offset).unwrap_or(line_end)
+    }
+}
+
+fn add_caret_vertical(
+    movement: Movement,
+    text: &Rope,
+    sel: &Selection,
+    measurement: &impl Measurement,
+) -> Selection {
+    let mut sel = sel.clone();
+    let region = match movement {
+        Movement::Up => sel.first(),
+        Movement::Down => sel.last(),
+        _ => None,
+    };
+    if let Some(region) = region {
+        let caret = SelRegion::caret(region.end).with_horiz(region.horiz);
+        let new_caret = movement.update_region(caret, text, measurement, false);
+        // At the top or bottom of the document, the movement lands on the
+        // same visual line; don't add a caret there.
+        if visual_line(text, measurement, new_caret.end)
+            != visual_line(text, measurement, caret.end)
+        {
+            sel.add_region(new_caret);
+        }
+    }
+    sel
+}
+
+fn move_lines(
+    movement: Movement,
+    text: &mut Rope,
+    sel: &Selection,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let up = match movement {
+        Movement::Up => true,
+        Movement::Down => false,
+        _ => return (sel.clone(), None),
+    };
+    // Blocks of lines that move together, and the indices of their regions.
+    let mut blocks: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+    for (i, region) in sel.iter().enumerate() {
+        let lines = measurement::lines_for_region(region, text);
+        match blocks.last_mut() {
+            Some((block, regions)) if lines.start <= block.end => {
+                block.end = block.end.max(lines.end);
+                regions.end = i + 1;
+            }
+            _ => blocks.push((lines, i..i + 1)),
+        }
+    }
+    let last_line = text.line_of_offset(text.len());
+    let at_edge = if up {
+        blocks.first().is_none_or(|(lines, _)| lines.start == 0)
+    } else {
+        blocks.last().is_none_or(|(lines, _)| lines.end > last_line)
+    };
+    if at_edge {
+        return (sel.clone(), None);
+    }
+    let mut builder = DeltaBuilder::new(text.len());
+    let mut new_sel = Selection::new();
+    for (lines, regions) in blocks {
+        // Swap the contents of the upper and lower lines, leaving the line
+        // ending between them, and after the lower one, in place.
+        let (upper, lower) = if up {
+            (lines.start - 1..lines.start, lines)
+        } else {
+            (lines.clone(), lines.end..lines.end + 1)
+        };
+        let start = text.offset_of_line(upper.start);
+        let upper_end = measurement::line_end(text, upper.end - 1);
+        let lower_start = text.offset_of_line(lower.start);
+        let end = measurement::line_end(text, lower.end - 1);
+        let mut swapped = String::from(text.slice_to_cow(lower_start..end));
+        swapped.push_str(&text.slice_to_cow(upper_end..lower_start));
+        swapped.push_str(&text.slice_to_cow(start..upper_end));
+        builder.replace(start..end, Rope::from(swapped));
+        let shift = if up {
+            start as isize - lower_start as isize
+        } else {
+            (end - upper_end) as isize
+        };
+        let moved = |offset: usize| (offset as isize + shift) as usize;
+        for region in &sel[regions] {
+            new_sel.add_region(SelRegion {
+                start: moved(region.start),
+                end: moved(region.end),
+                ..*region
+            });
+        }
+    }
+    let delta = builder.build();
+    *text = delta.apply(&text);
+    (new_sel, Some(delta))
+}
+
+fn toggle_line_comment(
+    text: &mut Rope,
+    sel: &Selection,
+    prefix: &str,
+) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    // The start and end of the non-blank part of each line, in order and
+    // without repeats, as adjacent regions can share a line.
+    let mut lines: Vec<(usize, usize)> = Vec::new();
+    let mut last_line = None;
+    for region in sel {
+        for line in measurement::lines_for_region(region, text) {
+            if last_line.is_some_and(|last| line <= last) {
+                continue;
+            }
+            last_line = Some(line);
+            let start = measurement::indent_end(text, line);
+            let end = measurement::line_end(text, line);
+            if start < end {
+                lines.push((start, end));
+            }
+        }
+    }
+    let uncomment = !lines.is_empty()
+        && lines
+            .iter()
+            .all(|&(start, end)| text.slice_to_cow(start..end).starts_with(prefix));
+    let mut builder = DeltaBuilder::new(text.len());
+    for (start, end) in lines {
+        if uncomment {
+            let rest = text.slice_to_cow(start + prefix.len()..end);
+            let space = if rest.starts_with(' ') { 1 } else { 0 };
+            builder.delete(start..start + prefix.len() + space);
+        } else {
+            builder.replace(start..start, Rope::from(format!("{} ", prefix)));
+        }
+    }
+    // Inside drift keeps a region that starts at an insertion covering it,
+    // so the selection still covers whole lines.
+    apply_delta_drift(text, sel, builder.build(), true, InsertDrift::Inside)
+}
+
+fn swap_case(text: &mut Rope, sel: &Selection) -> (Selection, Option<RopeDelta>) {
+    debug_assert_sorted(sel);
+    let mut builder = DeltaBuilder::new(text.len());
+    // The regions are computed directly, as with `overwrite`, so that
+    // adjacent regions don't grow over each other's replaced text.
+    let mut new_sel = Selection::new();
+    let mut shift = 0isize;
+    for region in sel {
+        let range = region.min()..region.max();
+        let old = text.slice_to_cow(range.clone());
+        let swapped = swap_case_str(&old);
+        let min = (region.min() as isize + shift) as usize;
+        let max = min + swapped.len();
+        shift += swapped.len() as isize - old.len() as isize;
+        if swapped != old {
+            builder.replace(range, Rope::from(swapped));
+        }
+        let (start, end) = if region.start <= region.end {
+            (min, max)
+        } else {
+            (max, min)
+        };
+        new_sel.add_region(SelRegion {
+            start,
+            end,
+            ..*region
+        });
+    }
+    let delta = builder.build();
+    if delta.is_identity() {
+        return (sel.clone(), None);
+    }
+    *text = delta.apply(&text);
+    (new_sel, Some(delta))
+}
+
+/// A string with the case of each character inverted.
+fn swap_case_str(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_lowercase() {
+            result.extend(c.to_uppercase());
+        } else if c.is_uppercase() {
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn trim_trailing_whitespace(text: &mut Rope, sel: &Selection) -> (Selection, Option<RopeDelta>) {
+    let mut builder = DeltaBuilder::new(text.len());
+    for line in 0..=text.line_of_offset(text.len()) {
+        let start = text.offset_of_line(line);
+        let end = measurement::line_end(text, line);
+        let trimmed_end = start + text.slice_to_cow(start..end).trim_end().len();
+        if trimmed_end < end {
+            builder.delete(trimmed_end..end);
+        }
+    }
+    // Carets in trailing whitespace end up at the new end of the line.
+    apply_delta(text, sel, builder.build())
+}
+
+fn ensure_final_newline(text: &mut Rope, sel: &Selection) -> (Selection, Option<RopeDelta>) {
+    let len = text.len();
+    if len == 0 {
+        return (sel.clone(), None);
+    }
+    // The end of the text before any trailing line endings.
+    let mut end = len;
+    while end > 0 && (text.byte_at(end - 1) == b'\n' || text.byte_at(end - 1) == b'\r') {
+        end -= 1;
+    }
+    let mut builder = DeltaBuilder::new(len);
+    if end == len {
+        let line_ending = LineEnding::detect(text).unwrap_or_default();
+        builder.replace(len..len, Rope::from(line_ending.as_str()));
+    } else {
+        let keep = text.offset_of_line(text.line_of_offset(end) + 1);
+        builder.delete(keep..len);
+    }
+    apply_delta_drift(text, sel, builder.build(), false, InsertDrift::Default)
+}
+
+/// The logical line and the visual line within it of an offset.
+fn visual_line(text: &Rope, measurement: &impl Measurement, offset: usize) -> (usize, usize) {
+    let line_num = text.line_of_offset(offset);
+    let line_start = text.offset_of_line(line_num);
+    let (_, rel_line) = measurement.to_pos(line_num, offset - line_start);
+    (line_num, rel_line)
+}
+
+/// Check that the regions are in order, as `DeltaBuilder` requires.
+///
+/// `Selection` maintains this, so a failure is a bug there.
+fn debug_assert_sorted(sel: &Selection) {
+    debug_assert!(
+        sel.windows(2).all(|w| w[0].max() <= w[1].min()),
+        "selection regions out of order: {}",
+        sel
+    );
+}
+
+fn apply_delta(
+    text: &mut Rope,
+    sel: &Selection,
+    delta: RopeDelta,
+) -> (Selection, Option<RopeDelta>) {
+    apply_delta_drift(text, sel, delta, true, InsertDrift::Default)
+}
+
+fn apply_delta_drift(
+    text: &mut Rope,
+    sel: &Selection,
+    delta: RopeDelta,
+    after: bool,
+    drift: InsertDrift,
+) -> (Selection, Option<RopeDelta>) {
+    if delta.is_identity() {
+        return (sel.clone(), None);
+    }
+    let new_text = delta.apply(text);
+    let old_text = std::mem::replace(text, new_text);
+    let new_sel = sel.apply_delta(&delta, after, drift);
+    (keep_horiz(&old_text, text, sel, new_sel), Some(delta))
+}
+
+/// Carry the horizontal positions of the regions over an edit.
+///
+/// `SelRegion::horiz` is the column that vertical movement aims for. A
+/// region keeps it if the grapheme column of its active end (see
+/// `measurement::offset_to_line_col`) is the same after the edit, for
+/// example when the edit was on another line. Otherwise, as when typing at
+/// the caret, the caret's current position becomes the new target.
+fn keep_horiz(old_text: &Rope, text: &Rope, old_sel: &Selection, sel: Selection) -> Selection {
+    // Regions that merged can't be matched up.
+    if old_sel.len() != sel.len() || old_sel.iter().all(|r| r.horiz.is_none()) {
+        return sel;
+    }
+    let mut result = Selection::new();
+    for (old, new) in old_sel.iter().zip(sel.iter()) {
+        let (_, old_col) = measurement::offset_to_line_col(old_text, old.end);
+        let (_, new_col) = measurement::offset_to_line_col(text, new.end);
+        let horiz = old.horiz.filter(|_| old_col == new_col);
+        result.add_region(new.with_horiz(horiz));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+
+    use xi_rope::{Interval, Rope};
+
+    use super::EditOp;
+    use crate::measurement::TestMeasurement;
+    use crate::{InsertDrift, Movement, SelRegion, Selection};
+
+    fn caret(offset: usize) -> SelRegion {
+        SelRegion::caret(offset)
+    }
+
+    fn carets(sel: &Selection) -> Vec<usize> {
+        sel.iter().map(|r| r.end).collect()
+    }
+
+    #[test]
+    fn add_caret_down() {
+        // Line starts: 0, 7, 10, 18, 25.
+        let mut text = Rope::from("abcdef\nab\nabcdefg\nabcdef\n");
+        let m = TestMeasurement::new(&text);
+        let mut sel = Selection::new_simple(caret(4));
+        let op = EditOp::AddCaretVertical(Movement::Down);
+        sel = op.apply(&mut text, &sel, &m);
+        // Clamped to the end of the short line.
+        assert_eq!(carets(&sel), vec![4, 9]);
+        sel = op.apply(&mut text, &sel, &m);
+        // The column survives the short line.
+        assert_eq!(carets(&sel), vec![4, 9, 14]);
+        sel = op.apply(&mut text, &sel, &m);
+        assert_eq!(carets(&sel), vec![4, 9, 14, 22]);
+        // Last line is empty.
+        sel = op.apply(&mut text, &sel, &m);
+        assert_eq!(carets(&sel), vec![4, 9, 14, 22, 25]);
+        // Bottom of the document, no change.
+        sel = op.apply(&mut text, &sel, &m);
+        assert_eq!(carets(&sel), vec![4, 9, 14, 22, 25]);
+    }
+
+    #[test]
+    fn add_caret_up() {
+        let mut text = Rope::from("abcdef\nab\nabcdefg");
+        let m = TestMeasurement::new(&text);
+        let mut sel = Selection::new_simple(caret(15));
+        let op = EditOp::AddCaretVertical(Movement::Up);
+        sel = op.apply(&mut text, &sel, &m);
+        assert_eq!(carets(&sel), vec![9, 15]);
+        sel = op.apply(&mut text, &sel, &m);
+        assert_eq!(carets(&sel), vec![5, 9, 15]);
+        sel = op.apply(&mut text, &sel, &m);
+        assert_eq!(carets(&sel), vec![5, 9, 15]);
+    }
+
+    fn insert_with_drift(region: SelRegion, after: bool, drift: InsertDrift) -> Selection {
+        let mut text = Rope::from("abcdef");
+        let m = TestMeasurement::new(&text);
+        let op = EditOp::InsertWithDrift {
+            text: "xyz".into(),
+            after,
+            drift,
+        };
+        let sel = op.apply(&mut text, &Selection::new_simple(region), &m);
+        assert_eq!(String::from(&text).len(), 9 - region.max() + region.min());
+        sel
+    }
+
+    #[test]
+    fn insert_drift_caret() {
+        for &drift in &[
+            InsertDrift::Default,
+            InsertDrift::Inside,
+            InsertDrift::Outside,
+        ] {
+            let sel = insert_with_drift(caret(2), true, drift);
+            assert_eq!(sel.deref(), &[caret(5)]);
+            let sel = insert_with_drift(caret(2), false, drift);
+            assert_eq!(sel.deref(), &[caret(2)]);
+        }
+    }
+
+    #[test]
+    fn insert_drift_region() {
+        let region = SelRegion::new(1, 3);
+        let sel = insert_with_drift(region, true, InsertDrift::Default);
+        assert_eq!(sel.deref(), &[caret(4)]);
+        let sel = insert_with_drift(region, false, InsertDrift::Default);
+        assert_eq!(sel.deref(), &[SelRegion::new(1, 4)]);
+        let sel = insert_with_drift(region, true, InsertDrift::Inside);
+        assert_eq!(sel.deref(), &[SelRegion::new(1, 4)]);
+        let sel = insert_with_drift(SelRegion::new(3, 1), true, InsertDrift::Inside);
+        assert_eq!(sel.deref(), &[SelRegion::new(4, 1)]);
+        let sel = insert_with_drift(region, true, InsertDrift::Outside);
+        assert_eq!(sel.deref(), &[caret(4)]);
+    }
+
+    fn overwrite(s: &str, sel: &Selection, ins: &str) -> (String, Selection) {
+        let mut text = Rope::from(s);
+        let m = TestMeasurement::new(&text);
+        let sel = EditOp::Overwrite(ins.into()).apply(&mut text, sel, &m);
+        (String::from(&text), sel)
+    }
+
+    #[test]
+    fn regions_added_out_of_order() {
+        let mut text = Rope::from("abcdef");
+        let m = TestMeasurement::new(&text);
+        let mut sel = Selection::new();
+        sel.add_region(caret(5));
+        sel.add_region(SelRegion::new(3, 2));
+        sel.add_region(caret(1));
+        assert_eq!(sel.deref(), &[caret(1), SelRegion::new(3, 2), caret(5)]);
+        let sel = EditOp::Insert("x".into()).apply(&mut text, &sel, &m);
+        assert_eq!(String::from(&text), "axbxdexf");
+        assert_eq!(carets(&sel), vec![2, 4, 7]);
+        let sel = EditOp::Backspace.apply(&mut text, &sel, &m);
+        assert_eq!(String::from(&text), "abdef");
+        assert_eq!(carets(&sel), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn backspace_merges_carets() {
+        let mut text = Rope::from("abc");
+        let m = TestMeasurement::new(&text);
+        let mut sel = Selection::new();
+        sel.add_region(caret(1));
+        sel.add_region(caret(2));
+        let sel = EditOp::Backspace.apply(&mut text, &sel, &m);
+        assert_eq!(String::from(&text), "c");
+        assert_eq!(sel.deref(), &[caret(0)]);
+    }
+
+    fn insert_tab(s: &str, sel: &Selection, soft: bool) -> (String, Selection) {
+        let mut text = Rope::from(s);
+        let m = TestMeasurement::new(&text);
+        let op = EditOp::InsertTab { soft, width: 4 };
+        let sel = op.apply(&mut text, sel, &m);
+        (String::from(&text), sel)
+    }
+
+    #[test]
+    fn soft_tab() {
+        // Between tab stops, fill to the next one.
+        let (text, sel) = insert_tab("ab", &Selection::new_simple(caret(2)), true);
+        assert_eq!(text, "ab  ");
+        assert_eq!(sel.deref(), &[caret(4)]);
+        // At a tab stop, insert a whole tab width.
+        let (text, _) = insert_tab("ab", &Selection::new_simple(caret(0)), true);
+        assert_eq!(text, "    ab");
+        let (text, _) = insert_tab("abcd\nx", &Selection::new_simple(caret(4)), true);
+        assert_eq!(text, "abcd    \nx");
+        // The column is counted from the start of the line.
+        let (text, _) = insert_tab("abcd\nx", &Selection::new_simple(caret(6)), true);
+        assert_eq!(text, "abcd\nx   ");
+        // A selection is replaced, counting from its start.
+        let (text, _) = insert_tab("abcdef", &Selection::new_simple(SelRegion::new(5, 1)), true);
+        assert_eq!(text, "a   f");
+    }
+
+    #[test]
+    fn soft_tab_visual_col() {
+        // Columns count graphemes, not bytes.
+        let (text, _) = insert_tab("e\u{301}\u{4e2d}", &Selection::new_simple(caret(6)), true);
+        assert_eq!(text, "e\u{301}\u{4e2d}  ");
+        // A tab advances to the next tab stop.
+        let (text, _) = insert_tab("a\tb", &Selection::new_simple(caret(3)), true);
+        assert_eq!(text, "a\tb   ");
+    }
+
+    #[test]
+    fn soft_tab_multiple_carets() {
+        let mut sel = Selection::new();
+        sel.add_region(caret(1));
+        sel.add_region(caret(7));
+        sel.add_region(caret(10));
+        let (text, sel) = insert_tab("a\nbcdef\nbcde", &sel, true);
+        assert_eq!(text, "a   \nbcdef   \nbc  de");
+        assert_eq!(carets(&sel), vec![4, 13, 18]);
+    }
+
+    #[test]
+    fn hard_tab() {
+        let (text, sel) = insert_tab("ab", &Selection::new_simple(caret(1)), false);
+        assert_eq!(text, "a\tb");
+        assert_eq!(sel.deref(), &[caret(2)]);
+    }
+
+    #[test]
+    fn overwrite_mid_line() {
+        let (text, sel) = overwrite("abcd\nef", &Selection::new_simple(caret(1)), "x");
+        assert_eq!(text, "axcd\nef");
+        assert_eq!(sel.deref(), &[caret(2)]);
+        // A multi-byte grapheme is replaced as a unit.
+        let (text, _) = overwrite("ae\u{301}c", &Selection::new_simple(caret(1)), "x");
+        assert_eq!(text, "axc");
+        // A non-caret region is replaced as with insert.
+        let (text, _) = overwrite("abcd", &Selection::new_simple(SelRegion::new(1, 3)), "x");
+        assert_eq!(text, "axd");
+    }
+
+    #[test]
+    fn overwrite_end_of_line() {
+        let (text, sel) = overwrite("ab\nc", &Selection::new_simple(caret(2)), "x");
+        assert_eq!(text, "abx\nc");
+        assert_eq!(sel.deref(), &[caret(3)]);
+        let (text, _) = overwrite("ab\r\nc", &Selection::new_simple(caret(2)), "x");
+        assert_eq!(text, "abx\r\nc");
+        let (text, _) = overwrite("ab", &Selection::new_simple(caret(2)), "x");
+        assert_eq!(text, "abx");
+    }
+
+    #[test]
+    fn overwrite_multi_cursor() {
+        let mut sel = Selection::new();
+        sel.add_region(caret(0));
+        sel.add_region(caret(1));
+        sel.add_region(caret(4));
+        let (text, sel) = overwrite("abc\ndef", &sel, "x");
+        assert_eq!(text, "xxc\nxef");
+        assert_eq!(carets(&sel), vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn delta_only_on_change() {
+        let mut text = Rope::from("abc");
+        let m = TestMeasurement::new(&text);
+        let sel = Selection::new_simple(caret(0));
+        let (sel, delta) = EditOp::Backspace.apply_with_delta(&mut text, &sel, &m);
+        assert!(delta.is_none());
+        assert_eq!(sel.deref(), &[caret(0)]);
+        let (sel, delta) = EditOp::Insert("x".into()).apply_with_delta(&mut text, &sel, &m);
+        assert_eq!(delta.unwrap().summary(), (Interval::new(0, 0), 1));
+        assert_eq!(sel.deref(), &[caret(1)]);
+        let (_, delta) = EditOp::Move(Movement::Right).apply_with_delta(&mut text, &sel, &m);
+        assert!(delta.is_none());
+    }
+
+    #[test]
+    fn collapse() {
+        let mut text = Rope::from("abcdefghij");
+        let m = TestMeasurement::new(&text);
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::new(1, 2));
+        sel.add_region(caret(4));
+        sel.add_region(SelRegion::new(8, 6));
+        let sel = EditOp::CollapseSelection.apply(&mut text, &sel, &m);
+        assert_eq!(sel.deref(), &[caret(2)]);
+    }
+
+    fn move_lines(s: &str, sel: &Selection, movement: Movement) -> (String, Selection) {
+        let mut text = Rope::from(s);
+        let m = TestMeasurement::new(&text);
+        let sel = EditOp::MoveLine(movement).apply(&mut text, sel, &m);
+        (String::from(&text), sel)
+    }
+
+    #[test]
+    fn move_line_down() {
+        let (text, sel) = move_lines(
+            "ab\ncd\nef",
+            &Selection::new_simple(caret(4)),
+            Movement::Down,
+        );
+        assert_eq!(text, "ab\nef\ncd");
+        assert_eq!(sel.deref(), &[caret(7)]);
+        // A region ending at the start of a line doesn't move that line.
+        let region = SelRegion::new(0, 3);
+        let (text, sel) = move_lines("ab\ncd\nef", &Selection::new_simple(region), Movement::Down);
+        assert_eq!(text, "cd\nab\nef");
+        assert_eq!(sel.deref(), &[SelRegion::new(3, 6)]);
+    }
+
+    #[test]
+    fn move_lines_up() {
+        // The region covers lines 1 and 2, which move up as a block.
+        let region = SelRegion::new(4, 7);
+        let (text, sel) = move_lines(
+            "ab\ncd\nef\ngh",
+            &Selection::new_simple(region),
+            Movement::Up,
+        );
+        assert_eq!(text, "cd\nef\nab\ngh");
+        assert_eq!(sel.deref(), &[SelRegion::new(1, 4)]);
+    }
+
+    #[test]
+    fn move_line_edges() {
+        let mut text = Rope::from("ab\ncd");
+        let m = TestMeasurement::new(&text);
+        let sel = Selection::new_simple(caret(1));
+        let (_, delta) = EditOp::MoveLine(Movement::Up).apply_with_delta(&mut text, &sel, &m);
+        assert!(delta.is_none());
+        let sel = Selection::new_simple(caret(4));
+        let (_, delta) = EditOp::MoveLine(Movement::Down).apply_with_delta(&mut text, &sel, &m);
+        assert!(delta.is_none());
+        assert_eq!(String::from(&text), "ab\ncd");
+        // The empty last line is a line like any other.
+        let (text, sel) = move_lines("ab\n", &Selection::new_simple(caret(3)), Movement::Up);
+        assert_eq!(text, "\nab");
+        assert_eq!(sel.deref(), &[caret(0)]);
+    }
+
+    #[test]
+    fn move_lines_multiple_carets() {
+        let mut sel = Selection::new();
+        sel.add_region(caret(0));
+        sel.add_region(caret(4));
+        let (text, sel) = move_lines("a\nb\nc\nd\ne", &sel, Movement::Down);
+        assert_eq!(text, "b\na\nd\nc\ne");
+        assert_eq!(carets(&sel), vec![2, 6]);
+        // Carets on adjacent lines move together.
+        let mut sel = Selection::new();
+        sel.add_region(caret(2));
+        sel.add_region(caret(4));
+        let (text, sel) = move_lines("a\nb\nc", &sel, Movement::Up);
+        assert_eq!(text, "b\nc\na");
+        assert_eq!(carets(&sel), vec![0, 2]);
+        // Unless one of them is at the edge.
+        let mut sel = Selection::new();
+        sel.add_region(caret(0));
+        sel.add_region(caret(4));
+        let (text, _) = move_lines("a\nb\nc", &sel, Movement::Up);
+        assert_eq!(text, "a\nb\nc");
+    }
+
+    fn toggle_comment(s: &str, sel: &Selection) -> (String, Selection) {
+        let mut text = Rope::from(s);
+        let m = TestMeasurement::new(&text);
+        let sel = EditOp::ToggleLineComment("//".into()).apply(&mut text, sel, &m);
+        (String::from(&text), sel)
+    }
+
+    #[test]
+    fn toggle_comment_indented() {
+        let before = "fn f() {\n    a;\n\n    b;\n}";
+        let after = "fn f() {\n    // a;\n\n    // b;\n}";
+        // The region ends at the start of the last line, which isn't touched.
+        let sel = Selection::new_simple(SelRegion::new(9, 24));
+        let (text, sel) = toggle_comment(before, &sel);
+        assert_eq!(text, after);
+        assert_eq!(sel.deref(), &[SelRegion::new(9, 30)]);
+        let (text, sel) = toggle_comment(after, &sel);
+        assert_eq!(text, before);
+        assert_eq!(sel.deref(), &[SelRegion::new(9, 24)]);
+    }
+
+    #[test]
+    fn toggle_comment_mixed() {
+        // Not every line is commented, so all of them get a comment.
+        let (text, sel) = toggle_comment("// a\nb\n", &Selection::new_simple(SelRegion::new(0, 6)));
+        assert_eq!(text, "// // a\n// b\n");
+        assert_eq!(sel.deref(), &[SelRegion::new(0, 12)]);
+        // The space after the prefix is optional when uncommenting.
+        let (text, sel) = toggle_comment(
+            "  //a\n  // b",
+            &Selection::new_simple(SelRegion::new(0, 12)),
+        );
+        assert_eq!(text, "  a\n  b");
+        assert_eq!(sel.deref(), &[SelRegion::new(0, 7)]);
+    }
+
+    #[test]
+    fn toggle_comment_carets() {
+        let (text, sel) = toggle_comment("ab", &Selection::new_simple(caret(1)));
+        assert_eq!(text, "// ab");
+        assert_eq!(sel.deref(), &[caret(4)]);
+        // Carets on the same line comment it once.
+        let mut sel = Selection::new();
+        sel.add_region(caret(0));
+        sel.add_region(caret(1));
+        sel.add_region(caret(4));
+        let (text, sel) = toggle_comment("ab\ncd", &sel);
+        assert_eq!(text, "// ab\n// cd");
+        assert_eq!(carets(&sel), vec![3, 4, 10]);
+        // A blank line is left alone.
+        let (text, _) = toggle_comment("  \n", &Selection::new_simple(caret(1)));
+        assert_eq!(text, "  \n");
+    }
+
+    fn apply_op(s: &str, sel: &Selection, op: EditOp) -> (String, Selection) {
+        let mut text = Rope::from(s);
+        let m = TestMeasurement::new(&text);
+        let sel = op.apply(&mut text, sel, &m);
+        (String::from(&text), sel)
+    }
+
+    fn surround(s: &str, sel: &Selection, open: &str, close: &str) -> (String, Selection) {
+        let op = EditOp::Surround {
+            open: open.into(),
+            close: close.into(),
+        };
+        apply_op(s, sel, op)
+    }
+
+    #[test]
+    fn surround_regions() {
+        // Each region is wrapped, keeping its direction, including regions
+        // that touch.
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::new(0, 3));
+        sel.add_region(SelRegion::new(7, 4));
+        sel.add_region(SelRegion::new(7, 9));
+        let (text, sel) = surround("one twoxy", &sel, "(", ")");
+        assert_eq!(text, "(one) (two)(xy)");
+        assert_eq!(
+            sel.deref(),
+            &[
+                SelRegion::new(1, 4),
+                SelRegion::new(10, 7),
+                SelRegion::new(12, 14)
+            ]
+        );
+    }
+
+    #[test]
+    fn surround_carets() {
+        // A caret gets only the opening half, as if it were typed.
+        let mut sel = Selection::new();
+        sel.add_region(caret(1));
+        sel.add_region(SelRegion::new(2, 4));
+        let (text, sel) = surround("abcd", &sel, "\"", "\"");
+        assert_eq!(text, "a\"b\"cd\"");
+        assert_eq!(sel.deref(), &[caret(2), SelRegion::new(4, 6)]);
+    }
+
+    #[test]
+    fn swap_case() {
+        let sel = Selection::new_simple(SelRegion::new(0, 12));
+        let (text, sel) = apply_op("Hello, World", &sel, EditOp::SwapCase);
+        assert_eq!(text, "hELLO, wORLD");
+        assert_eq!(sel.deref(), &[SelRegion::new(0, 12)]);
+        // Only the selected text changes.
+        let sel = Selection::new_simple(SelRegion::new(1, 3));
+        let (text, _) = apply_op("abcd", &sel, EditOp::SwapCase);
+        assert_eq!(text, "aBCd");
+    }
+
+    #[test]
+    fn swap_case_without_case() {
+        // Digits, punctuation, CJK and the titlecase U+01C5 have no case.
+        let s = "1 + 2 = \u{1c5} \u{65e5}\u{672c}";
+        let sel = Selection::new_simple(SelRegion::new(0, s.len()));
+        let mut text = Rope::from(s);
+        let m = TestMeasurement::new(&text);
+        let (new_sel, delta) = EditOp::SwapCase.apply_with_delta(&mut text, &sel, &m);
+        assert!(delta.is_none());
+        assert_eq!(String::from(&text), s);
+        assert_eq!(new_sel.deref(), sel.deref());
+    }
+
+    #[test]
+    fn replace_lines() {
+        let op = || EditOp::ReplaceLines {
+            lines: 1..3,
+            text: "x\ny\nz\n".to_string(),
+        };
+        // Line starts: 0, 2, 4, 6. Carets outside the lines move with the
+        // text around them, and one inside ends up after the replacement.
+        let mut sel = Selection::new();
+        sel.add_region(caret(1));
+        sel.add_region(caret(2));
+        sel.add_region(caret(4));
+        sel.add_region(caret(7));
+        let (text, sel) = apply_op("a\nb\nc\nd", &sel, op());
+        assert_eq!(text, "a\nx\ny\nz\nd");
+        assert_eq!(sel.deref(), &[caret(1), caret(2), caret(8), caret(9)]);
+
+        // A region over the lines covers their replacement, in either
+        // direction.
+        let sel = Selection::new_simple(SelRegion::new(2, 6));
+        let (_, sel) = apply_op("a\nb\nc\nd", &sel, op());
+        assert_eq!(sel.deref(), &[SelRegion::new(2, 8)]);
+        let sel = Selection::new_simple(SelRegion::new(6, 2));
+        let (_, sel) = apply_op("a\nb\nc\nd", &sel, op());
+        assert_eq!(sel.deref(), &[SelRegion::new(8, 2)]);
+
+        // At the end, the last line has no line ending to replace.
+        let op = EditOp::ReplaceLines {
+            lines: 3..4,
+            text: "e\nf".to_string(),
+        };
+        let (text, _) = apply_op("a\nb\nc\nd", &Selection::new(), op);
+        assert_eq!(text, "a\nb\nc\ne\nf");
+
+        // Lines past the end are clamped to it.
+        let op = |lines| EditOp::ReplaceLines {
+            lines,
+            text: "e".to_string(),
+        };
+        let (text, _) = apply_op("a\nb\nc\nd", &Selection::new(), op(3..10));
+        assert_eq!(text, "a\nb\nc\ne");
+        let (text, _) = apply_op("a\nb\nc\nd", &Selection::new(), op(10..12));
+        assert_eq!(text, "a\nb\nc\nde");
+    }
+
+    fn sort(s: &str, sel: &Selection, ascending: bool, case_sensitive: bool) -> String {
+        let op = EditOp::SortLines {
+            ascending,
+            case_sensitive,
+        };
+        apply_op(s, sel, op).0
+    }
+
+    #[test]
+    fn sort_lines() {
+        // The selected block is the middle four lines, ending at the start
+        // of the last, which isn't included.
+        let text = "x\nb\nC\na\nc\nz";
+        let sel = Selection::new_simple(SelRegion::new(2, 10));
+        assert_eq!(sort(text, &sel, true, true), "x\nC\na\nb\nc\nz");
+        assert_eq!(sort(text, &sel, false, true), "x\nc\nb\na\nC\nz");
+        // Without case, "C" and "c" are equal and keep their order.
+        assert_eq!(sort(text, &sel, true, false), "x\na\nb\nC\nc\nz");
+        assert_eq!(sort(text, &sel, false, false), "x\nC\nc\nb\na\nz");
+
+        // The selection still covers the lines.
+        let op = EditOp::SortLines {
+            ascending: true,
+            case_sensitive: false,
+        };
+        let (_, new_sel) = apply_op(text, &sel, op);
+        assert_eq!(new_sel.deref(), &[SelRegion::new(2, 10)]);
+    }
+
+    #[test]
+    fn sort_lines_keeps_line_endings() {
+        // The last line has no line ending, and it doesn't get one.
+        let sel = Selection::new_simple(SelRegion::new(0, 6));
+        assert_eq!(sort("b\r\nc\na", &sel, true, true), "a\r\nb\nc");
+        // Regions sharing a line sort together, and others apart.
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::new(0, 4));
+        sel.add_region(SelRegion::new(5, 7));
+        sel.add_region(SelRegion::new(12, 17));
+        let text = "cc\nbb\naa\nzz\nyy\nxx";
+        assert_eq!(sort(text, &sel, true, true), "aa\nbb\ncc\nzz\nxx\nyy");
+    }
+
+    fn dedupe(s: &str, sel: &Selection, trim_whitespace: bool) -> (String, Selection) {
+        apply_op(s, sel, EditOp::DedupeLines { trim_whitespace })
+    }
+
+    #[test]
+    fn dedupe_lines() {
+        // With only a caret, the whole document, including the last line.
+        let sel = Selection::new_simple(caret(0));
+        let (text, _) = dedupe("a\na\nb\nb\nb\nc\na\na", &sel, false);
+        assert_eq!(text, "a\nb\nc\na");
+
+        // Only the selected lines, and the region covers what's left of
+        // them. The first line is the same as the second, but isn't
+        // selected.
+        let sel = Selection::new_simple(SelRegion::new(2, 8));
+        let (text, sel) = dedupe("x\nx\ny\ny\nz\nz", &sel, false);
+        assert_eq!(text, "x\nx\ny\nz\nz");
+        assert_eq!(sel.deref(), &[SelRegion::new(2, 6)]);
+
+        // Without duplicates, nothing changes.
+        let sel = Selection::new_simple(caret(1));
+        let (text, sel) = dedupe("a\nb\na", &sel, false);
+        assert_eq!(text, "a\nb\na");
+        assert_eq!(sel.deref(), &[caret(1)]);
+    }
+
+    #[test]
+    fn dedupe_lines_whitespace() {
+        let sel = Selection::new();
+        assert_eq!(dedupe("a \n a\n\ta\nb", &sel, false).0, "a \n a\n\ta\nb");
+        assert_eq!(dedupe("a \n a\n\ta\nb", &sel, true).0, "a \nb");
+    }
+
+    fn increment(s: &str, offset: usize, delta: i64) -> (String, usize) {
+        let sel = Selection::new_simple(caret(offset));
+        let (text, sel) = apply_op(s, &sel, EditOp::IncrementNumber(delta));
+        (text, sel[0].end)
+    }
+
+    #[test]
+    fn increment_number() {
+        // In a number, or before one on the same line.
+        assert_eq!(increment("x = 41;", 5, 1), ("x = 42;".to_string(), 5));
+        assert_eq!(increment("x = 41;", 0, 1), ("x = 42;".to_string(), 5));
+        assert_eq!(increment("x = 9", 4, 1), ("x = 10".to_string(), 5));
+        // Not a number on another line.
+        assert_eq!(increment("x\n1", 0, 1), ("x\n1".to_string(), 0));
+        // Across zero, with the sign.
+        assert_eq!(increment("a 1", 0, -2), ("a -1".to_string(), 3));
+        assert_eq!(increment("a -1", 3, 1), ("a 0".to_string(), 2));
+        assert_eq!(increment("a -1", 2, 5), ("a 4".to_string(), 2));
+        // Too big for an `i64`.
+        let big = "9223372036854775807";
+        assert_eq!(increment(big, 0, 1), (big.to_string(), 0));
+    }
+
+    #[test]
+    fn increment_number_leading_zeros() {
+        assert_eq!(increment("007", 0, 1).0, "008");
+        assert_eq!(increment("009", 0, 1).0, "010");
+        assert_eq!(increment("001", 0, -2).0, "-001");
+        assert_eq!(increment("999", 0, 1).0, "1000");
+        assert_eq!(increment("0", 0, -1).0, "-1");
+    }
+
+    #[test]
+    fn increment_numbers_multiple_carets() {
+        // Each caret on its own number, and two on the same one.
+        let mut sel = Selection::new();
+        sel.add_region(caret(0));
+        sel.add_region(caret(3));
+        sel.add_region(caret(5));
+        sel.add_region(caret(6));
+        sel.add_region(caret(9));
+        let (text, sel) = apply_op("9, 12 x\ny", &sel, EditOp::IncrementNumber(1));
+        assert_eq!(text, "10, 13 x\ny");
+        assert_eq!(sel.deref(), &[caret(1), caret(5), caret(7), caret(10)]);
+    }
+
+    #[test]
+    fn swap_case_changes_length() {
+        // The ligature U+FB01 (3 bytes) becomes "FI" (2 bytes), so the
+        // second region moves back by a byte. Directions are kept.
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::new(4, 0));
+        sel.add_region(SelRegion::new(5, 6));
+        sel.add_region(caret(7));
+        let (text, sel) = apply_op("\u{fb01}x ab", &sel, EditOp::SwapCase);
+        assert_eq!(text, "FIX Ab");
+        assert_eq!(
+            sel.deref(),
+            &[SelRegion::new(3, 0), SelRegion::new(4, 5), caret(6)]
+        );
+        // U+0149 (2 bytes) becomes U+02BC and "N" (3 bytes), and the
+        // sharp s becomes "SS", with more characters in the same length.
+        let sel = Selection::new_simple(SelRegion::new(0, 5));
+        let (text, sel) = apply_op("\u{149}\u{df}!", &sel, EditOp::SwapCase);
+        assert_eq!(text, "\u{2bc}NSS!");
+        assert_eq!(sel.deref(), &[SelRegion::new(0, 6)]);
+    }
+
+    fn trim(s: &str, sel: &Selection) -> (String, Selection) {
+        apply_op(s, sel, EditOp::TrimTrailingWhitespace)
+    }
+
+    #[test]
+    fn trim_whitespace() {
+        let sel = Selection::new_simple(caret(0));
+        let (text, _) = trim("a  \n\t\n  b\t \r\nc", &sel);
+        assert_eq!(text, "a\n\n  b\r\nc");
+        let (text, _) = trim("a\n  ", &sel);
+        assert_eq!(text, "a\n");
+    }
+
+    #[test]
+    fn trim_whitespace_carets() {
+        let mut sel = Selection::new();
+        sel.add_region(caret(1));
+        sel.add_region(caret(3));
+        sel.add_region(caret(4));
+        sel.add_region(caret(6));
+        let (text, sel) = trim("ab  \ncd", &sel);
+        assert_eq!(text, "ab\ncd");
+        // The carets in and after the trailing whitespace merge at the end
+        // of the line.
+        assert_eq!(carets(&sel), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn final_newline() {
+        let ensure = |s| {
+            apply_op(
+                s,
+                &Selection::new_simple(caret(1)),
+                EditOp::EnsureFinalNewline,
+            )
+        };
+        let (text, sel) = ensure("ab");
+        assert_eq!(text, "ab\n");
+        assert_eq!(sel.deref(), &[caret(1)]);
+        assert_eq!(ensure("ab\n").0, "ab\n");
+        assert_eq!(ensure("ab\n\n\n").0, "ab\n");
+        // The added line ending matches the text, and the kept one is left
+        // as it is.
+        assert_eq!(ensure("a\r\nb").0, "a\r\nb\r\n");
+        assert_eq!(ensure("a\r\nb\r\n\r\n").0, "a\r\nb\r\n");
+        // A text of only line endings keeps one.
+        assert_eq!(ensure("\n\n").0, "\n");
+        // An empty text stays empty.
+        assert_eq!(ensure("").0, "");
+
+        // Carets on removed lines end up on the last line.
+        let (text, sel) = apply_op(
+            "ab\n\n",
+            &Selection::new_simple(caret(4)),
+            EditOp::EnsureFinalNewline,
+        );
+        assert_eq!(text, "ab\n");
+        assert_eq!(sel.deref(), &[caret(3)]);
+    }
+
+    #[test]
+    fn horiz_kept_through_edits() {
+        // Line starts: 0, 9, 12.
+        let mut text = Rope::from("abcdef  \nab\nabcdef");
+        let m = TestMeasurement::new(&text);
+        let up = EditOp::Move(Movement::Up);
+        let sel = Selection::new_simple(caret(18));
+        // Through the short line, the column is remembered.
+        let sel = up.apply(&mut text, &sel, &m);
+        assert_eq!(sel.deref(), &[caret(11).with_horiz(Some(6.0))]);
+        // An edit on another line leaves the caret's column alone.
+        let sel = EditOp::TrimTrailingWhitespace.apply(&mut text, &sel, &m);
+        assert_eq!(sel.deref(), &[caret(9).with_horiz(Some(6.0))]);
+        let m = TestMeasurement::new(&text);
+        let sel = up.apply(&mut text, &sel, &m);
+        assert_eq!(sel.deref(), &[caret(6).with_horiz(Some(6.0))]);
+        // Typing moves the caret, so the target is dropped.
+        let sel = EditOp::Insert("x".into()).apply(&mut text, &sel, &m);
+        assert_eq!(sel.deref(), &[caret(7)]);
+    }
+
+    #[test]
+    fn cut() {
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion::new(0, 1));
+        sel.add_region(caret(4));
+        sel.add_region(SelRegion::new(7, 6));
+        let (text, sel) = apply_op("ab\ncd\nef", &sel, EditOp::Cut);
+        assert_eq!(text, "b\nf");
+        assert_eq!(carets(&sel), vec![0, 2]);
+    }
 }
@@ -13,12 +13,21 @@
 // limitations under the License.
 
 mod backspace;
+pub mod bracket;
+pub mod clipboard;
 mod edit;
-mod measurement;
+pub mod line_ending;
+pub mod measurement;
 mod movement;
+mod search;
 mod selection;
+pub mod stats;
+mod undo;
 
 pub use edit::EditOp;
+pub use line_ending::LineEnding;
 pub use measurement::Measurement;
 pub use movement::Movement;
-pub use selection::{Affinity, HorizPos, SelRegion, Selection};
+pub use search::Search;
+pub use selection::{Affinity, HorizPos, InsertDrift, SelRegion, Selection};
+pub use undo::{EditKind, Snapshot, UndoStack};
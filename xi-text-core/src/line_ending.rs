@@ -0,0 +1,168 @@
+// Copyright 2020 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Line ending detection and conversion.
+
+use std::io::{self, Write};
+
+use xi_rope::Rope;
+
+/// The line ending convention of a document.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineEnding {
+    /// Unix style, `\n`.
+    Lf,
+    /// Windows style, `\r\n`.
+    CrLf,
+}
+
+impl Default for LineEnding {
+    fn default() -> LineEnding {
+        LineEnding::Lf
+    }
+}
+
+impl LineEnding {
+    /// Detect the line ending of a document from its first line break.
+    ///
+    /// Returns `None` if the document has no line breaks.
+    pub fn detect(text: &Rope) -> Option<LineEnding> {
+        let first_line = text.lines_raw(..).next()?;
+        if first_line.ends_with("\r\n") {
+            Some(LineEnding::CrLf)
+        } else if first_line.ends_with('\n') {
+            Some(LineEnding::Lf)
+        } else {
+            None
+        }
+    }
+
+    /// The string representation of the line ending.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    /// Convert all line endings in a string to this one.
+    pub fn normalize(self, s: &str) -> String {
+        let mut result = String::with_capacity(s.len());
+        for line in s.split_inclusive('\n') {
+            let trimmed = trim_line_ending(line);
+            result.push_str(trimmed);
+            if trimmed.len() != line.len() {
+                result.push_str(self.as_str());
+            }
+        }
+        result
+    }
+}
+
+/// Trim the line ending (either `\n` or `\r\n`) from a raw line.
+pub fn trim_line_ending(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+/// Write the text, exactly as it is.
+pub fn write_text(text: &Rope, w: &mut impl Write) -> io::Result<()> {
+    for chunk in text.iter_chunks(..) {
+        w.write_all(chunk.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write the text, converting all line endings to the given one.
+pub fn write_text_normalized(
+    text: &Rope,
+    line_ending: LineEnding,
+    w: &mut impl Write,
+) -> io::Result<()> {
+    for line in text.lines_raw(..) {
+        let trimmed = trim_line_ending(&line);
+        w.write_all(trimmed.as_bytes())?;
+        if trimmed.len() != line.len() {
+            w.write_all(line_ending.as_str().as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{trim_line_ending, write_text, write_text_normalized, LineEnding};
+    use xi_rope::Rope;
+
+    fn normalized(s: &str, line_ending: LineEnding) -> String {
+        let mut buf = Vec::new();
+        write_text_normalized(&Rope::from(s), line_ending, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(LineEnding::detect(&Rope::from("")), None);
+        assert_eq!(LineEnding::detect(&Rope::from("abc")), None);
+        assert_eq!(
+            LineEnding::detect(&Rope::from("a\nb\r\n")),
+            Some(LineEnding::Lf)
+        );
+        assert_eq!(
+            LineEnding::detect(&Rope::from("a\r\nb\n")),
+            Some(LineEnding::CrLf)
+        );
+        assert_eq!(
+            LineEnding::detect(&Rope::from("a\rb\n")),
+            Some(LineEnding::Lf)
+        );
+    }
+
+    #[test]
+    fn normalize_str() {
+        assert_eq!(LineEnding::CrLf.normalize("\n"), "\r\n");
+        assert_eq!(LineEnding::CrLf.normalize("a\r\nb\nc"), "a\r\nb\r\nc");
+        assert_eq!(LineEnding::Lf.normalize("a\r\nb\nc\r\n"), "a\nb\nc\n");
+        assert_eq!(LineEnding::Lf.normalize("abc"), "abc");
+    }
+
+    #[test]
+    fn trim() {
+        assert_eq!(trim_line_ending("abc"), "abc");
+        assert_eq!(trim_line_ending("abc\n"), "abc");
+        assert_eq!(trim_line_ending("abc\r\n"), "abc");
+        assert_eq!(trim_line_ending("\r\n"), "");
+    }
+
+    #[test]
+    fn crlf_round_trip() {
+        let s = "one\r\ntwo\r\n\r\nthree";
+        let mut buf = Vec::new();
+        write_text(&Rope::from(s), &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), s);
+        assert_eq!(normalized(s, LineEnding::CrLf), s);
+        let lf = normalized(s, LineEnding::Lf);
+        assert_eq!(lf, "one\ntwo\n\nthree");
+        assert_eq!(normalized(&lf, LineEnding::CrLf), s);
+    }
+
+    #[test]
+    fn normalize_mixed() {
+        assert_eq!(
+            normalized("a\nb\r\nc\n", LineEnding::CrLf),
+            "a\r\nb\r\nc\r\n"
+        );
+        assert_eq!(normalized("a\nb\r\nc\n", LineEnding::Lf), "a\nb\nc\n");
+    }
+}
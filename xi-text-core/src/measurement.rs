@@ -1,10 +1,19 @@
 //! Text measurement.
 
+use std::ops::Range;
+
+use xi_rope::Rope;
+
+use crate::SelRegion;
+
 /// A trait for measurement of text.
 ///
 /// The client is expected to provide this.
 pub trait Measurement {
     /// Report the number of visual lines for a logical line.
+    ///
+    /// A line that isn't shown, such as one hidden by folding, has none,
+    /// and vertical movement skips over it.
     fn n_visual_lines(&self, line_num: usize) -> usize;
 
     /// Report cursor position for an offset within the logical line.
@@ -23,3 +32,303 @@ pub trait Measurement {
     /// logical line.
     fn from_pos(&self, line_num: usize, horiz: f64, visual_line: usize) -> usize;
 }
+
+// This lets a `&dyn Measurement` be passed where an `impl Measurement` is
+// expected.
+impl<M: Measurement + ?Sized> Measurement for &M {
+    fn n_visual_lines(&self, line_num: usize) -> usize {
+        (**self).n_visual_lines(line_num)
+    }
+
+    fn to_pos(&self, line_num: usize, offset: usize) -> (f64, usize) {
+        (**self).to_pos(line_num, offset)
+    }
+
+    fn from_pos(&self, line_num: usize, horiz: f64, visual_line: usize) -> usize {
+        (**self).from_pos(line_num, horiz, visual_line)
+    }
+}
+
+/// Convert a document offset to a logical line and a column.
+///
+/// The column is measured in grapheme clusters from the start of the line,
+/// which is what a user would expect to see in a status bar.
+pub fn offset_to_line_col(text: &Rope, offset: usize) -> (usize, usize) {
+    let line = text.line_of_offset(offset);
+    let mut pos = text.offset_of_line(line);
+    let mut col = 0;
+    while pos < offset {
+        match text.next_grapheme_offset(pos) {
+            Some(next) => pos = next,
+            None => break,
+        }
+        col += 1;
+    }
+    (line, col)
+}
+
+/// The grapheme boundary at or before an offset, which is clamped to the
+/// end of the text.
+pub fn grapheme_boundary(text: &Rope, offset: usize) -> usize {
+    if offset >= text.len() {
+        return text.len();
+    }
+    let offset = text.at_or_prev_codepoint_boundary(offset).unwrap_or(0);
+    let prev = match text.prev_grapheme_offset(offset) {
+        Some(prev) => prev,
+        None => return 0,
+    };
+    if text.next_grapheme_offset(prev) == Some(offset) {
+        offset
+    } else {
+        prev
+    }
+}
+
+/// The visual column of an offset within its line.
+///
+/// Like the column of `offset_to_line_col`, this counts grapheme clusters,
+/// except that a tab advances to the next multiple of `tab_width`.
+///
+/// # Panics
+///
+/// Panics if `tab_width` is zero.
+pub fn visual_col(text: &Rope, offset: usize, tab_width: usize) -> usize {
+    assert!(tab_width > 0, "tab width must be nonzero");
+    let mut pos = text.offset_of_line(text.line_of_offset(offset));
+    let mut col = 0;
+    while pos < offset {
+        let next = match text.next_grapheme_offset(pos) {
+            Some(next) => next,
+            None => break,
+        };
+        if text.byte_at(pos) == b'\t' {
+            col += tab_width - col % tab_width;
+        } else {
+            col += 1;
+        }
+        pos = next;
+    }
+    col
+}
+
+/// Convert a logical line and a grapheme column to a document offset.
+///
+/// Out of range values are clamped: a line past the end maps to the last
+/// line, and a column past the end of the line maps to the end of the line
+/// (before the line ending).
+pub fn line_col_to_offset(text: &Rope, line: usize, col: usize) -> usize {
+    let line = line.min(text.line_of_offset(text.len()));
+    let mut pos = text.offset_of_line(line);
+    let end = line_end(text, line);
+    for _ in 0..col {
+        if pos >= end {
+            break;
+        }
+        pos = text.next_grapheme_offset(pos).unwrap_or(end);
+    }
+    pos
+}
+
+/// The end of a logical line, not including the line ending.
+pub fn line_end(text: &Rope, line: usize) -> usize {
+    let start = text.offset_of_line(line);
+    let mut end = text.offset_of_line(line + 1);
+    if end > start && text.byte_at(end - 1) == b'\n' {
+        end -= 1;
+        if end > start && text.byte_at(end - 1) == b'\r' {
+            end -= 1;
+        }
+    }
+    end
+}
+
+/// The offset of the first non-whitespace character of a logical line, or
+/// the end of the line if it is all whitespace.
+pub fn indent_end(text: &Rope, line_num: usize) -> usize {
+    let line_start = text.offset_of_line(line_num);
+    let line_end = line_end(text, line_num);
+    let line = text.slice_to_cow(line_start..line_end);
+    match line.find(|c: char| !c.is_whitespace()) {
+        Some(i) => line_start + i,
+        None => line_end,
+    }
+}
+
+/// The word at an offset: the run of characters of the same kind as the
+/// one after it, or at the end of a line, the one before it.
+///
+/// Words are runs of alphanumeric characters and underscores; runs of
+/// whitespace and of other characters are also treated as words, so that
+/// every offset is in one. Words don't extend past the end of a line, and
+/// the range is empty on an empty line.
+pub fn word_range(text: &Rope, offset: usize) -> Range<usize> {
+    let line = text.line_of_offset(offset);
+    let start = text.offset_of_line(line);
+    let s = text.slice_to_cow(start..line_end(text, line));
+    // An offset within a line ending is at the end of the line.
+    let pos = (offset - start).min(s.len());
+    let (ix, c) = match s[pos..].chars().next() {
+        Some(c) => (pos, c),
+        None => match s[..pos].char_indices().next_back() {
+            Some(last) => last,
+            None => return start + pos..start + pos,
+        },
+    };
+    let class = WordClass::of(c);
+    let word_start = s[..ix]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| WordClass::of(c) == class)
+        .last()
+        .map_or(ix, |(i, _)| i);
+    let word_end = s[ix..]
+        .char_indices()
+        .find(|&(_, c)| WordClass::of(c) != class)
+        .map_or(s.len(), |(i, _)| ix + i);
+    start + word_start..start + word_end
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum WordClass {
+    Word,
+    Space,
+    Punctuation,
+}
+
+impl WordClass {
+    pub(crate) fn of(c: char) -> WordClass {
+        if c.is_alphanumeric() || c == '_' {
+            WordClass::Word
+        } else if c.is_whitespace() {
+            WordClass::Space
+        } else {
+            WordClass::Punctuation
+        }
+    }
+}
+
+/// The logical lines a region touches.
+///
+/// A non-empty region that ends exactly at the start of a line doesn't
+/// include that line: selecting whole lines by dragging to the start of the
+/// next one shouldn't make line operations act on the next line too. A
+/// caret always includes its own line.
+pub fn lines_for_region(region: &SelRegion, text: &Rope) -> Range<usize> {
+    let first = text.line_of_offset(region.min());
+    let mut last = text.line_of_offset(region.max());
+    if last > first && text.offset_of_line(last) == region.max() {
+        last -= 1;
+    }
+    first..last + 1
+}
+
+/// A measurement for tests: unwrapped text where every grapheme cluster
+/// is one unit wide.
+#[cfg(test)]
+pub(crate) struct TestMeasurement {
+    text: Rope,
+}
+
+#[cfg(test)]
+impl TestMeasurement {
+    pub(crate) fn new(text: &Rope) -> TestMeasurement {
+        TestMeasurement { text: text.clone() }
+    }
+}
+
+#[cfg(test)]
+impl Measurement for TestMeasurement {
+    fn n_visual_lines(&self, _line_num: usize) -> usize {
+        1
+    }
+
+    fn to_pos(&self, line_num: usize, offset: usize) -> (f64, usize) {
+        let start = self.text.offset_of_line(line_num);
+        let (_, col) = offset_to_line_col(&self.text, start + offset);
+        (col as f64, 0)
+    }
+
+    fn from_pos(&self, line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+        let start = self.text.offset_of_line(line_num);
+        let col = horiz.round().max(0.0) as usize;
+        line_col_to_offset(&self.text, line_num, col) - start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use xi_rope::Rope;
+
+    use super::{line_col_to_offset, line_end, lines_for_region, offset_to_line_col, word_range};
+    use crate::SelRegion;
+
+    #[test]
+    fn line_col_ascii() {
+        let text = Rope::from("abc\ndef\r\n");
+        assert_eq!(offset_to_line_col(&text, 0), (0, 0));
+        assert_eq!(offset_to_line_col(&text, 3), (0, 3));
+        assert_eq!(offset_to_line_col(&text, 4), (1, 0));
+        assert_eq!(offset_to_line_col(&text, 6), (1, 2));
+        assert_eq!(offset_to_line_col(&text, 9), (2, 0));
+        assert_eq!(line_end(&text, 1), 7);
+        assert_eq!(line_col_to_offset(&text, 1, 2), 6);
+        assert_eq!(line_col_to_offset(&text, 1, 10), 7);
+        assert_eq!(line_col_to_offset(&text, 5, 0), 9);
+    }
+
+    #[test]
+    fn line_col_multibyte() {
+        // "e" + combining acute accent is one grapheme of 3 bytes, the
+        // emoji is 4 bytes, and the flag is two regional indicators.
+        let text = Rope::from("x\nne\u{301}e\u{1F600}\u{1F1FA}\u{1F1F8}!");
+        assert_eq!(offset_to_line_col(&text, 2), (1, 0));
+        assert_eq!(offset_to_line_col(&text, 3), (1, 1));
+        assert_eq!(offset_to_line_col(&text, 6), (1, 2));
+        assert_eq!(offset_to_line_col(&text, 7), (1, 3));
+        assert_eq!(offset_to_line_col(&text, 11), (1, 4));
+        assert_eq!(offset_to_line_col(&text, 19), (1, 5));
+        assert_eq!(offset_to_line_col(&text, 20), (1, 6));
+        assert_eq!(line_col_to_offset(&text, 1, 2), 6);
+        assert_eq!(line_col_to_offset(&text, 1, 5), 19);
+        assert_eq!(line_col_to_offset(&text, 1, 6), 20);
+    }
+
+    #[test]
+    fn region_lines() {
+        let text = Rope::from("ab\ncd\r\nef\n");
+        let lines = |start, end| lines_for_region(&SelRegion::new(start, end), &text);
+        assert_eq!(lines(1, 1), 0..1);
+        assert_eq!(lines(0, 2), 0..1);
+        assert_eq!(lines(1, 4), 0..2);
+        assert_eq!(lines(4, 1), 0..2);
+        // Ending at the start of a line doesn't include it, whichever way
+        // round the region is.
+        assert_eq!(lines(0, 3), 0..1);
+        assert_eq!(lines(3, 0), 0..1);
+        assert_eq!(lines(1, 7), 0..2);
+        // Ending just before the line ending does.
+        assert_eq!(lines(0, 5), 0..2);
+        // A caret at the start of a line is on that line, including the
+        // empty last line.
+        assert_eq!(lines(3, 3), 1..2);
+        assert_eq!(lines(10, 10), 3..4);
+        // The whole text.
+        assert_eq!(lines(0, 10), 0..3);
+    }
+
+    #[test]
+    fn words() {
+        let text = Rope::from("foo_bar, baz\n\nx  \r\n");
+        assert_eq!(word_range(&text, 0), 0..7);
+        assert_eq!(word_range(&text, 3), 0..7);
+        // Punctuation and whitespace are words of their own.
+        assert_eq!(word_range(&text, 7), 7..8);
+        assert_eq!(word_range(&text, 8), 8..9);
+        // At the end of a line, the word before.
+        assert_eq!(word_range(&text, 12), 9..12);
+        assert_eq!(word_range(&text, 13), 13..13);
+        assert_eq!(word_range(&text, 15), 15..17);
+        assert_eq!(word_range(&text, 18), 15..17);
+    }
+}
@@ -14,14 +14,15 @@
 
 use xi_rope::Rope;
 
+use crate::measurement;
 use crate::{Measurement, SelRegion, Selection};
 
 /// The specification of a movement.
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Movement {
-    /// Move to the left by one grapheme cluster.
+    /// Move to the left by one grapheme cluster, in visual order.
     Left,
-    /// Move to the right by one grapheme cluster.
+    /// Move to the right by one grapheme cluster, in visual order.
     Right,
     /// Move to the left by one word.
     LeftWord,
@@ -49,6 +50,9 @@ pub enum Movement {
     EndOfParagraph,
     /// Move to the end of the text line, or next line if already at end.
     EndOfParagraphKill,
+    /// Move to the first non-whitespace character of the text line, or to
+    /// the start of the line if already there.
+    SmartHome,
     /// Move to the start of the document.
     StartOfDocument,
     /// Move to the end of the document
@@ -68,8 +72,9 @@ impl Movement {
         let (offset, horiz) = match self {
             Movement::Left => {
                 if r.is_caret() || modify {
-                    if let Some(offset) = text.prev_grapheme_offset(r.end) {
-                        (offset, None)
+                    if let Some(offset) = visual_neighbor(text, measurement, r.end, false) {
+                        let offset = skip_hidden(text, measurement, offset, false);
+                        (offset.unwrap_or(r.end), None)
                     } else {
                         (0, r.horiz)
                     }
@@ -79,8 +84,9 @@ impl Movement {
             }
             Movement::Right => {
                 if r.is_caret() || modify {
-                    if let Some(offset) = text.next_grapheme_offset(r.end) {
-                        (offset, None)
+                    if let Some(offset) = visual_neighbor(text, measurement, r.end, true) {
+                        let offset = skip_hidden(text, measurement, offset, true);
+                        (offset.unwrap_or(r.end), None)
                     } else {
                         (r.end, r.horiz)
                     }
@@ -88,40 +94,76 @@ impl Movement {
                     (r.max(), None)
                 }
             }
+            // The measurement might map a position into the middle of a
+            // grapheme cluster, so vertical movement snaps to a boundary.
             Movement::Up => {
                 let info = pos_info(&r, text, measurement, true, modify);
-                if info.rel_line > 0 {
+                let (offset, horiz) = if info.rel_line > 0 {
                     let rel_offset =
                         measurement.from_pos(info.line_num, info.horiz, info.rel_line - 1);
                     (info.line_start + rel_offset, Some(info.horiz))
-                } else if info.line_num == 0 {
-                    (0, Some(info.horiz))
                 } else {
-                    let prev_line = info.line_num - 1;
-                    let n_lines = measurement.n_visual_lines(prev_line);
-                    let prev_line_start = text.offset_of_line(prev_line);
-                    let rel_offset = measurement.from_pos(prev_line, info.horiz, n_lines - 1);
-                    (prev_line_start + rel_offset, Some(info.horiz))
-                }
+                    match shown_line(measurement, (0..info.line_num).rev()) {
+                        Some((prev_line, n_lines)) => {
+                            let prev_line_start = text.offset_of_line(prev_line);
+                            let rel_offset =
+                                measurement.from_pos(prev_line, info.horiz, n_lines - 1);
+                            (prev_line_start + rel_offset, Some(info.horiz))
+                        }
+                        // With only hidden lines above, stop at the start of
+                        // this one.
+                        None => (text.offset_of_line(info.line_num), Some(info.horiz)),
+                    }
+                };
+                (measurement::grapheme_boundary(text, offset), horiz)
             }
             Movement::Down => {
                 let info = pos_info(&r, text, measurement, false, modify);
                 let n_lines = measurement.n_visual_lines(info.line_num);
-                if info.rel_line + 1 < n_lines {
+                let (offset, horiz) = if info.rel_line + 1 < n_lines {
                     let rel_offset =
                         measurement.from_pos(info.line_num, info.horiz, info.rel_line + 1);
                     (info.line_start + rel_offset, Some(info.horiz))
                 } else {
-                    let next_line_start = text.offset_of_line(info.line_num + 1);
-                    let offset = if next_line_start == text.len() {
-                        next_line_start
-                    } else {
-                        let rel_offset = measurement.from_pos(info.line_num + 1, info.horiz, 0);
-                        next_line_start + rel_offset
+                    let last_line = text.line_of_offset(text.len());
+                    let offset = match shown_line(measurement, info.line_num + 1..=last_line) {
+                        Some((next_line, _)) => {
+                            let next_line_start = text.offset_of_line(next_line);
+                            if next_line_start == text.len() {
+                                next_line_start
+                            } else {
+                                let rel_offset = measurement.from_pos(next_line, info.horiz, 0);
+                                next_line_start + rel_offset
+                            }
+                        }
+                        None if info.line_num == last_line => text.len(),
+                        // With only hidden lines below, stop at the end of
+                        // this one.
+                        None => measurement::line_end(text, info.line_num),
                     };
                     (offset, Some(info.horiz))
+                };
+                (measurement::grapheme_boundary(text, offset), horiz)
+            }
+            Movement::StartOfParagraph => {
+                let line_num = text.line_of_offset(r.end);
+                (text.offset_of_line(line_num), None)
+            }
+            Movement::EndOfParagraph => {
+                let line_num = text.line_of_offset(r.end);
+                (measurement::line_end(text, line_num), None)
+            }
+            Movement::SmartHome => {
+                let line_num = text.line_of_offset(r.end);
+                let indent_end = measurement::indent_end(text, line_num);
+                if r.end == indent_end {
+                    (text.offset_of_line(line_num), None)
+                } else {
+                    (indent_end, None)
                 }
             }
+            Movement::StartOfDocument => (0, None),
+            Movement::EndOfDocument => (text.len(), None),
             _ => todo!(),
         };
         SelRegion::new(if modify { r.start } else { offset }, offset).with_horiz(horiz)
@@ -143,6 +185,77 @@ impl Movement {
     }
 }
 
+/// The grapheme boundary next to `offset` to the left or right, in visual
+/// order.
+///
+/// In bidirectional text, visual order differs from logical order, so we
+/// look for the closest grapheme boundary on the same visual line in the
+/// direction of movement. At the visual end of a line, or if the line has
+/// no right-to-left text, this is the logically adjacent boundary.
+// TODO: at the visual end of a line in a right-to-left paragraph, going
+// further should move to the previous line, not the next.
+fn visual_neighbor(
+    text: &Rope,
+    measurement: &impl Measurement,
+    offset: usize,
+    right: bool,
+) -> Option<usize> {
+    let logical = || {
+        if right {
+            text.next_grapheme_offset(offset)
+        } else {
+            text.prev_grapheme_offset(offset)
+        }
+    };
+    let line_num = text.line_of_offset(offset);
+    let line_start = text.offset_of_line(line_num);
+    let line_end = measurement::line_end(text, line_num);
+    if !text.slice_to_cow(line_start..line_end).chars().any(is_rtl) {
+        return logical();
+    }
+    let (x, visual_line) = measurement.to_pos(line_num, offset - line_start);
+    // The closest boundary in the direction of movement, and its distance.
+    let mut best: Option<(usize, f64)> = None;
+    let mut pos = line_start;
+    loop {
+        let (pos_x, pos_visual_line) = measurement.to_pos(line_num, pos - line_start);
+        let dx = if right { pos_x - x } else { x - pos_x };
+        if pos_visual_line == visual_line && dx > 0.0 && best.is_none_or(|(_, d)| dx < d) {
+            best = Some((pos, dx));
+        }
+        if pos >= line_end {
+            break;
+        }
+        pos = text
+            .next_grapheme_offset(pos)
+            .unwrap_or(line_end)
+            .min(line_end);
+    }
+    match best {
+        Some((pos, _)) => Some(pos),
+        None => logical(),
+    }
+}
+
+/// Whether a character has strong right-to-left direction, or is a
+/// control character starting a right-to-left run.
+///
+/// This is an approximation by block, good enough to decide whether a line
+/// needs bidi-aware movement.
+fn is_rtl(c: char) -> bool {
+    match c {
+        // Hebrew, Arabic, Syriac, Thaana, NKo, Samaritan, Mandaic, and so on.
+        '\u{590}'..='\u{8ff}' => true,
+        // Presentation forms.
+        '\u{fb1d}'..='\u{fdff}' | '\u{fe70}'..='\u{feff}' => true,
+        // RLM, RLE, RLO and RLI.
+        '\u{200f}' | '\u{202b}' | '\u{202e}' | '\u{2067}' => true,
+        // Historic right-to-left scripts in the SMP.
+        '\u{10800}'..='\u{10fff}' | '\u{1e800}'..='\u{1efff}' => true,
+        _ => false,
+    }
+}
+
 struct PosInfo {
     line_num: usize,
     horiz: f64,
@@ -168,7 +281,13 @@ fn pos_info(
     let line_start = text.offset_of_line(line_num);
     let rel_offset = offset - line_start;
     let (meas_horiz, rel_line) = measurement.to_pos(line_num, rel_offset);
-    let horiz = r.horiz.unwrap_or(meas_horiz);
+    // The saved horizontal position is that of the active end, so it only
+    // applies when moving from there. With proportional text, the other
+    // end is usually at a different position.
+    let horiz = match r.horiz {
+        Some(horiz) if offset == r.end => horiz,
+        _ => meas_horiz,
+    };
     PosInfo {
         line_num,
         horiz,
@@ -176,3 +295,384 @@ fn pos_info(
         rel_line,
     }
 }
+
+/// The first of the lines that is shown, with its number of visual lines.
+///
+/// Lines with no visual lines, such as folded ones, are skipped.
+fn shown_line(
+    measurement: &impl Measurement,
+    mut lines: impl Iterator<Item = usize>,
+) -> Option<(usize, usize)> {
+    lines.find_map(|line| match measurement.n_visual_lines(line) {
+        0 => None,
+        n_lines => Some((line, n_lines)),
+    })
+}
+
+/// Move an offset out of a hidden line, such as a folded one.
+///
+/// An offset in a shown line is returned as it is. Otherwise, moving right
+/// goes to the start of the next shown line, and moving left to the end of
+/// the previous one. Returns `None` if there is no shown line that way.
+fn skip_hidden(
+    text: &Rope,
+    measurement: &impl Measurement,
+    offset: usize,
+    right: bool,
+) -> Option<usize> {
+    let line_num = text.line_of_offset(offset);
+    if measurement.n_visual_lines(line_num) > 0 {
+        return Some(offset);
+    }
+    if right {
+        let last_line = text.line_of_offset(text.len());
+        let (next_line, _) = shown_line(measurement, line_num + 1..=last_line)?;
+        Some(text.offset_of_line(next_line))
+    } else {
+        let (prev_line, _) = shown_line(measurement, (0..line_num).rev())?;
+        Some(measurement::line_end(text, prev_line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use xi_rope::Rope;
+
+    use super::Movement;
+    use crate::measurement::{self, TestMeasurement};
+    use crate::{Measurement, SelRegion};
+
+    /// A measurement of a single line, with the horizontal position of each
+    /// grapheme boundary given explicitly.
+    struct BidiMeasurement {
+        positions: Vec<(usize, f64)>,
+    }
+
+    impl Measurement for BidiMeasurement {
+        fn n_visual_lines(&self, _line_num: usize) -> usize {
+            1
+        }
+
+        fn to_pos(&self, _line_num: usize, offset: usize) -> (f64, usize) {
+            let &(_, x) = self.positions.iter().find(|&&(o, _)| o == offset).unwrap();
+            (x, 0)
+        }
+
+        fn from_pos(&self, _line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+            let (offset, _) = self
+                .positions
+                .iter()
+                .min_by(|a, b| {
+                    (a.1 - horiz)
+                        .abs()
+                        .partial_cmp(&(b.1 - horiz).abs())
+                        .unwrap()
+                })
+                .unwrap();
+            *offset
+        }
+    }
+
+    fn step(movement: Movement, text: &Rope, m: &impl Measurement, offset: usize) -> usize {
+        movement
+            .update_region(SelRegion::caret(offset), text, m, false)
+            .end
+    }
+
+    /// Repeat a movement until it gets stuck, returning the offsets visited.
+    fn walk(movement: Movement, text: &Rope, m: &impl Measurement, start: usize) -> Vec<usize> {
+        let mut result = Vec::new();
+        let mut offset = start;
+        loop {
+            let next = step(movement, text, m, offset);
+            if next == offset {
+                return result;
+            }
+            result.push(next);
+            offset = next;
+        }
+    }
+
+    #[test]
+    fn bidi_left_right() {
+        // "ab", then alef, bet, gimel (2 bytes each), then "cd". The Hebrew
+        // run is displayed right to left, as "ab" gimel bet alef "cd".
+        let text = Rope::from("ab\u{5d0}\u{5d1}\u{5d2}cd");
+        let m = BidiMeasurement {
+            positions: vec![
+                (0, 0.0),
+                (1, 1.0),
+                (2, 2.0),
+                (4, 4.0),
+                (6, 3.0),
+                (8, 5.0),
+                (9, 6.0),
+                (10, 7.0),
+            ],
+        };
+        // Visually rightward, which is backward in the Hebrew run.
+        assert_eq!(
+            walk(Movement::Right, &text, &m, 0),
+            vec![1, 2, 6, 4, 8, 9, 10]
+        );
+        assert_eq!(
+            walk(Movement::Left, &text, &m, 10),
+            vec![9, 8, 4, 6, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn paragraph_and_document() {
+        let text = Rope::from("ab\r\ncd\nef");
+        let m = TestMeasurement::new(&text);
+        assert_eq!(step(Movement::StartOfParagraph, &text, &m, 5), 4);
+        assert_eq!(step(Movement::EndOfParagraph, &text, &m, 0), 2);
+        assert_eq!(step(Movement::EndOfParagraph, &text, &m, 4), 6);
+        assert_eq!(step(Movement::EndOfParagraph, &text, &m, 7), 9);
+        assert_eq!(step(Movement::StartOfDocument, &text, &m, 5), 0);
+        assert_eq!(step(Movement::EndOfDocument, &text, &m, 5), 9);
+    }
+
+    #[test]
+    fn smart_home() {
+        let text = Rope::from("ab\n  \tcd\n   \n");
+        let m = TestMeasurement::new(&text);
+        // From within the line, go to the first non-whitespace character,
+        // then toggle between it and the start of the line.
+        assert_eq!(step(Movement::SmartHome, &text, &m, 8), 6);
+        assert_eq!(step(Movement::SmartHome, &text, &m, 6), 3);
+        assert_eq!(step(Movement::SmartHome, &text, &m, 3), 6);
+        // Without indentation, both are the start of the line.
+        assert_eq!(step(Movement::SmartHome, &text, &m, 2), 0);
+        assert_eq!(step(Movement::SmartHome, &text, &m, 0), 0);
+        // A line of only whitespace.
+        assert_eq!(step(Movement::SmartHome, &text, &m, 10), 12);
+        assert_eq!(step(Movement::SmartHome, &text, &m, 12), 9);
+
+        // When extending, the anchor stays put.
+        let region = SelRegion::caret(8);
+        let region = Movement::SmartHome.update_region(region, &text, &m, true);
+        assert_eq!(region, SelRegion::new(8, 6));
+        let region = Movement::SmartHome.update_region(region, &text, &m, true);
+        assert_eq!(region, SelRegion::new(8, 3));
+    }
+
+    /// A measurement where the horizontal position is the byte offset
+    /// within the line, so `from_pos` can land inside a grapheme cluster.
+    struct ByteMeasurement {
+        text: Rope,
+    }
+
+    impl Measurement for ByteMeasurement {
+        fn n_visual_lines(&self, _line_num: usize) -> usize {
+            1
+        }
+
+        fn to_pos(&self, _line_num: usize, offset: usize) -> (f64, usize) {
+            (offset as f64, 0)
+        }
+
+        fn from_pos(&self, line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+            let len =
+                measurement::line_end(&self.text, line_num) - self.text.offset_of_line(line_num);
+            (horiz as usize).min(len)
+        }
+    }
+
+    /// A measurement of unwrapped text in a proportional font, where "i"
+    /// is 1 unit wide, "m" is 3, and everything else is 2.
+    struct ProportionalMeasurement {
+        text: Rope,
+    }
+
+    impl ProportionalMeasurement {
+        fn line(&self, line_num: usize) -> String {
+            let start = self.text.offset_of_line(line_num);
+            let end = measurement::line_end(&self.text, line_num);
+            String::from(self.text.slice_to_cow(start..end))
+        }
+    }
+
+    fn char_width(c: char) -> f64 {
+        match c {
+            'i' => 1.0,
+            'm' => 3.0,
+            _ => 2.0,
+        }
+    }
+
+    impl Measurement for ProportionalMeasurement {
+        fn n_visual_lines(&self, _line_num: usize) -> usize {
+            1
+        }
+
+        fn to_pos(&self, line_num: usize, offset: usize) -> (f64, usize) {
+            let x = self.line(line_num)[..offset].chars().map(char_width).sum();
+            (x, 0)
+        }
+
+        fn from_pos(&self, line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+            let line = self.line(line_num);
+            let mut best = (0, horiz.abs());
+            let mut x = 0.0;
+            for (i, c) in line.char_indices() {
+                x += char_width(c);
+                let offset = i + c.len_utf8();
+                if (x - horiz).abs() < best.1 {
+                    best = (offset, (x - horiz).abs());
+                }
+            }
+            best.0
+        }
+    }
+
+    #[test]
+    fn vertical_proportional() {
+        // Line starts: 0, 5, 14.
+        let text = Rope::from("mmmm\niiiiiiii\nmm");
+        let m = ProportionalMeasurement { text: text.clone() };
+        // From x = 6, through a line of narrow glyphs, to one that's too
+        // short, and back.
+        let mut region = SelRegion::caret(2);
+        let mut offsets = Vec::new();
+        for &movement in &[Movement::Down, Movement::Down, Movement::Up, Movement::Up] {
+            region = movement.update_region(region, &text, &m, false);
+            offsets.push(region.end);
+        }
+        assert_eq!(offsets, vec![11, 16, 11, 2]);
+        assert_eq!(region.horiz, Some(6.0));
+
+        // The saved position belongs to the active end, so moving from the
+        // other end measures it instead. Here the region is from x = 1 on
+        // line 1 to x = 3 on line 0.
+        let region = SelRegion::new(6, 1).with_horiz(Some(3.0));
+        let moved = Movement::Down.update_region(region, &text, &m, false);
+        assert_eq!(moved, SelRegion::caret(14).with_horiz(Some(1.0)));
+        // Extending moves the active end, so the position is used.
+        let moved = Movement::Down.update_region(region, &text, &m, true);
+        assert_eq!(moved, SelRegion::new(6, 8).with_horiz(Some(3.0)));
+    }
+
+    /// A measurement of lines two bytes long, some of which are hidden.
+    struct FoldMeasurement {
+        hidden: Vec<usize>,
+    }
+
+    impl Measurement for FoldMeasurement {
+        fn n_visual_lines(&self, line_num: usize) -> usize {
+            if self.hidden.contains(&line_num) {
+                0
+            } else {
+                1
+            }
+        }
+
+        fn to_pos(&self, _line_num: usize, offset: usize) -> (f64, usize) {
+            (offset as f64, 0)
+        }
+
+        fn from_pos(&self, _line_num: usize, horiz: f64, _visual_line: usize) -> usize {
+            (horiz as usize).min(2)
+        }
+    }
+
+    #[test]
+    fn vertical_skips_hidden_lines() {
+        // Line starts: 0, 3, 6, 9.
+        let text = Rope::from("ab\ncd\nef\ngh");
+        let m = FoldMeasurement { hidden: vec![1, 2] };
+        assert_eq!(step(Movement::Down, &text, &m, 1), 10);
+        assert_eq!(step(Movement::Up, &text, &m, 10), 1);
+
+        // With nothing shown past a line, moving stops at its end or start.
+        let m = FoldMeasurement { hidden: vec![2, 3] };
+        assert_eq!(step(Movement::Down, &text, &m, 4), 5);
+        let m = FoldMeasurement { hidden: vec![0] };
+        assert_eq!(step(Movement::Up, &text, &m, 4), 3);
+    }
+
+    #[test]
+    fn horizontal_skips_hidden_lines() {
+        // Line starts: 0, 3, 6, 9.
+        let text = Rope::from("ab\ncd\nef\ngh");
+        let m = FoldMeasurement { hidden: vec![1, 2] };
+        // From the end of the line before the fold to the start of the one
+        // after it, and back.
+        assert_eq!(step(Movement::Right, &text, &m, 2), 9);
+        assert_eq!(step(Movement::Left, &text, &m, 9), 2);
+        // Extending a selection skips the fold too.
+        let region = Movement::Right.update_region(SelRegion::new(1, 2), &text, &m, true);
+        assert_eq!(region, SelRegion::new(1, 9));
+
+        // With nothing shown past the fold, the caret stays where it is.
+        let m = FoldMeasurement { hidden: vec![2, 3] };
+        assert_eq!(step(Movement::Right, &text, &m, 5), 5);
+        let m = FoldMeasurement { hidden: vec![0] };
+        assert_eq!(step(Movement::Left, &text, &m, 3), 3);
+    }
+
+    #[test]
+    fn left_right_graphemes() {
+        let cases: &[(&str, &[usize])] = &[
+            // Combining marks.
+            ("e\u{301}\u{302}x", &[5, 6]),
+            // A ZWJ emoji sequence (family).
+            (
+                "a\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}b",
+                &[1, 19, 20],
+            ),
+            // An emoji with a skin tone modifier.
+            ("\u{1f44d}\u{1f3fd}", &[8]),
+            // Flags are pairs of regional indicators.
+            ("\u{1f1fa}\u{1f1f8}\u{1f1ec}\u{1f1e7}", &[8, 16]),
+            // Hangul syllable from conjoining jamo.
+            ("\u{1100}\u{1161}\u{11a8}a", &[9, 10]),
+            // CRLF is a single cluster.
+            ("a\r\nb", &[1, 3, 4]),
+        ];
+        for &(s, boundaries) in cases {
+            let text = Rope::from(s);
+            let m = TestMeasurement::new(&text);
+            assert_eq!(walk(Movement::Right, &text, &m, 0), boundaries, "{:?}", s);
+            let mut back: Vec<usize> = boundaries.iter().rev().skip(1).copied().collect();
+            back.push(0);
+            assert_eq!(walk(Movement::Left, &text, &m, text.len()), back, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn left_right_clear_horiz() {
+        let text = Rope::from("ab\ncd");
+        let m = TestMeasurement::new(&text);
+        let region = SelRegion::caret(1).with_horiz(Some(5.0));
+        for &movement in &[Movement::Left, Movement::Right] {
+            let moved = movement.update_region(region, &text, &m, false);
+            assert_eq!(moved.horiz, None);
+        }
+    }
+
+    #[test]
+    fn vertical_snaps_to_grapheme() {
+        // The combining accent starts at byte 4 of the text, byte 1 of the
+        // second line.
+        let text = Rope::from("ab\ne\u{301}x\nabcdef");
+        let m = ByteMeasurement { text: text.clone() };
+        // Byte 2 of the second line is in the middle of the accent.
+        assert_eq!(step(Movement::Down, &text, &m, 2), 3);
+        assert_eq!(step(Movement::Up, &text, &m, 10), 3);
+        assert_eq!(step(Movement::Down, &text, &m, 1), 3);
+        // Positions on a boundary are kept.
+        assert_eq!(step(Movement::Down, &text, &m, 6), 11);
+    }
+
+    #[test]
+    fn ltr_left_right() {
+        let text = Rope::from("ab\ncd");
+        let m = TestMeasurement::new(&text);
+        assert_eq!(step(Movement::Right, &text, &m, 1), 2);
+        assert_eq!(step(Movement::Right, &text, &m, 2), 3);
+        assert_eq!(step(Movement::Left, &text, &m, 3), 2);
+        assert_eq!(step(Movement::Left, &text, &m, 0), 0);
+        assert_eq!(step(Movement::Right, &text, &m, 5), 5);
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2020 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Search (and replace) over the document text.
+
+use regex::{Regex, RegexBuilder};
+use xi_rope::{DeltaBuilder, Interval, Rope, RopeDelta};
+
+/// A compiled search query.
+///
+/// Both literal and regex queries are compiled to a `Regex`; a literal query
+/// is just an escaped pattern.
+///
+/// The `regex` crate wants a contiguous `&str` haystack, and a `Rope` is not
+/// one. We materialize the document with `slice_to_cow` once per search (this
+/// borrows without copying when the rope is a single leaf). Matching per line
+/// would be cheaper for big documents, but it can't find matches spanning a
+/// newline, and it would silently change the meaning of `^` and `$` when the
+/// multiline flag is off, so we don't do that (yet).
+pub struct Search {
+    regex: Regex,
+}
+
+impl Search {
+    /// A query matching the given string literally.
+    pub fn literal(s: &str, case_sensitive: bool) -> Search {
+        // An escaped pattern always compiles.
+        Self::build(&regex::escape(s), case_sensitive, false).unwrap()
+    }
+
+    /// A regex query.
+    ///
+    /// When `multiline` is set, `^` and `$` match at the start and end of
+    /// each line rather than only at the start and end of the document.
+    pub fn regex(
+        pattern: &str,
+        case_sensitive: bool,
+        multiline: bool,
+    ) -> Result<Search, regex::Error> {
+        Self::build(pattern, case_sensitive, multiline)
+    }
+
+    fn build(pattern: &str, case_sensitive: bool, multiline: bool) -> Result<Search, regex::Error> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(!case_sensitive)
+            .multi_line(multiline)
+            .build()?;
+        Ok(Search { regex })
+    }
+
+    /// Find all non-overlapping matches, in document order.
+    pub fn find_all(&self, text: &Rope) -> Vec<Interval> {
+        let haystack = text.slice_to_cow(..);
+        self.regex
+            .find_iter(&haystack)
+            .map(|m| Interval::new(m.start(), m.end()))
+            .collect()
+    }
+
+    /// Build a delta replacing all matches.
+    ///
+    /// Capture groups may be referenced from `replacement` as `$1`, `${name}`
+    /// and so on, as in `Regex::replace`; use `$$` for a literal dollar sign.
+    pub fn replace_all(&self, text: &Rope, replacement: &str) -> RopeDelta {
+        let haystack = text.slice_to_cow(..);
+        let mut builder = DeltaBuilder::new(text.len());
+        for caps in self.regex.captures_iter(&haystack) {
+            let m = caps.get(0).unwrap();
+            let mut expanded = String::new();
+            caps.expand(replacement, &mut expanded);
+            builder.replace(m.start()..m.end(), Rope::from(expanded));
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Search;
+    use xi_rope::{Interval, Rope};
+
+    #[test]
+    fn literal() {
+        let text = Rope::from("a.b a.b axb");
+        let search = Search::literal("a.b", true);
+        assert_eq!(
+            search.find_all(&text),
+            vec![Interval::new(0, 3), Interval::new(4, 7)]
+        );
+        let search = Search::literal("A.B", false);
+        assert_eq!(search.find_all(&text).len(), 2);
+    }
+
+    #[test]
+    fn multiline_flag() {
+        let text = Rope::from("foo\nfoo bar\nbar foo\n");
+        let search = Search::regex("^foo", true, false).unwrap();
+        assert_eq!(search.find_all(&text), vec![Interval::new(0, 3)]);
+        let search = Search::regex("^foo", true, true).unwrap();
+        assert_eq!(
+            search.find_all(&text),
+            vec![Interval::new(0, 3), Interval::new(4, 7)]
+        );
+        let search = Search::regex("foo$", true, true).unwrap();
+        assert_eq!(
+            search.find_all(&text),
+            vec![Interval::new(0, 3), Interval::new(16, 19)]
+        );
+    }
+
+    #[test]
+    fn match_spans_newline() {
+        let text = Rope::from("one\ntwo\n");
+        let search = Search::regex("e\nt", true, false).unwrap();
+        assert_eq!(search.find_all(&text), vec![Interval::new(2, 5)]);
+    }
+
+    #[test]
+    fn capture_group_replace() {
+        let mut text = Rope::from("x = 1; y = 22;");
+        let search = Search::regex(r"(\w) = (\d+)", true, false).unwrap();
+        let delta = search.replace_all(&text, "$2 =: $1 ($$)");
+        text = delta.apply(&text);
+        assert_eq!(String::from(&text), "1 =: x ($); 22 =: y ($);");
+    }
+
+    #[test]
+    fn bad_regex() {
+        assert!(Search::regex("(", true, false).is_err());
+    }
+}
@@ -19,9 +19,12 @@
 
 use std::cmp::{max, min};
 use std::fmt;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 
-use xi_rope::{Interval, RopeDelta, Transformer};
+use xi_rope::{Interval, Rope, RopeDelta, Transformer};
+
+use crate::measurement;
+use crate::Measurement;
 
 /// A type representing horizontal measurements. Generally this will be
 /// px units.
@@ -44,7 +47,13 @@ pub enum InsertDrift {
 }
 
 /// A set of zero or more selection regions, representing a selection state.
-#[derive(Default, Debug, Clone)]
+///
+/// The regions are always sorted and disjoint, whatever order they were
+/// added in (see `add_region`), so edits can build a delta by iterating
+/// over them. A region's index, as with `iter().enumerate()`, is its
+/// position in document order, and only changes when regions are added
+/// or removed before it.
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Selection {
     // An invariant: regions[i].max() <= regions[i+1].min()
     // and < if either is_caret()
@@ -64,13 +73,75 @@ impl Selection {
         }
     }
 
+    /// Creates a block selection, as when dragging out a rectangle with Alt
+    /// held: a region on each visual line from the anchor's to the active
+    /// end's, running from the anchor's horizontal position to the active
+    /// end's.
+    ///
+    /// Each end is an offset, which gives its visual line, and a horizontal
+    /// position, which can be past the end of the line. A line too short to
+    /// reach the rectangle gets a caret at its end. Hidden lines (see
+    /// `Measurement::n_visual_lines`) are skipped.
+    pub fn new_block(
+        text: &Rope,
+        measurement: &impl Measurement,
+        anchor: (usize, HorizPos),
+        active: (usize, HorizPos),
+    ) -> Selection {
+        let visual_pos = |offset: usize| {
+            let line = text.line_of_offset(offset);
+            let (_, visual_line) = measurement.to_pos(line, offset - text.offset_of_line(line));
+            (line, visual_line)
+        };
+        let (top, bottom) = {
+            let (a, b) = (visual_pos(anchor.0), visual_pos(active.0));
+            (a.min(b), a.max(b))
+        };
+        let mut sel = Selection::new();
+        for line in top.0..=bottom.0 {
+            let n_visual_lines = measurement.n_visual_lines(line);
+            if n_visual_lines == 0 {
+                continue;
+            }
+            let first = if line == top.0 { top.1 } else { 0 };
+            let last = if line == bottom.0 {
+                bottom.1
+            } else {
+                n_visual_lines - 1
+            };
+            let line_start = text.offset_of_line(line);
+            let offset = |horiz, visual_line| {
+                let offset = line_start + measurement.from_pos(line, horiz, visual_line);
+                measurement::grapheme_boundary(text, offset)
+            };
+            for visual_line in first..=last {
+                let start = offset(anchor.1, visual_line);
+                let end = offset(active.1, visual_line);
+                sel.add_region(SelRegion::new(start, end));
+            }
+        }
+        sel
+    }
+
     /// Clear the selection.
     pub fn clear(&mut self) {
         self.regions.clear();
     }
 
-    /// Collapse all selections into a single caret.
+    /// The primary region, if the selection is not empty.
+    ///
+    /// We consider the first region in document order to be primary. This
+    /// is the region that survives `collapse`.
+    pub fn primary(&self) -> Option<&SelRegion> {
+        self.regions.first()
+    }
+
+    /// Collapse all selections into a single caret, at the end of the
+    /// primary region.
     pub fn collapse(&mut self) {
+        if self.regions.is_empty() {
+            return;
+        }
         self.regions.truncate(1);
         self.regions[0].start = self.regions[0].end;
     }
@@ -94,13 +165,18 @@ impl Selection {
     /// a non-caret if it is in the interior or on either edge. Two carets
     /// merge if they are the same offset.
     ///
+    /// Returns the range of indices, from before the call, of the regions
+    /// merged into the new one. The new region is at the start of the range,
+    /// and the regions after the range shift by one minus its length, so
+    /// when nothing merges, the range is empty and they shift up by one.
+    ///
     /// Performance note: should be O(1) if the new region strictly comes
     /// after all the others in the selection, otherwise O(n).
-    pub fn add_region(&mut self, region: SelRegion) {
+    pub fn add_region(&mut self, region: SelRegion) -> Range<usize> {
         let mut ix = self.search(region.min());
         if ix == self.regions.len() {
             self.regions.push(region);
-            return;
+            return ix..ix;
         }
         let mut region = region;
         let mut end_ix = ix;
@@ -122,6 +198,7 @@ impl Selection {
             self.regions[ix] = region;
             remove_n_at(&mut self.regions, ix + 1, end_ix - ix - 1);
         }
+        ix..end_ix
     }
 
     /// Gets a slice of regions that intersect the given range. Regions that
@@ -198,6 +275,19 @@ impl Selection {
         (self.regions[ix].min(), self.regions[ix].max())
     }
 
+    /// The text of each region, in order. A caret's text is empty.
+    pub fn region_texts(&self, text: &Rope) -> Vec<String> {
+        self.regions
+            .iter()
+            .map(|r| text.slice_to_cow(r.min()..r.max()).into_owned())
+            .collect()
+    }
+
+    /// The text of all the regions, separated by newlines.
+    pub fn joined_text(&self, text: &Rope) -> String {
+        self.region_texts(text).join("\n")
+    }
+
     /// Computes a new selection based on applying a delta to the old selection.
     ///
     /// When new text is inserted at a caret, the new caret can be either before
@@ -205,6 +295,9 @@ impl Selection {
     ///
     /// Whether or not the preceding selections are restored depends on the keep_selections
     /// value (only set to true on transpose).
+    ///
+    /// Regions are re-added in order, so carets that land on the same offset,
+    /// and regions that come to overlap, are merged into one.
     pub fn apply_delta(&self, delta: &RopeDelta, after: bool, drift: InsertDrift) -> Selection {
         let mut result = Selection::new();
         let mut transformer = Transformer::new(delta);
@@ -423,8 +516,9 @@ pub fn remove_n_at<T>(v: &mut Vec<T>, index: usize, n: usize) {
 #[cfg(test)]
 mod tests {
     use super::{InsertDrift, SelRegion, Selection};
+    use crate::measurement::TestMeasurement;
     use std::ops::Deref;
-    use xi_rope::{DeltaBuilder, Interval};
+    use xi_rope::{DeltaBuilder, Interval, Rope};
 
     fn r(start: usize, end: usize) -> SelRegion {
         SelRegion::new(start, end)
@@ -527,6 +621,43 @@ mod tests {
         assert_eq!(s.deref(), &[r(1, 9)]);
     }
 
+    #[test]
+    fn add_region_indices() {
+        let mut s = Selection::new();
+        assert_eq!(s.add_region(r(10, 12)), 0..0);
+        assert_eq!(s.add_region(r(20, 22)), 1..1);
+        // Before both, so they shift up by one.
+        assert_eq!(s.add_region(r(1, 3)), 0..0);
+        assert_eq!(s.deref(), &[r(1, 3), r(10, 12), r(20, 22)]);
+        // Between two, leaving the first where it was.
+        assert_eq!(s.add_region(r(15, 16)), 2..2);
+        assert_eq!(s.deref(), &[r(1, 3), r(10, 12), r(15, 16), r(20, 22)]);
+        let indexed: Vec<_> = s.iter().enumerate().map(|(i, r)| (i, r.min())).collect();
+        assert_eq!(indexed, vec![(0, 1), (1, 10), (2, 15), (3, 20)]);
+        // Merging the middle two, so the last moves down by one.
+        assert_eq!(s.add_region(r(11, 16)), 1..3);
+        assert_eq!(s.deref(), &[r(1, 3), r(10, 16), r(20, 22)]);
+        // A caret inside a region merges with it.
+        assert_eq!(s.add_region(SelRegion::caret(21)), 2..3);
+        assert_eq!(s.deref(), &[r(1, 3), r(10, 16), r(20, 22)]);
+    }
+
+    #[test]
+    fn region_texts() {
+        let text = Rope::from("abc\ndef");
+        let mut s = Selection::new();
+        s.add_region(r(1, 2));
+        s.add_region(SelRegion::caret(3));
+        s.add_region(r(7, 4));
+        assert_eq!(s.region_texts(&text), vec!["b", "", "def"]);
+        assert_eq!(s.joined_text(&text), "b\n\ndef");
+        // Carets only.
+        let s = Selection::new_simple(SelRegion::caret(0));
+        assert_eq!(s.region_texts(&text), vec![""]);
+        assert_eq!(s.joined_text(&text), "");
+        assert!(Selection::new().region_texts(&text).is_empty());
+    }
+
     #[test]
     fn merge_carets() {
         let mut s = Selection::new();
@@ -621,6 +752,61 @@ mod tests {
         assert_eq!(s3.deref(), &[r(1, 1)]);
     }
 
+    #[test]
+    fn apply_delta_merges_converging_carets() {
+        let mut s = Selection::new();
+        s.add_region(r(1, 1));
+        s.add_region(r(3, 3));
+        s.add_region(r(5, 3));
+
+        // "abcdef" -> "af": everything between the carets is deleted
+        let mut builder = DeltaBuilder::new("abcdef".len());
+        builder.delete(Interval::new(1, 5));
+        let s2 = s.apply_delta(&builder.build(), true, InsertDrift::Default);
+        assert_eq!(s2.deref(), &[r(1, 1)]);
+    }
+
+    #[test]
+    fn collapse() {
+        let mut s = Selection::new();
+        s.add_region(r(1, 1));
+        s.add_region(r(3, 5));
+        s.add_region(r(8, 6));
+        s.collapse();
+        assert_eq!(s.deref(), &[r(1, 1)]);
+
+        let mut s = Selection::new();
+        s.add_region(r(4, 2));
+        s.add_region(r(6, 7));
+        s.add_region(r(9, 9));
+        s.collapse();
+        assert_eq!(s.deref(), &[r(2, 2)]);
+
+        let mut s = Selection::new();
+        s.collapse();
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn block() {
+        // Line starts: 0, 7, 10.
+        let text = Rope::from("abcdef\nab\nabcdef");
+        let m = TestMeasurement::new(&text);
+        let block = |anchor, active| Selection::new_block(&text, &m, anchor, active);
+        // The short middle line gets a caret at its end.
+        let sel = block((1, 1.0), (14, 4.0));
+        assert_eq!(sel.deref(), &[r(1, 4), r(8, 9), r(11, 14)]);
+        // Dragging up and to the left, the regions are backward.
+        let sel = block((14, 4.0), (1, 1.0));
+        assert_eq!(sel.deref(), &[r(4, 1), r(9, 8), r(14, 11)]);
+        // The active end can be past the end of its line.
+        let sel = block((0, 0.0), (9, 5.0));
+        assert_eq!(sel.deref(), &[r(0, 5), r(7, 9)]);
+        // A zero-width block is a column of carets.
+        let sel = block((2, 2.0), (12, 2.0));
+        assert_eq!(sel.deref(), &[r(2, 2), r(9, 9), r(12, 12)]);
+    }
+
     #[test]
     fn display() {
         let mut s = Selection::new();
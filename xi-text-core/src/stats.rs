@@ -0,0 +1,162 @@
+// Copyright 2020 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counts of characters, words, and lines, for a status readout.
+
+use xi_rope::{LinesMetric, Rope};
+
+use crate::measurement::WordClass;
+
+/// The size of a document in characters, words, and lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TextStats {
+    /// The number of grapheme clusters.
+    pub chars: usize,
+    pub words: usize,
+    pub lines: usize,
+}
+
+impl TextStats {
+    /// Count everything in the text.
+    pub fn of(text: &Rope) -> TextStats {
+        TextStats {
+            chars: grapheme_count(text),
+            words: word_count(text),
+            lines: line_count(text),
+        }
+    }
+}
+
+/// The number of grapheme clusters in the text.
+///
+/// A `\r\n` line ending counts as one.
+pub fn grapheme_count(text: &Rope) -> usize {
+    let mut count = 0;
+    // The start of the next grapheme cluster to count.
+    let mut pos = 0;
+    let mut chunk_start = 0;
+    for chunk in text.iter_chunks(..) {
+        let chunk_end = chunk_start + chunk.len();
+        if pos < chunk_end {
+            let rest = &chunk[pos - chunk_start..];
+            if rest.is_ascii() {
+                // Every byte is a cluster, except for line endings. The last
+                // cluster may continue into the next chunk, as with a
+                // combining mark, so leave it to the slow path.
+                let last = if rest.ends_with("\r\n") { 2 } else { 1 };
+                count += rest.len() - rest.matches("\r\n").count() - 1;
+                pos = chunk_end - last;
+            }
+            while pos < chunk_end {
+                pos = text.next_grapheme_offset(pos).unwrap_or(chunk_end);
+                count += 1;
+            }
+        }
+        chunk_start = chunk_end;
+    }
+    count
+}
+
+/// The number of words in the text.
+///
+/// Words are runs of alphanumeric characters and underscores, as for
+/// `measurement::word_range`, so punctuation separates words as well as
+/// whitespace does. A grapheme cluster is classed by its first character,
+/// so a combining mark doesn't split a word.
+pub fn word_count(text: &Rope) -> usize {
+    let mut count = 0;
+    // The class of the previous grapheme cluster.
+    let mut prev = WordClass::Space;
+    // The start of the next grapheme cluster to class.
+    let mut pos = 0;
+    let mut chunk_start = 0;
+    for chunk in text.iter_chunks(..) {
+        let chunk_end = chunk_start + chunk.len();
+        while pos < chunk_end {
+            let rest = &chunk[pos - chunk_start..];
+            let c = rest.chars().next().unwrap();
+            let class = WordClass::of(c);
+            if class == WordClass::Word && prev != WordClass::Word {
+                count += 1;
+            }
+            prev = class;
+            // An ASCII character followed by another is a cluster of its
+            // own, except in `\r\n`, which is whitespace either way.
+            pos = if c.is_ascii() && rest.as_bytes().get(1).is_some_and(u8::is_ascii) {
+                pos + 1
+            } else {
+                text.next_grapheme_offset(pos).unwrap_or(chunk_end)
+            };
+        }
+        chunk_start = chunk_end;
+    }
+    count
+}
+
+/// The number of logical lines in the text.
+///
+/// As with line numbers, a trailing line ending starts a new (empty)
+/// line, which is counted, so an empty text has one line.
+pub fn line_count(text: &Rope) -> usize {
+    text.measure::<LinesMetric>() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use xi_rope::Rope;
+
+    use super::{grapheme_count, line_count, word_count, TextStats};
+
+    #[test]
+    fn stats() {
+        let text = Rope::from("Hello,  world\n\tfoo bar\r\n\n  e\u{301}nd  \n");
+        assert_eq!(
+            TextStats::of(&text),
+            TextStats {
+                chars: 32,
+                words: 5,
+                lines: 5,
+            }
+        );
+        let empty = Rope::from("");
+        assert_eq!(
+            TextStats::of(&empty),
+            TextStats {
+                chars: 0,
+                words: 0,
+                lines: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn stats_across_chunks() {
+        // Long enough for many chunks, so clusters and words straddle
+        // chunk boundaries.
+        let text = Rope::from("word\r\nx\u{301}y  ".repeat(1000));
+        assert_eq!(grapheme_count(&text), 9000);
+        assert_eq!(word_count(&text), 2000);
+        assert_eq!(line_count(&text), 1001);
+    }
+
+    #[test]
+    fn words_split_by_punctuation() {
+        let count = |s| word_count(&Rope::from(s));
+        assert_eq!(count("foo_bar,baz"), 2);
+        assert_eq!(count("a--b"), 2);
+        assert_eq!(count("(x) + y2;"), 2);
+        assert_eq!(count("-- ,."), 0);
+        assert_eq!(count("caf\u{e9} e\u{301}t\u{e9}"), 2);
+    }
+}
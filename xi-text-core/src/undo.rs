@@ -0,0 +1,283 @@
+// Copyright 2020 The xi-editor Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Undo history.
+
+use std::time::{Duration, Instant};
+
+use xi_rope::compare::RopeScanner;
+use xi_rope::Rope;
+
+use crate::Selection;
+
+/// The default interval within which typing is undone as one unit.
+pub const DEFAULT_COALESCE_INTERVAL: Duration = Duration::from_millis(300);
+
+/// The state of the document before or after an edit.
+///
+/// Ropes are persistent, so keeping a snapshot of the text is cheap: it
+/// shares everything but the edited part with the current text.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub text: Rope,
+    pub sel: Selection,
+}
+
+/// The kind of an edit, which decides whether it joins the previous one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EditKind {
+    /// Typing, which joins typing just before it.
+    Insert,
+    /// Any other edit, which is always undone on its own.
+    Other,
+}
+
+/// A history of edits, grouped into units that are undone together.
+///
+/// An insert joins the previous unit if that was also an insert, no more
+/// than the coalescing interval ago, with no selection change in between
+/// (see `break_unit`).
+pub struct UndoStack {
+    undos: Vec<UndoUnit>,
+    redos: Vec<UndoUnit>,
+    interval: Duration,
+    /// Whether an insert can join the last unit.
+    open: bool,
+}
+
+struct UndoUnit {
+    before: Snapshot,
+    after: Snapshot,
+    /// The time of the last edit in the unit.
+    last_edit: Instant,
+}
+
+impl UndoStack {
+    /// An empty history, coalescing inserts within the given interval.
+    pub fn new(interval: Duration) -> UndoStack {
+        UndoStack {
+            undos: Vec::new(),
+            redos: Vec::new(),
+            interval,
+            open: false,
+        }
+    }
+
+    /// Record an edit made at the given time.
+    ///
+    /// This clears the redo history.
+    pub fn record(&mut self, before: Snapshot, after: Snapshot, kind: EditKind, time: Instant) {
+        self.rebase_appended(&before.text);
+        self.redos.clear();
+        let interval = self.interval;
+        let joins = match self.undos.last() {
+            Some(unit) if self.open && kind == EditKind::Insert => {
+                time.saturating_duration_since(unit.last_edit) <= interval
+            }
+            _ => false,
+        };
+        if joins {
+            let unit = self.undos.last_mut().unwrap();
+            unit.after = after;
+            unit.last_edit = time;
+        } else {
+            self.undos.push(UndoUnit {
+                before,
+                after,
+                last_edit: time,
+            });
+        }
+        self.open = kind == EditKind::Insert;
+    }
+
+    /// Keep text appended to the document outside the history.
+    ///
+    /// If `text` is the current text of the history with more at the end,
+    /// as after appending log output, the addition is put at the end of
+    /// every snapshot, so that undo and redo don't remove it.
+    pub fn rebase_appended(&mut self, text: &Rope) {
+        let current = match self.current_text() {
+            Some(current) => current,
+            None => return,
+        };
+        let len = current.len();
+        if text.len() <= len || RopeScanner::new(current, text).find_ne_char(0, 0, None) < len {
+            return;
+        }
+        let suffix = text.subseq(len..);
+        for unit in self.undos.iter_mut().chain(self.redos.iter_mut()) {
+            for snapshot in &mut [&mut unit.before, &mut unit.after] {
+                let end = snapshot.text.len();
+                snapshot.text.edit(end..end, suffix.clone());
+            }
+        }
+    }
+
+    /// The text after the last edit, or before the last undone one.
+    fn current_text(&self) -> Option<&Rope> {
+        match self.undos.last() {
+            Some(unit) => Some(&unit.after.text),
+            None => self.redos.last().map(|unit| &unit.before.text),
+        }
+    }
+
+    /// Start a new unit with the next edit, as when the caret moves.
+    pub fn break_unit(&mut self) {
+        self.open = false;
+    }
+
+    /// Undo the last unit, returning the state before it.
+    pub fn undo(&mut self) -> Option<Snapshot> {
+        let unit = self.undos.pop()?;
+        let before = unit.before.clone();
+        self.redos.push(unit);
+        self.open = false;
+        Some(before)
+    }
+
+    /// Redo the last undone unit, returning the state after it.
+    pub fn redo(&mut self) -> Option<Snapshot> {
+        let unit = self.redos.pop()?;
+        let after = unit.after.clone();
+        self.undos.push(unit);
+        self.open = false;
+        Some(after)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undos.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redos.is_empty()
+    }
+}
+
+impl Default for UndoStack {
+    fn default() -> UndoStack {
+        UndoStack::new(DEFAULT_COALESCE_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use xi_rope::Rope;
+
+    use super::{EditKind, Snapshot, UndoStack};
+    use crate::{SelRegion, Selection};
+
+    fn snapshot(s: &str) -> Snapshot {
+        Snapshot {
+            text: Rope::from(s),
+            sel: Selection::new_simple(SelRegion::caret(s.len())),
+        }
+    }
+
+    /// Type the strings one after another, each at the given time in ms
+    /// since the start.
+    fn type_at(stack: &mut UndoStack, start: Instant, steps: &[(&str, &str, u64)]) {
+        for &(before, after, ms) in steps {
+            let time = start + Duration::from_millis(ms);
+            stack.record(snapshot(before), snapshot(after), EditKind::Insert, time);
+        }
+    }
+
+    fn undo_text(stack: &mut UndoStack) -> Option<String> {
+        stack.undo().map(|snapshot| String::from(&snapshot.text))
+    }
+
+    #[test]
+    fn coalesce_by_time() {
+        let mut stack = UndoStack::new(Duration::from_millis(300));
+        let start = Instant::now();
+        type_at(
+            &mut stack,
+            start,
+            &[
+                ("", "a", 0),
+                ("a", "ab", 100),
+                // Exactly the interval still joins.
+                ("ab", "abc", 400),
+                // A pause starts a new unit.
+                ("abc", "abcd", 701),
+                ("abcd", "abcde", 800),
+            ],
+        );
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("abc"));
+        assert_eq!(undo_text(&mut stack).as_deref(), Some(""));
+        assert_eq!(undo_text(&mut stack), None);
+    }
+
+    #[test]
+    fn break_on_movement() {
+        let mut stack = UndoStack::default();
+        let start = Instant::now();
+        type_at(&mut stack, start, &[("", "a", 0), ("a", "ab", 10)]);
+        stack.break_unit();
+        type_at(&mut stack, start, &[("ab", "abc", 20)]);
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("ab"));
+        assert_eq!(undo_text(&mut stack).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn break_on_other_edits() {
+        let mut stack = UndoStack::default();
+        let start = Instant::now();
+        type_at(&mut stack, start, &[("", "a", 0), ("a", "ab", 10)]);
+        let time = start + Duration::from_millis(20);
+        stack.record(snapshot("ab"), snapshot("a"), EditKind::Other, time);
+        type_at(&mut stack, start, &[("a", "ac", 30), ("ac", "acd", 40)]);
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("a"));
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("ab"));
+        assert_eq!(undo_text(&mut stack).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn redo() {
+        let mut stack = UndoStack::default();
+        let start = Instant::now();
+        type_at(&mut stack, start, &[("", "a", 0), ("a", "ab", 1000)]);
+        assert!(stack.can_undo());
+        assert!(!stack.can_redo());
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("a"));
+        let redone = stack.redo().unwrap();
+        assert_eq!(String::from(&redone.text), "ab");
+        assert_eq!(&*redone.sel, &[SelRegion::caret(2)]);
+        assert!(stack.redo().is_none());
+
+        // A new edit clears the redo history, and doesn't join the unit
+        // that was redone.
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("a"));
+        type_at(&mut stack, start, &[("a", "ax", 1010)]);
+        assert!(!stack.can_redo());
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn rebase_appended() {
+        let mut stack = UndoStack::default();
+        let start = Instant::now();
+        type_at(&mut stack, start, &[("", "a", 0), ("a", "ab", 1000)]);
+        stack.rebase_appended(&Rope::from("ab\nlog"));
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("a\nlog"));
+        let redone = stack.redo().unwrap();
+        assert_eq!(String::from(&redone.text), "ab\nlog");
+
+        // Text that isn't an addition at the end is left alone.
+        stack.rebase_appended(&Rope::from("x\nlog more"));
+        assert_eq!(undo_text(&mut stack).as_deref(), Some("a\nlog"));
+    }
+}